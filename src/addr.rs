@@ -0,0 +1,183 @@
+//! The mailbox grammar of RFC 5321, section 4.1.2
+//!
+//! This is stricter than the `emailaddress` crate, which only splits at the
+//! last `@`. Non-ASCII characters are allowed where RFC 6531 allows them, so
+//! whether they may be used is left to the caller.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+
+/// The maximum length of a local part, in octets
+pub const MAX_LOCAL_LEN: usize = 64;
+/// The maximum length of a domain, in octets
+pub const MAX_DOMAIN_LEN: usize = 255;
+
+
+/// Split a mailbox into its local part and domain, validating both
+///
+/// The local part is a dot-atom or a quoted string, and is returned as is,
+/// including quotes. The domain is a domain name, or an address literal in
+/// brackets. On failure, returns a description of the problem.
+pub fn parse_addr_spec(input: &str) -> Result<(&str, &str), &'static str> {
+    let at = if input.starts_with('"') {
+        quoted_string_len(input).ok_or("invalid quoted local part")?
+    } else {
+        input.find('@').ok_or("missing '@' character")?
+    };
+    let (local, domain) = (&input[..at], &input[at..]);
+    if !domain.starts_with('@') {
+        return Err("expected '@' after local part");
+    }
+    let domain = &domain[1..];
+
+    if local.is_empty() {
+        return Err("empty local part");
+    }
+    if local.len() > MAX_LOCAL_LEN {
+        return Err("local part is too long");
+    }
+    if !local.starts_with('"') && !is_dot_string(local) {
+        return Err("invalid local part");
+    }
+
+    if domain.is_empty() {
+        return Err("empty domain");
+    }
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err("domain is too long");
+    }
+    let valid_domain = if domain.starts_with('[') {
+        is_address_literal(domain)
+    } else {
+        is_domain(domain)
+    };
+    if !valid_domain {
+        return Err("invalid domain");
+    }
+    Ok((local, domain))
+}
+
+/// The length of the quoted string at the start of the input, with quotes
+fn quoted_string_len(input: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in input.char_indices().skip(1) {
+        if escaped {
+            if c < ' ' || c > '~' {
+                return None;
+            }
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some(idx + 1);
+        } else if !(c == ' ' || c == '!' || (c >= '#' && c <= '~') || !c.is_ascii()) {
+            return None;
+        }
+    }
+    None
+}
+
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+/// `Atom *("." Atom)`
+fn is_dot_string(s: &str) -> bool {
+    s.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// `sub-domain *("." sub-domain)`, where labels may be U-labels
+fn is_domain(s: &str) -> bool {
+    s.split('.').all(is_ldh_label)
+}
+
+/// `Let-dig [Ldh-str]`
+fn is_ldh_label(label: &str) -> bool {
+    let is_let_dig = |c: char| c.is_ascii_alphanumeric() || !c.is_ascii();
+    !label.is_empty() &&
+        label.chars().all(|c| is_let_dig(c) || c == '-') &&
+        label.chars().next().map_or(false, &is_let_dig) &&
+        label.chars().last().map_or(false, &is_let_dig)
+}
+
+/// `"[" ( IPv4 / "IPv6:" IPv6 / General ) "]"`
+fn is_address_literal(s: &str) -> bool {
+    if s.len() < 3 || !s.ends_with(']') {
+        return false;
+    }
+    let inner = &s[1..s.len() - 1];
+    if inner.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+    let mut parts = inner.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(tag), Some(addr)) if tag.eq_ignore_ascii_case("IPv6") => {
+            addr.parse::<Ipv6Addr>().is_ok()
+        },
+        (Some(tag), Some(content)) => {
+            is_ldh_label(tag) && tag.is_ascii() && !content.is_empty() &&
+                content.chars().all(|c| (c >= '!' && c <= 'Z') || (c >= '^' && c <= '~'))
+        },
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use addr::{parse_addr_spec};
+
+    #[test]
+    fn test() {
+        for (input, local, domain) in vec![
+            ("john@example.test", "john", "example.test"),
+            ("john.doe@example.test", "john.doe", "example.test"),
+            ("j!#$%&'*+-/=?^_`{|}~n@example.test", "j!#$%&'*+-/=?^_`{|}~n", "example.test"),
+            ("jøhn@exämple.test", "jøhn", "exämple.test"),
+            ("john@localhost", "john", "localhost"),
+            ("john@a-b.x1.test", "john", "a-b.x1.test"),
+            ("\"John Doe\"@example.test", "\"John Doe\"", "example.test"),
+            ("\"john@home\"@example.test", "\"john@home\"", "example.test"),
+            ("\"a\\\"b\\\\c\"@example.test", "\"a\\\"b\\\\c\"", "example.test"),
+            ("\"\"@example.test", "\"\"", "example.test"),
+            ("john@[192.0.2.1]", "john", "[192.0.2.1]"),
+            ("john@[IPv6:2001:db8::1]", "john", "[IPv6:2001:db8::1]"),
+            ("john@[x-tag:some-content]", "john", "[x-tag:some-content]"),
+        ] {
+            assert_eq!(parse_addr_spec(input), Ok((local, domain)), "{}", input);
+        }
+
+        for (input, err) in vec![
+            ("john", "missing '@' character"),
+            ("@example.test", "empty local part"),
+            ("john@", "empty domain"),
+            (".john@example.test", "invalid local part"),
+            ("john.@example.test", "invalid local part"),
+            ("jo..hn@example.test", "invalid local part"),
+            ("jo hn@example.test", "invalid local part"),
+            ("jo(hn)@example.test", "invalid local part"),
+            ("\"john@example.test", "invalid quoted local part"),
+            ("\"jo\"hn@example.test", "expected '@' after local part"),
+            ("\"jo\x01hn\"@example.test", "invalid quoted local part"),
+            ("john@example..test", "invalid domain"),
+            ("john@-example.test", "invalid domain"),
+            ("john@example-.test", "invalid domain"),
+            ("john@exa_mple.test", "invalid domain"),
+            ("john@example.test.", "invalid domain"),
+            ("john@john@example.test", "invalid domain"),
+            ("john@[192.0.2.256]", "invalid domain"),
+            ("john@[IPv6:192.0.2.1]", "invalid domain"),
+            ("john@[x-tag:]", "invalid domain"),
+            ("john@[x-tag:a[b]", "invalid domain"),
+            ("john@[192.0.2.1", "invalid domain"),
+        ] {
+            assert_eq!(parse_addr_spec(input), Err(err), "{}", input);
+        }
+
+        let local = "a".repeat(64);
+        assert!(parse_addr_spec(&format!("{}@example.test", local)).is_ok());
+        assert_eq!(parse_addr_spec(&format!("a{}@example.test", local)), Err("local part is too long"));
+        let domain = format!("{}.test", vec!["a".repeat(63); 4].join("."));
+        assert_eq!(parse_addr_spec(&format!("john@{}", domain)), Err("domain is too long"));
+    }
+}
@@ -0,0 +1,468 @@
+//! Helpers for SMTP authentication
+//!
+//! This module contains the pieces used to build `AUTH` requests. It is used
+//! by the client handshake, but can also be used directly. `AuthExchange`
+//! drives a complete exchange, from the initial `AUTH` request to the final
+//! server reply.
+//!
+//! With the `scram` feature enabled, this module also provides builders for
+//! the `SCRAM-SHA-256` mechanism (RFC 7677).
+
+use base64;
+use codes;
+#[cfg(feature = "scram")]
+use hmac::{Hmac, Mac};
+use request::{Request};
+use response::{Response};
+#[cfg(feature = "scram")]
+use sha2::{Digest, Sha256};
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+
+/// Errors that occur while preparing or reading authentication data
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum AuthError {
+    /// Data that should be base64 encoded could not be decoded
+    InvalidBase64,
+    /// The server sent a challenge that could not be understood
+    InvalidChallenge,
+    /// The server failed to prove it knows the credentials
+    VerificationFailed,
+    /// The server rejected the credentials
+    Rejected,
+    /// The server sent a reply that doesn't fit the exchange
+    UnexpectedReply,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::InvalidBase64 => "invalid base64 data",
+            AuthError::InvalidChallenge => "invalid server challenge",
+            AuthError::VerificationFailed => "server verification failed",
+            AuthError::Rejected => "authentication rejected",
+            AuthError::UnexpectedReply => "unexpected reply during authentication",
+        }
+    }
+}
+
+
+/// The server's reply to `AUTH`, or to a response to a challenge
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum AuthReply {
+    /// `334`, with the base64 challenge, which may be empty
+    Continue(String),
+    /// `235`, the client is authenticated
+    Success,
+    /// `535`, the credentials were rejected
+    Failure,
+    /// `432`, the user must change their password first
+    PasswordTransitionNeeded,
+    /// `454`, authentication failed for a reason that may be temporary
+    TemporaryFailure,
+    /// Any other reply, e.g. `504` for an unsupported mechanism
+    Other(Response),
+}
+
+impl AuthReply {
+    /// Classify a reply by its code
+    pub fn from_response(response: &Response) -> AuthReply {
+        match response.code.numeric() {
+            codes::AUTH_CONTINUE => AuthReply::Continue(
+                response.text.get(0).map_or("", |line| line.trim()).to_string()),
+            codes::AUTH_SUCCEEDED => AuthReply::Success,
+            codes::AUTH_FAILED => AuthReply::Failure,
+            codes::PASSWORD_TRANSITION_NEEDED => AuthReply::PasswordTransitionNeeded,
+            codes::AUTH_TEMPORARY_FAILURE => AuthReply::TemporaryFailure,
+            _ => AuthReply::Other(response.clone()),
+        }
+    }
+}
+
+
+/// Encode authentication data as base64
+pub(crate) fn b64_encode<T: AsRef<[u8]>>(input: T) -> String {
+    base64::encode(input.as_ref())
+}
+
+/// Decode base64 authentication data
+pub(crate) fn b64_decode<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, AuthError> {
+    base64::decode(input.as_ref()).map_err(|_| AuthError::InvalidBase64)
+}
+
+
+
+/// A SASL mechanism supported by `AuthExchange`
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub enum AuthMechanism {
+    /// `PLAIN` (RFC 4616), sent as an initial response
+    Plain,
+    /// `LOGIN`, answering the username and password challenges
+    Login,
+}
+
+impl AuthMechanism {
+    /// The mechanism name, as used in `AUTH`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN",
+        }
+    }
+}
+
+
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+enum AuthStep {
+    Start,
+    Username,
+    Password,
+    Outcome,
+    Done,
+}
+
+
+/// The client side of an `AUTH` exchange
+///
+/// Call `start` for the initial request, then pass each server reply to
+/// `next`, sending any request it returns. The exchange is complete when
+/// `next` returns `Ok(None)`.
+#[derive(Clone,Debug)]
+pub struct AuthExchange {
+    mechanism: AuthMechanism,
+    username: String,
+    password: String,
+    step: AuthStep,
+}
+
+impl AuthExchange {
+    pub fn new<S>(mechanism: AuthMechanism, username: S, password: S) -> Self
+    where S: Into<String>
+    {
+        AuthExchange {
+            mechanism,
+            username: username.into(),
+            password: password.into(),
+            step: AuthStep::Start,
+        }
+    }
+
+    /// The `AUTH` request that starts the exchange
+    pub fn start(&mut self) -> Request {
+        let data = match self.mechanism {
+            AuthMechanism::Plain => {
+                self.step = AuthStep::Outcome;
+                Some(b64_encode(format!("{}\0{}\0{}", self.username, self.username, self.password)))
+            },
+            AuthMechanism::Login => {
+                self.step = AuthStep::Username;
+                None
+            },
+        };
+        Request::Auth {
+            method: Some(self.mechanism.name().to_string()),
+            data,
+        }
+    }
+
+    /// Handle a server reply, returning the next request to send, if any
+    ///
+    /// A `334` reply asks for the next step, `235` completes the exchange, and
+    /// `535` means the credentials were rejected.
+    pub fn next(&mut self, response: &Response) -> Result<Option<Request>, AuthError> {
+        let step = self.step;
+        self.step = AuthStep::Done;
+        let data = match (AuthReply::from_response(response), step) {
+            (_, AuthStep::Start) | (_, AuthStep::Done) => return Err(AuthError::UnexpectedReply),
+            (AuthReply::Success, AuthStep::Outcome) => return Ok(None),
+            (AuthReply::Failure, _) => return Err(AuthError::Rejected),
+            (AuthReply::Continue(_), AuthStep::Username) |
+            (AuthReply::Continue(_), AuthStep::Password) => {
+                // Prefer what the prompt asks for, over the expected order.
+                let wants_password = match login_prompt(response) {
+                    LoginPrompt::Username => false,
+                    LoginPrompt::Password => true,
+                    LoginPrompt::Unknown => step == AuthStep::Password,
+                };
+                if wants_password {
+                    self.step = AuthStep::Outcome;
+                    b64_encode(&self.password)
+                } else {
+                    self.step = AuthStep::Password;
+                    b64_encode(&self.username)
+                }
+            },
+            _ => return Err(AuthError::UnexpectedReply),
+        };
+        Ok(Some(Request::Auth { method: None, data: Some(data) }))
+    }
+
+    /// Tells if the exchange has finished, successfully or not
+    pub fn is_done(&self) -> bool {
+        self.step == AuthStep::Done
+    }
+}
+
+
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+enum LoginPrompt {
+    Username,
+    Password,
+    Unknown,
+}
+
+/// Read the prompt of a `LOGIN` challenge
+///
+/// Servers should send `Username:` and `Password:` in base64, but some send
+/// them as is, so the raw text is used if it doesn't decode.
+fn login_prompt(response: &Response) -> LoginPrompt {
+    let text = response.text.get(0).map_or("", |line| line.trim());
+    let prompt = b64_decode(text).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or_else(|| text.to_string())
+        .to_ascii_lowercase();
+    if prompt.starts_with("user") {
+        LoginPrompt::Username
+    } else if prompt.starts_with("pass") {
+        LoginPrompt::Password
+    } else {
+        LoginPrompt::Unknown
+    }
+}
+
+
+/// The SCRAM-SHA-256 client-final message, and the expected server response
+#[cfg(feature = "scram")]
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct ScramClientFinal {
+    /// The client-final message to send to the server
+    pub message: String,
+    /// The server signature the server-final message should contain
+    pub server_signature: Vec<u8>,
+}
+
+#[cfg(feature = "scram")]
+impl ScramClientFinal {
+    /// Check the server-final message against the expected signature
+    pub fn verify(&self, server_final: &str) -> Result<(), AuthError> {
+        let signature = scram_attr(server_final, 'v')
+            .ok_or(AuthError::InvalidChallenge)?;
+        if b64_decode(signature)? == self.server_signature {
+            Ok(())
+        } else {
+            Err(AuthError::VerificationFailed)
+        }
+    }
+}
+
+/// Build the SCRAM-SHA-256 client-first message
+///
+/// The nonce should be a random printable string, not containing `,`. Channel
+/// binding is not supported, and the username is not normalized using SASLprep.
+///
+/// The result is the raw message, which must be base64 encoded before being
+/// sent in an `AUTH` request.
+#[cfg(feature = "scram")]
+pub fn scram_sha256_client_first(username: &str, nonce: &str) -> String {
+    let username = username.replace('=', "=3D").replace(',', "=2C");
+    format!("n,,n={},r={}", username, nonce)
+}
+
+/// Build the SCRAM-SHA-256 client-final message
+///
+/// Takes the client-first message as built by `scram_sha256_client_first`,
+/// and the decoded server-first message received in response.
+#[cfg(feature = "scram")]
+pub fn scram_sha256_client_final(client_first: &str, server_first: &str, password: &str)
+        -> Result<ScramClientFinal, AuthError> {
+    let client_first_bare = client_first.splitn(3, ',').nth(2)
+        .ok_or(AuthError::InvalidChallenge)?;
+    let client_nonce = scram_attr(client_first_bare, 'r')
+        .ok_or(AuthError::InvalidChallenge)?;
+
+    let nonce = scram_attr(server_first, 'r')
+        .ok_or(AuthError::InvalidChallenge)?;
+    let salt = b64_decode(scram_attr(server_first, 's')
+        .ok_or(AuthError::InvalidChallenge)?)?;
+    let iterations = scram_attr(server_first, 'i')
+        .and_then(|i| i.parse::<u32>().ok())
+        .ok_or(AuthError::InvalidChallenge)?;
+    if !nonce.starts_with(client_nonce) || nonce.len() == client_nonce.len() || iterations == 0 {
+        return Err(AuthError::InvalidChallenge);
+    }
+
+    // Hi(password, salt, i), which is PBKDF2 with HMAC-SHA-256.
+    let mut block = salt;
+    block.extend_from_slice(&[0, 0, 0, 1]);
+    let mut u = hmac_sha256(password.as_bytes(), &block);
+    let mut salted_password = u.clone();
+    for _ in 1..iterations {
+        u = hmac_sha256(password.as_bytes(), &u);
+        for (x, y) in salted_password.iter_mut().zip(u.iter()) {
+            *x ^= *y;
+        }
+    }
+
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let without_proof = format!("c={},r={}", b64_encode("n,,"), nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+    let proof = client_key.iter().zip(client_signature.iter())
+        .map(|(x, y)| x ^ y)
+        .collect::<Vec<_>>();
+
+    let server_key = hmac_sha256(&salted_password, b"Server Key");
+    Ok(ScramClientFinal {
+        message: format!("{},p={}", without_proof, b64_encode(proof)),
+        server_signature: hmac_sha256(&server_key, auth_message.as_bytes()),
+    })
+}
+
+/// Find an attribute value in a SCRAM message
+#[cfg(feature = "scram")]
+fn scram_attr(message: &str, name: char) -> Option<&str> {
+    message.split(',').find(|attr| {
+        let mut chars = attr.chars();
+        chars.next() == Some(name) && chars.next() == Some('=')
+    }).map(|attr| &attr[2..])
+}
+
+#[cfg(feature = "scram")]
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key)
+        .expect("HMAC accepts keys of any length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use auth::{AuthError, AuthExchange, AuthMechanism, AuthReply, b64_decode, b64_encode};
+    use request::{Request};
+    use response::{Response};
+
+    #[test]
+    fn test() {
+        for (input, expect) in vec![
+            ("".as_bytes(), ""),
+            ("Username:".as_bytes(), "VXNlcm5hbWU6"),
+            ("john\0john\0secret".as_bytes(), "am9obgBqb2huAHNlY3JldA=="),
+        ] {
+            assert_eq!(b64_encode(input), expect);
+            assert_eq!(b64_decode(expect), Ok(input.to_vec()));
+        }
+
+        assert_eq!(b64_decode("not base64!"), Err(AuthError::InvalidBase64));
+    }
+
+    #[test]
+    fn test_exchange() {
+        let reply = |s: &str| s.parse::<Response>().unwrap();
+        let auth = |data: &str| Some(Request::Auth { method: None, data: Some(data.to_string()) });
+
+        let mut login = AuthExchange::new(AuthMechanism::Login, "john", "secret");
+        assert_eq!(login.start(), Request::Auth { method: Some("LOGIN".to_string()), data: None });
+        assert_eq!(login.next(&reply("334 VXNlcm5hbWU6\r\n")), Ok(auth("am9obg==")));
+        assert_eq!(login.next(&reply("334 UGFzc3dvcmQ6\r\n")), Ok(auth("c2VjcmV0")));
+        assert!(!login.is_done());
+        assert_eq!(login.next(&reply("235 Authentication successful\r\n")), Ok(None));
+        assert!(login.is_done());
+        assert_eq!(login.next(&reply("235 Again\r\n")), Err(AuthError::UnexpectedReply));
+
+        let mut login = AuthExchange::new(AuthMechanism::Login, "john", "wrong");
+        login.start();
+        assert_eq!(login.next(&reply("334 VXNlcm5hbWU6\r\n")), Ok(auth("am9obg==")));
+        assert_eq!(login.next(&reply("334 UGFzc3dvcmQ6\r\n")), Ok(auth("d3Jvbmc=")));
+        assert_eq!(login.next(&reply("535 Authentication failed\r\n")), Err(AuthError::Rejected));
+        assert!(login.is_done());
+
+        let mut login = AuthExchange::new(AuthMechanism::Login, "john", "secret");
+        login.start();
+        assert_eq!(login.next(&reply("235 Too early\r\n")), Err(AuthError::UnexpectedReply));
+
+        for (user_prompt, pass_prompt) in vec![
+            ("334 VXNlcm5hbWU6\r\n", "334 UGFzc3dvcmQ6\r\n"),
+            ("334 Username:\r\n", "334 Password:\r\n"),
+            ("334 username:\r\n", "334 UGFzc3dvcmQ6\r\n"),
+            ("334 \r\n", "334 \r\n"),
+        ] {
+            let mut login = AuthExchange::new(AuthMechanism::Login, "john", "secret");
+            login.start();
+            assert_eq!(login.next(&reply(user_prompt)), Ok(auth("am9obg==")));
+            assert_eq!(login.next(&reply(pass_prompt)), Ok(auth("c2VjcmV0")));
+            assert_eq!(login.next(&reply("235 Ok\r\n")), Ok(None));
+        }
+
+        // A server skipping the username prompt gets the password.
+        let mut login = AuthExchange::new(AuthMechanism::Login, "john", "secret");
+        login.start();
+        assert_eq!(login.next(&reply("334 Password:\r\n")), Ok(auth("c2VjcmV0")));
+        assert_eq!(login.next(&reply("235 Ok\r\n")), Ok(None));
+
+        let mut plain = AuthExchange::new(AuthMechanism::Plain, "john", "secret");
+        assert_eq!(plain.start(), Request::Auth {
+            method: Some("PLAIN".to_string()),
+            data: Some("am9obgBqb2huAHNlY3JldA==".to_string()),
+        });
+        assert_eq!(plain.next(&reply("334 \r\n")), Err(AuthError::UnexpectedReply));
+    }
+
+    #[test]
+    fn test_reply() {
+        for (input, expect) in vec![
+            ("334 VXNlcm5hbWU6\r\n", AuthReply::Continue("VXNlcm5hbWU6".to_string())),
+            ("334 \r\n", AuthReply::Continue(String::new())),
+            ("334\r\n", AuthReply::Continue(String::new())),
+            ("235 2.7.0 Authentication successful\r\n", AuthReply::Success),
+            ("535 5.7.8 Authentication credentials invalid\r\n", AuthReply::Failure),
+            ("432 4.7.12 A password transition is needed\r\n", AuthReply::PasswordTransitionNeeded),
+            ("454 4.7.0 Temporary authentication failure\r\n", AuthReply::TemporaryFailure),
+        ] {
+            let response: Response = input.parse().unwrap();
+            assert_eq!(AuthReply::from_response(&response), expect);
+        }
+
+        let response: Response = "504 5.5.4 Unrecognized authentication type\r\n".parse().unwrap();
+        assert_eq!(AuthReply::from_response(&response), AuthReply::Other(response.clone()));
+    }
+
+    #[cfg(feature = "scram")]
+    #[test]
+    fn test_scram() {
+        use auth::{scram_sha256_client_final, scram_sha256_client_first};
+
+        // Example from RFC 7677, section 3.
+        let client_first = scram_sha256_client_first("user", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(client_first, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = scram_sha256_client_final(&client_first, server_first, "pencil")
+            .unwrap();
+        assert_eq!(client_final.message,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        assert_eq!(client_final.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="), Ok(()));
+        assert_eq!(client_final.verify("v=AAAA"), Err(AuthError::VerificationFailed));
+
+        assert_eq!(scram_sha256_client_first("a=b,c", "n"), "n,,n=a=3Db=2Cc,r=n");
+        for server_first in vec![
+            "r=someothernonce,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            "r=rOprNGfwEbeRWgbNEkqO,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            "r=rOprNGfwEbeRWgbNEkqOabc,s=W22ZaJ0SNY7soEsUEjb6gQ==",
+            "r=rOprNGfwEbeRWgbNEkqOabc,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=0",
+        ] {
+            assert_eq!(scram_sha256_client_final(&client_first, server_first, "pencil"),
+                Err(AuthError::InvalidChallenge));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Structures that model the extensions advertised by a server
+//!
+//! A server lists the extensions it supports in the response to `EHLO`. The
+//! first line of the response is the server greeting, and each following line
+//! is an extension keyword, optionally followed by parameters.
+//!
+//! Some servers list several keywords on one line, such as
+//! `250 8BITMIME PIPELINING`. On the wire this looks like a keyword with
+//! parameters, so words are read as follows: the first word of a line is a
+//! keyword, and each following word is a parameter to it, unless the keyword
+//! is a known extension without parameters, or the word itself is a known
+//! extension keyword. In those cases, the word starts a new keyword instead.
+//! So `250 SIZE 1024 PIPELINING` is read as two extensions.
+//!
+//! This is ambiguous for unknown extensions, whose keywords are always read
+//! as parameters of the keyword before them on the same line.
+
+use codes;
+use response::{Code, Response};
+
+
+/// An extension advertised in an `EHLO` response
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct Extension {
+    /// The extension keyword, in uppercase
+    pub keyword: String,
+    /// Parameters to the extension, if any
+    pub params: Vec<String>,
+}
+
+
+/// Extensions that are known to take no parameters
+const NO_PARAMS: &'static [&'static str] = &[
+    "8BITMIME",
+    "BINARYMIME",
+    "CHUNKING",
+    "DSN",
+    "ENHANCEDSTATUSCODES",
+    "PIPELINING",
+    "SMTPUTF8",
+    "STARTTLS",
+];
+
+
+/// Extensions that are known to take parameters
+const WITH_PARAMS: &'static [&'static str] = &[
+    "AUTH",
+    "SIZE",
+];
+
+
+/// The set of extensions advertised by a server
+#[derive(PartialEq,Eq,Clone,Debug,Default)]
+pub struct Capabilities {
+    pub extensions: Vec<Extension>,
+}
+
+impl Capabilities {
+    /// Read the extensions from an `EHLO` response
+    pub fn from_response(response: &Response) -> Capabilities {
+        let mut extensions: Vec<Extension> = vec![];
+        for line in response.text.iter().skip(1) {
+            let mut takes_params = false;
+            for word in line.split_whitespace() {
+                let keyword = word.to_ascii_uppercase();
+                let is_known = NO_PARAMS.contains(&keyword.as_str()) ||
+                    WITH_PARAMS.contains(&keyword.as_str());
+                if takes_params && !is_known {
+                    if let Some(ext) = extensions.last_mut() {
+                        ext.params.push(word.to_string());
+                        continue;
+                    }
+                }
+                takes_params = !NO_PARAMS.contains(&keyword.as_str());
+                extensions.push(Extension { keyword, params: vec![] });
+            }
+        }
+        Capabilities { extensions }
+    }
+
+    /// Render the extensions as an `EHLO` response, for use in a server
+    ///
+    /// The first line is the server domain, and each extension follows on its
+    /// own line, with its parameters. This is the reverse of `from_response`.
+    pub fn to_response(&self, domain: &str) -> Response {
+        let mut text = vec![domain.to_string()];
+        for ext in &self.extensions {
+            let mut line = ext.keyword.clone();
+            for param in &ext.params {
+                line.push(' ');
+                line.push_str(param);
+            }
+            text.push(line);
+        }
+        Response::new(Code::from_numeric(codes::OK).unwrap(), text)
+    }
+
+    /// Find an extension by keyword
+    pub fn get(&self, keyword: &str) -> Option<&Extension> {
+        self.extensions.iter()
+            .find(|ext| ext.keyword.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Tells if the extension with the given keyword was advertised
+    pub fn has(&self, keyword: &str) -> bool {
+        self.get(keyword).is_some()
+    }
+
+    /// Tells if the server supports `PIPELINING` (RFC 2920)
+    pub fn pipelining(&self) -> bool {
+        self.has("PIPELINING")
+    }
+
+    /// Tells if the server supports `CHUNKING` (RFC 3030)
+    pub fn chunking(&self) -> bool {
+        self.has("CHUNKING")
+    }
+
+    /// Tells if the server supports `8BITMIME` (RFC 6152)
+    pub fn eight_bit_mime(&self) -> bool {
+        self.has("8BITMIME")
+    }
+
+    /// Tells if the server supports `DSN` (RFC 3461)
+    pub fn dsn(&self) -> bool {
+        self.has("DSN")
+    }
+
+    /// Tells if the server supports `ENHANCEDSTATUSCODES` (RFC 2034)
+    pub fn enhanced_status_codes(&self) -> bool {
+        self.has("ENHANCEDSTATUSCODES")
+    }
+
+    /// Tells if the server supports `STARTTLS` (RFC 3207)
+    pub fn starttls(&self) -> bool {
+        self.has("STARTTLS")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use capabilities::{Capabilities, Extension};
+
+    #[test]
+    fn test() {
+        let caps = Capabilities::from_response(
+            &"250-mail.example.test\r\n250-PIPELINING\r\n250-size 1024\r\n250 AUTH PLAIN LOGIN\r\n"
+                .parse().unwrap());
+        assert_eq!(caps.extensions, vec![
+            Extension { keyword: "PIPELINING".to_string(), params: vec![] },
+            Extension { keyword: "SIZE".to_string(), params: vec!["1024".to_string()] },
+            Extension {
+                keyword: "AUTH".to_string(),
+                params: vec!["PLAIN".to_string(), "LOGIN".to_string()],
+            },
+        ]);
+        assert!(caps.pipelining());
+        assert!(caps.has("Size"));
+        assert!(!caps.starttls());
+        assert!(!caps.dsn());
+
+        let caps = Capabilities::from_response(
+            &"250-mail.example.test\r\n250-8BITMIME PIPELINING\r\n250-SIZE 1024\r\n\
+              250-smtputf8 AUTH PLAIN\r\n250 X-CUSTOM A B\r\n"
+                .parse().unwrap());
+        assert_eq!(caps.extensions, vec![
+            Extension { keyword: "8BITMIME".to_string(), params: vec![] },
+            Extension { keyword: "PIPELINING".to_string(), params: vec![] },
+            Extension { keyword: "SIZE".to_string(), params: vec!["1024".to_string()] },
+            Extension { keyword: "SMTPUTF8".to_string(), params: vec![] },
+            Extension { keyword: "AUTH".to_string(), params: vec!["PLAIN".to_string()] },
+            Extension {
+                keyword: "X-CUSTOM".to_string(),
+                params: vec!["A".to_string(), "B".to_string()],
+            },
+        ]);
+
+        // Known keywords end the parameters of the keyword before them.
+        let caps = Capabilities::from_response(
+            &"250-mail.example.test\r\n250-SIZE 1024 PIPELINING\r\n250 AUTH PLAIN LOGIN 8BITMIME SIZE\r\n"
+                .parse().unwrap());
+        assert_eq!(caps.extensions, vec![
+            Extension { keyword: "SIZE".to_string(), params: vec!["1024".to_string()] },
+            Extension { keyword: "PIPELINING".to_string(), params: vec![] },
+            Extension {
+                keyword: "AUTH".to_string(),
+                params: vec!["PLAIN".to_string(), "LOGIN".to_string()],
+            },
+            Extension { keyword: "8BITMIME".to_string(), params: vec![] },
+            Extension { keyword: "SIZE".to_string(), params: vec![] },
+        ]);
+        assert!(caps.pipelining());
+        assert!(caps.eight_bit_mime());
+
+        let caps = Capabilities::from_response(
+            &"250 mail.example.test\r\n".parse().unwrap());
+        assert_eq!(caps.extensions, vec![]);
+        assert!(!caps.pipelining());
+    }
+
+    #[test]
+    fn test_to_response() {
+        let caps = Capabilities {
+            extensions: vec![
+                Extension { keyword: "SIZE".to_string(), params: vec!["1024".to_string()] },
+                Extension {
+                    keyword: "AUTH".to_string(),
+                    params: vec!["PLAIN".to_string(), "LOGIN".to_string()],
+                },
+                Extension { keyword: "STARTTLS".to_string(), params: vec![] },
+            ],
+        };
+        let response = caps.to_response("mail.example.test");
+        assert_eq!(response.to_string(),
+            "250-mail.example.test\r\n250-SIZE 1024\r\n250-AUTH PLAIN LOGIN\r\n250 STARTTLS\r\n");
+        assert_eq!(Capabilities::from_response(&response), caps);
+        assert!(Capabilities::from_response(&"250-mail.example.test\r\n250 DSN\r\n".parse().unwrap()).dsn());
+
+        assert_eq!(Capabilities::default().to_response("mail.example.test").to_string(),
+            "250 mail.example.test\r\n");
+    }
+}
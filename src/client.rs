@@ -88,24 +88,33 @@
 //! }
 //! ```
 
-use base64;
-use futures::{future, Future, Stream, Sink, Poll};
+use auth::{AuthExchange, AuthMechanism};
+use capabilities::{Capabilities};
+use codes;
+use futures::{future, stream, Async, AsyncSink, Future, Stream, Sink, StartSend, Poll};
+use line::{split_line};
 use native_tls::{Result as TlsResult, TlsConnector};
 use nom::{IResult as NomResult};
-use request::{ClientId, Request};
-use response::{Response, Severity};
+use request::{ClientId, Mailbox, MailBodyParam, MailParam, Request};
+use response::{Code, RawResponse, Response, Severity};
+use session::{SmtpSession};
+use std::collections::{VecDeque};
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Read, Write};
+use std::mem::{replace};
 use std::sync::{Arc};
+use std::time::{Duration, Instant};
 use bytes::{BufMut, BytesMut};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Encoder, Decoder, Framed};
 use tokio_proto::{TcpClient as TokioTcpClient};
 use tokio_proto::streaming::{Body};
-use tokio_proto::streaming::pipeline::{ClientProto as TokioClientProto, Frame, StreamingPipeline};
+use tokio_proto::streaming::pipeline::{ClientProto as TokioClientProto, Frame, StreamingPipeline, Transport};
 use tokio_tls::{TlsConnectorExt, TlsStream};
 
 // FIXME: `<T: Io + 'static>`, but E0122
-pub type ClientTransport<T> = Framed<ClientIo<T>, ClientCodec>;
 pub type ClientBindTransport<T> = Box<Future<Item = ClientTransport<T>, Error = IoError>>;
 pub type TcpClient = TokioTcpClient<StreamingPipeline<Body<Vec<u8>, IoError>>, ClientProto>;
 
@@ -163,16 +172,436 @@ pub struct ClientParams {
 }
 
 
+/// A point in time after which waiting should stop
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    /// Create a deadline the given amount of time from now
+    pub fn after(timeout: Duration) -> Self {
+        Deadline(Instant::now() + timeout)
+    }
+
+    /// Tells if the deadline has passed
+    pub fn has_elapsed(&self) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+
+/// The replies to an LMTP `DATA` command, one for each accepted recipient
+///
+/// An LMTP server reports delivery separately for each recipient that was
+/// accepted with `RCPT TO`, in the same order.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct DataReplies {
+    pub replies: Vec<(Mailbox, Response)>,
+}
+
+impl DataReplies {
+    /// The reply for a recipient
+    pub fn get(&self, recipient: &Mailbox) -> Option<&Response> {
+        self.replies.iter()
+            .find(|&&(ref rcpt, _)| rcpt == recipient)
+            .map(|&(_, ref response)| response)
+    }
+
+    /// Tells if delivery succeeded for all recipients
+    pub fn all_delivered(&self) -> bool {
+        self.replies.iter().all(|&(_, ref response)| response.code.severity.is_positive())
+    }
+
+    /// Combine the replies into a single one
+    ///
+    /// The code is that of the first failed delivery, or of the first reply
+    /// if all succeeded. Each line of text starts with the recipient, followed
+    /// by the code and text of its reply.
+    pub fn to_response(&self) -> Response {
+        let code = self.replies.iter()
+            .map(|&(_, ref response)| &response.code)
+            .find(|code| !code.severity.is_positive())
+            .or_else(|| self.replies.first().map(|&(_, ref response)| &response.code))
+            .cloned()
+            .unwrap_or_else(|| Code::from_numeric(codes::OK).expect("reply code is valid"));
+        let text = self.replies.iter()
+            .flat_map(|&(ref rcpt, ref response)| {
+                response.text.iter()
+                    .map(move |line| format!("{} {} {}", rcpt, response.code, line))
+            })
+            .collect();
+        Response { code, text }
+    }
+}
+
+
+/// The default limit on the number of lines in a reply
+pub const DEFAULT_MAX_REPLY_LINES: usize = 100;
+
+
 /// The codec used to encode client requests and decode server responses
-#[derive(Default)]
 pub struct ClientCodec {
     escape_count: u8,
+    binary_mime: bool,
+    eight_bit_body: bool,
+    check_eight_bit_body: Option<bool>,
+    session: Option<SmtpSession>,
+    bdat_remaining: Option<usize>,
+    reply_timeout: Option<Duration>,
+    deadline: Option<Deadline>,
+    force_starttls: bool,
+    max_reply_lines: usize,
+    validate_requests: bool,
+    lmtp: bool,
+    data_replies: Option<(Vec<Mailbox>, Vec<Response>)>,
+    lmtp_sent: VecDeque<Option<Mailbox>>,
+    lmtp_accepted: Vec<Mailbox>,
+    lenient_line_endings: bool,
+    lenient_lines: BytesMut,
+    lenient_raw: Vec<u8>,
+}
+
+impl Default for ClientCodec {
+    fn default() -> Self {
+        ClientCodec {
+            escape_count: 0,
+            binary_mime: false,
+            eight_bit_body: false,
+            check_eight_bit_body: None,
+            session: None,
+            bdat_remaining: None,
+            reply_timeout: None,
+            deadline: None,
+            force_starttls: false,
+            max_reply_lines: DEFAULT_MAX_REPLY_LINES,
+            validate_requests: true,
+            lmtp: false,
+            data_replies: None,
+            lmtp_sent: VecDeque::new(),
+            lmtp_accepted: Vec::new(),
+            lenient_line_endings: false,
+            lenient_lines: BytesMut::new(),
+            lenient_raw: Vec::new(),
+        }
+    }
 }
 
 impl ClientCodec {
     pub fn new() -> Self {
         ClientCodec::default()
     }
+
+    /// Limit the number of lines in a reply
+    ///
+    /// Decoding fails with an `InvalidData` error as soon as a reply has more
+    /// lines, instead of buffering it. Defaults to `DEFAULT_MAX_REPLY_LINES`.
+    pub fn set_max_reply_lines(&mut self, max: usize) {
+        self.max_reply_lines = max;
+    }
+
+    /// Limit the time a response may take to arrive completely
+    ///
+    /// The deadline starts when the first part of a response is received. If
+    /// more data arrives after the deadline, but the response is still not
+    /// complete, decoding fails with a `TimedOut` error.
+    ///
+    /// The codec has no timer, so a reply that stalls without further data is
+    /// not detected here. See `ClientTransport::set_reply_timeout` for that.
+    pub fn set_reply_timeout(&mut self, timeout: Option<Duration>) {
+        self.reply_timeout = timeout;
+        self.deadline = None;
+    }
+
+    /// Allow sending `STARTTLS` even if the server did not advertise it
+    pub fn set_force_starttls(&mut self, force: bool) {
+        self.force_starttls = force;
+    }
+
+    /// Check requests for CR and LF characters before sending them
+    ///
+    /// This is enabled by default, and encoding a request that fails
+    /// `Request::validate` is an `InvalidInput` error. It can be disabled if
+    /// all requests are known to be safe.
+    pub fn set_validate_requests(&mut self, validate: bool) {
+        self.validate_requests = validate;
+    }
+
+    /// Check that a `DATA` body only has 8-bit bytes if declared
+    ///
+    /// If enabled, encoding an 8-bit body is an `InvalidInput` error, unless
+    /// `MAIL FROM` had `BODY=8BITMIME` (RFC 6152). By default, the check is
+    /// enabled if the session given to `set_session` lacks `8BITMIME`, as the
+    /// body cannot be delivered intact then. If the server supports it, or the
+    /// session is unknown, undeclared bodies are let through, because many
+    /// senders never declare the body type.
+    pub fn set_check_eight_bit_body(&mut self, check: bool) {
+        self.check_eight_bit_body = Some(check);
+    }
+
+    /// Set the session state the requests are sent in
+    ///
+    /// The codec uses it to check what the server supports, and every request
+    /// is then checked as by `encode_batch`. `ClientProto` sets it once `EHLO`
+    /// has completed.
+    pub fn set_session(&mut self, session: SmtpSession) {
+        self.session = Some(session);
+    }
+
+    /// The session state given to `set_session`
+    pub fn session(&self) -> Option<&SmtpSession> {
+        self.session.as_ref()
+    }
+
+    /// Speak LMTP (RFC 2033) instead of SMTP
+    ///
+    /// LMTP replaces `EHLO` with `LHLO`, so `EHLO` requests are sent as `LHLO`
+    /// instead. After `DATA`, an LMTP server replies once for each accepted
+    /// recipient, rather than once for the whole message. The codec keeps
+    /// track of the recipients accepted in the transaction, and collects these
+    /// replies into a single frame, see `expect_data_replies`. `BDAT` is not
+    /// handled this way, its replies are decoded one by one.
+    pub fn set_lmtp(&mut self, lmtp: bool) {
+        self.lmtp = lmtp;
+    }
+
+    /// Accept replies with lines terminated by a bare LF
+    ///
+    /// Some broken servers send `\n` instead of `\r\n`. In lenient mode,
+    /// these are read as if they were `\r\n`, but `decode_raw` still returns
+    /// the bytes as received. By default, the codec is strict, and such
+    /// replies are never complete.
+    pub fn set_lenient_line_endings(&mut self, lenient: bool) {
+        self.lenient_line_endings = lenient;
+    }
+
+    /// Tells if the codec speaks LMTP
+    pub fn is_lmtp(&self) -> bool {
+        self.lmtp
+    }
+
+    /// Expect the LMTP replies to `DATA`, for the given accepted recipients
+    ///
+    /// This is used after the message body was sent, and switches to
+    /// collecting the replies using `decode_data_replies`. An LMTP codec does
+    /// this itself once the body of `DATA` is sent, for the recipients whose
+    /// `RCPT TO` it decoded a positive reply to, and `decode` then returns
+    /// the replies as one, see `DataReplies::to_response`.
+    pub fn expect_data_replies(&mut self, recipients: Vec<Mailbox>) {
+        let replies = Vec::with_capacity(recipients.len());
+        self.data_replies = Some((recipients, replies));
+    }
+
+    /// Decode the LMTP replies to `DATA`, once they have all arrived
+    ///
+    /// This returns `None` until there is a reply for every recipient given to
+    /// `expect_data_replies`, and the codec then returns to decoding single
+    /// replies. It fails if no replies are expected.
+    pub fn decode_data_replies(&mut self, buf: &mut BytesMut) -> IoResult<Option<DataReplies>> {
+        loop {
+            let complete = match self.data_replies {
+                Some((ref recipients, ref replies)) => replies.len() >= recipients.len(),
+                None => return Err(IoError::new(IoErrorKind::InvalidInput,
+                    "not expecting data replies")),
+            };
+            if complete {
+                let (recipients, replies) = self.data_replies.take()
+                    .expect("data replies are expected");
+                return Ok(Some(DataReplies {
+                    replies: recipients.into_iter().zip(replies).collect(),
+                }));
+            }
+
+            let response = match self.decode_next(buf)? {
+                Some(res) => res.response,
+                None => return Ok(None),
+            };
+            if let Some((_, ref mut replies)) = self.data_replies {
+                replies.push(response);
+            }
+        }
+    }
+
+    /// Encode several requests at once, as a single pipelined batch
+    ///
+    /// This fails if more than one request is given, but the session does not
+    /// allow pipelining. It also fails if `STARTTLS` is given, but the server
+    /// did not advertise it, unless forced using `set_force_starttls`.
+    ///
+    /// It also fails if `MAIL FROM` has `BODY=8BITMIME`, but the server did
+    /// not advertise `8BITMIME`.
+    ///
+    /// If `EHLO` has completed, but the server did not advertise `DSN`, the
+    /// DSN parameters are removed from `MAIL FROM` and `RCPT TO`, see
+    /// `Request::without_dsn`.
+    pub fn encode_batch(&mut self, session: &SmtpSession, requests: Vec<Request>, buf: &mut BytesMut)
+            -> IoResult<()> {
+        if requests.len() > 1 && !session.can_pipeline() {
+            return Err(IoError::new(IoErrorKind::InvalidInput,
+                "session does not support pipelining"));
+        }
+        let requests = requests.into_iter()
+            .map(|request| check_request(session, self.force_starttls, request))
+            .collect::<IoResult<Vec<_>>>()?;
+        for request in requests {
+            self.encode_request(request, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Encode a single request, which has been checked against the session
+    fn encode_request(&mut self, mut message: Request, buf: &mut BytesMut) -> IoResult<()> {
+        if self.lmtp {
+            if let Request::Ehlo(id) = message {
+                message = Request::Lhlo(id);
+            }
+        }
+        if self.validate_requests {
+            message.validate()
+                .map_err(|err| IoError::new(IoErrorKind::InvalidInput, err))?;
+        }
+        if self.lmtp {
+            let rcpt = match message {
+                Request::Rcpt { ref to, .. } => Some(to.clone()),
+                _ => None,
+            };
+            self.lmtp_sent.push_back(rcpt);
+        }
+        // A `BINARYMIME` message cannot be transferred using `DATA`.
+        match message {
+            Request::Mail { ref params, .. } => {
+                self.lmtp_accepted.clear();
+                self.binary_mime = params.contains(
+                    &MailParam::Body(MailBodyParam::BinaryMime));
+                self.eight_bit_body = self.binary_mime || params.contains(
+                    &MailParam::Body(MailBodyParam::EightBitMime));
+            },
+            Request::Data if self.binary_mime => {
+                return Err(IoError::new(IoErrorKind::InvalidInput,
+                    "BODY=BINARYMIME requires BDAT, not DATA"));
+            },
+            // The body starts at the start of a line.
+            Request::Data => self.escape_count = 2,
+            // The chunk is sent as is.
+            Request::Bdat { size, .. } => self.bdat_remaining = Some(size),
+            Request::Rset => {
+                self.binary_mime = false;
+                self.eight_bit_body = false;
+                self.lmtp_accepted.clear();
+            },
+            _ => {},
+        }
+        #[cfg(feature = "tracing")]
+        trace_request(&message);
+        buf.put_slice(&message.to_bytes());
+        Ok(())
+    }
+
+    /// Decode a reply, along with the bytes it was decoded from
+    ///
+    /// This is what `decode` does, but the bytes are kept, e.g. for logging
+    /// replies exactly as the server sent them.
+    pub fn decode_raw(&mut self, buf: &mut BytesMut) -> IoResult<Option<RawResponse>> {
+        let res = self.decode_next(buf)?;
+        if let Some(ref res) = res {
+            // Pair the reply with its request, to know the accepted recipients.
+            if self.lmtp && self.data_replies.is_none() {
+                if let Some(Some(rcpt)) = self.lmtp_sent.pop_front() {
+                    if res.response.code.severity.is_positive() {
+                        self.lmtp_accepted.push(rcpt);
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Decode the next reply, with or without lenient line endings
+    fn decode_next(&mut self, buf: &mut BytesMut) -> IoResult<Option<RawResponse>> {
+        if !self.lenient_line_endings {
+            return self.decode_reply(buf);
+        }
+        let mut lines = replace(&mut self.lenient_lines, BytesMut::new());
+        let res = self.decode_lenient(buf, &mut lines);
+        self.lenient_lines = lines;
+        res
+    }
+
+    /// Decode a reply with CRLF line endings
+    fn decode_reply(&mut self, buf: &mut BytesMut) -> IoResult<Option<RawResponse>> {
+        let mut bytes: usize = 0;
+
+        let res = match Response::parse_raw(buf.as_ref()) {
+            NomResult::Done(_, ref res) if res.response.text.len() > self.max_reply_lines => {
+                return Err(IoError::new(IoErrorKind::InvalidData, "too many reply lines"));
+            },
+            NomResult::Done(rest, res) => {
+                // Calculate how much data to drain.
+                bytes = buf.len() - rest.len();
+
+                // Intermediate messages are passed on as well: `AUTH` answers
+                // `334` challenges, and `ClientTransport` waits for `354`.
+                #[cfg(feature = "tracing")]
+                ::tracing::debug!(code = %res.response.code, "received response");
+                debug!("S: {:?}", &res.response);
+                Ok(Some(res))
+            },
+            NomResult::Incomplete(_) => {
+                // All complete lines belong to the incomplete reply.
+                let lines = buf.windows(2).filter(|window| *window == b"\r\n").count();
+                if lines > self.max_reply_lines {
+                    return Err(IoError::new(IoErrorKind::InvalidData, "too many reply lines"));
+                }
+                match self.deadline {
+                    Some(deadline) if deadline.has_elapsed() => {
+                        return Err(IoError::new(IoErrorKind::TimedOut,
+                            "timed out waiting for complete response"));
+                    },
+                    Some(_) => {},
+                    None => self.deadline = self.reply_timeout.map(Deadline::after),
+                }
+                Ok(None)
+            },
+            NomResult::Error(_) => {
+                Err(IoError::new(IoErrorKind::InvalidData, "malformed response"))
+            },
+        };
+
+        // Drain parsed data.
+        if bytes != 0 {
+            buf.split_to(bytes);
+            self.deadline = None;
+        }
+
+        res
+    }
+
+    /// Decode a reply that may have bare LF line endings
+    ///
+    /// Complete lines are moved to `lines` one at a time, with a CRLF ending,
+    /// and the reply is decoded from there. The bytes as received replace the
+    /// normalized ones in the `RawResponse`.
+    fn decode_lenient(&mut self, buf: &mut BytesMut, lines: &mut BytesMut)
+            -> IoResult<Option<RawResponse>> {
+        loop {
+            let len = buf.len();
+            let line = split_line(buf, false)?;
+            if let Some(ref line) = line {
+                let ending: &[u8] = if len - buf.len() > line.len() + 1 { b"\r\n" } else { b"\n" };
+                self.lenient_raw.extend_from_slice(line);
+                self.lenient_raw.extend_from_slice(ending);
+                lines.reserve(line.len() + 2);
+                lines.put_slice(line);
+                lines.put_slice(b"\r\n");
+            }
+            match self.decode_reply(lines)? {
+                Some(res) => {
+                    let raw = replace(&mut self.lenient_raw, Vec::new());
+                    return Ok(Some(RawResponse { raw, ..res }));
+                },
+                None if line.is_none() => return Ok(None),
+                None => {},
+            }
+        }
+    }
 }
 
 impl Encoder for ClientCodec {
@@ -183,19 +612,46 @@ impl Encoder for ClientCodec {
         debug!("C: {:?}", &frame);
         match frame {
             Frame::Message { message, .. } => {
-                buf.put_slice(message.to_string().as_bytes());
+                let message = match self.session {
+                    Some(ref session) => check_request(session, self.force_starttls, message)?,
+                    None => message,
+                };
+                self.encode_request(message, buf)?;
+            },
+            Frame::Body { chunk: Some(chunk) } if self.bdat_remaining.is_some() => {
+                let remaining = self.bdat_remaining.unwrap_or(0);
+                if chunk.len() > remaining {
+                    return Err(IoError::new(IoErrorKind::InvalidInput,
+                        "BDAT chunk larger than announced"));
+                }
+                self.bdat_remaining = Some(remaining - chunk.len());
+                buf.put_slice(&chunk);
+            },
+            Frame::Body { chunk: None } if self.bdat_remaining.is_some() => {
+                if self.bdat_remaining.take() != Some(0) {
+                    return Err(IoError::new(IoErrorKind::InvalidInput,
+                        "BDAT chunk smaller than announced"));
+                }
             },
             Frame::Body { chunk: Some(chunk) } => {
+                let eight_bit = chunk.iter().any(|&byte| byte >= 0x80);
+                let session = self.session.as_ref();
+                let check = self.check_eight_bit_body
+                    .unwrap_or_else(|| session.map_or(false, |session| !session.eight_bit_mime()));
+                if eight_bit && check && !self.eight_bit_body {
+                    return Err(IoError::new(IoErrorKind::InvalidInput,
+                        "8-bit body requires BODY=8BITMIME"));
+                }
                 // Escape lines starting with a '.'
                 // FIXME: additional encoding for non-ASCII?
                 let mut start = 0;
                 for (idx, byte) in chunk.iter().enumerate() {
-                    match self.escape_count {
-                        0 => self.escape_count = if *byte == b'\r' { 1 } else { 0 },
-                        1 => self.escape_count = if *byte == b'\n' { 2 } else { 0 },
-                        2 => self.escape_count = if *byte == b'.'  { 3 } else { 0 },
-                        _ => unreachable!(),
-                    }
+                    self.escape_count = match (self.escape_count, *byte) {
+                        (_, b'\r') => 1,
+                        (1, b'\n') => 2,
+                        (2, b'.') => 3,
+                        _ => 0,
+                    };
                     if self.escape_count == 3 {
                         self.escape_count = 0;
                         buf.put_slice(&chunk[start..idx]);
@@ -213,6 +669,11 @@ impl Encoder for ClientCodec {
                     _ => unreachable!(),
                 }
                 self.escape_count = 0;
+                // An LMTP server replies for each accepted recipient.
+                if self.lmtp && !self.lmtp_accepted.is_empty() {
+                    let recipients = replace(&mut self.lmtp_accepted, Vec::new());
+                    self.expect_data_replies(recipients);
+                }
             },
             Frame::Error { error } => {
                 panic!("unimplemented error handling: {:?}", error);
@@ -227,45 +688,190 @@ impl Decoder for ClientCodec {
     type Error = IoError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> IoResult<Option<Self::Item>> {
-        let mut bytes: usize = 0;
+        // The LMTP replies to `DATA` are a single frame, to keep the pipeline aligned.
+        if self.data_replies.is_some() {
+            return Ok(self.decode_data_replies(buf)?
+                .map(|replies| Frame::Message { message: replies.to_response(), body: false }));
+        }
+        Ok(self.decode_raw(buf)?.map(|res| Frame::Message { message: res.response, body: false }))
+    }
+}
 
-        let res = match Response::parse(buf.as_ref()) {
-            NomResult::Done(rest, res) => {
-                // Calculate how much data to drain.
-                bytes = buf.len() - rest.len();
 
-                // Drop intermediate messages (e.g. DATA 354)
-                if res.code.severity == Severity::PositiveIntermediate {
-                    Ok(None)
-                } else {
-                    let frame = Frame::Message { message: res, body: false };
-                    debug!("S: {:?}", &frame);
-                    Ok(Some(frame))
-                }
-            },
-            NomResult::Incomplete(_) => {
-                Ok(None)
-            },
-            NomResult::Error(_) => {
-                Err(IoError::new(IoErrorKind::InvalidData, "malformed response"))
-            },
-        };
+/// Check a request against what the server supports
+///
+/// See `ClientCodec::encode_batch`. DSN parameters are removed if the server
+/// did not advertise `DSN`, the other checks fail with `InvalidInput`.
+fn check_request(session: &SmtpSession, force_starttls: bool, request: Request) -> IoResult<Request> {
+    match request {
+        Request::StartTls if !force_starttls => {
+            if !session.capabilities().map_or(false, |caps| caps.starttls()) {
+                return Err(IoError::new(IoErrorKind::InvalidInput,
+                    "server does not support starttls"));
+            }
+        },
+        Request::Mail { ref params, .. } => {
+            if params.contains(&MailParam::Body(MailBodyParam::EightBitMime)) && !session.eight_bit_mime() {
+                return Err(IoError::new(IoErrorKind::InvalidInput,
+                    "server does not support 8bitmime"));
+            }
+        },
+        _ => {},
+    }
+    if session.capabilities().map_or(true, |caps| caps.dsn()) {
+        Ok(request)
+    } else {
+        Ok(request.without_dsn())
+    }
+}
 
-        // Drain parsed data.
-        if bytes != 0 {
-            buf.split_to(bytes);
 
-            // If we dropped the message, try to parse the remaining data.
-            if let Ok(None) = res {
-                return self.decode(buf);
-            }
+/// Emit a `tracing` event for a request that is sent
+///
+/// `AUTH` data may contain credentials, so only the mechanism is included.
+#[cfg(feature = "tracing")]
+fn trace_request(request: &Request) {
+    let line = match *request {
+        Request::Auth { method: Some(ref method), .. } => format!("AUTH {} [redacted]", method),
+        Request::Auth { method: None, .. } => "AUTH [redacted]".to_string(),
+        _ => request.to_string().trim_right().to_string(),
+    };
+    ::tracing::debug!(verb = request.verb(), request = %line, "sending request");
+}
+
+
+/// The frames to send a message body, using `BDAT` if possible
+///
+/// If the server advertised `CHUNKING`, the body is split into `BDAT` chunks
+/// of at most `chunk_size` octets, the last one marked `LAST`. Otherwise, the
+/// body is sent using `DATA`.
+pub fn body_frames(session: &SmtpSession, body: Vec<u8>, chunk_size: usize)
+        -> Vec<Frame<Request, Vec<u8>, IoError>> {
+    let chunking = session.capabilities().map_or(false, |caps| caps.chunking());
+    if !chunking {
+        return vec![
+            Request::Data.into(),
+            Frame::Body { chunk: Some(body) },
+            Frame::Body { chunk: None },
+        ];
+    }
+
+    let chunks = body.chunks(chunk_size.max(1)).collect::<Vec<_>>();
+    if chunks.is_empty() {
+        return vec![Request::Bdat { size: 0, last: true }.into(), Frame::Body { chunk: None }];
+    }
+    let mut frames = Vec::with_capacity(chunks.len() * 3);
+    for (idx, chunk) in chunks.iter().enumerate() {
+        frames.push(Request::Bdat { size: chunk.len(), last: idx == chunks.len() - 1 }.into());
+        frames.push(Frame::Body { chunk: Some(chunk.to_vec()) });
+        frames.push(Frame::Body { chunk: None });
+    }
+    frames
+}
+
+/// The state of a `BDAT` transfer, after a reply
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum BdatStatus {
+    /// An intermediate chunk was accepted
+    Continue,
+    /// The `LAST` chunk was accepted, with the final reply for the message
+    Complete(Response),
+    /// A chunk was rejected, and the transfer is aborted
+    Aborted(Response),
+}
+
+
+/// Tracks the replies to the chunks of a `BDAT` transfer (RFC 3030)
+///
+/// The server replies to every chunk. Intermediate chunks are acknowledged
+/// with a positive reply, and the reply to the `LAST` chunk is the outcome of
+/// the whole message. A negative reply to any chunk aborts the transfer, and
+/// the client should then send `RSET`.
+#[derive(Clone,Debug,Default)]
+pub struct BdatTransfer {
+    chunks: usize,
+    replies: usize,
+    last_sent: bool,
+    finished: bool,
+}
+
+impl BdatTransfer {
+    pub fn new() -> Self {
+        BdatTransfer::default()
+    }
+
+    /// Record that a chunk was sent
+    ///
+    /// Fails if the `LAST` chunk was already sent, or the transfer is over.
+    pub fn send(&mut self, request: &Request) -> IoResult<()> {
+        let last = match *request {
+            Request::Bdat { last, .. } => last,
+            _ => return Err(IoError::new(IoErrorKind::InvalidInput, "not a BDAT request")),
+        };
+        if self.last_sent || self.finished {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "BDAT transfer is over"));
+        }
+        self.chunks += 1;
+        self.last_sent = last;
+        Ok(())
+    }
+
+    /// Process the reply to the oldest unacknowledged chunk
+    ///
+    /// Fails if there is no chunk awaiting a reply.
+    pub fn reply(&mut self, response: Response) -> IoResult<BdatStatus> {
+        if self.finished || self.replies >= self.chunks {
+            return Err(IoError::new(IoErrorKind::InvalidData, "unexpected reply to BDAT"));
+        }
+        self.replies += 1;
+        if !response.code.severity.is_positive() {
+            self.finished = true;
+            return Ok(BdatStatus::Aborted(response));
         }
+        if self.last_sent && self.replies == self.chunks {
+            self.finished = true;
+            return Ok(BdatStatus::Complete(response));
+        }
+        Ok(BdatStatus::Continue)
+    }
 
-        res
+    /// The number of chunks sent
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// Tells if the transfer completed or was aborted
+    pub fn is_finished(&self) -> bool {
+        self.finished
     }
 }
 
 
+/// Render the bytes a client sends for the given requests
+///
+/// If the requests contain `DATA`, the body follows it, dot stuffed and
+/// terminated, exactly as `ClientCodec` sends it. Server replies are not part
+/// of the transcript.
+pub fn transcript(requests: &[Request], body: Option<&[u8]>) -> IoResult<Vec<u8>> {
+    let body = body.unwrap_or(b"");
+    // Dot stuffing at most doubles the body, plus the terminator.
+    let len = requests.iter().map(|request| request.to_bytes().len()).sum::<usize>() +
+        body.len() * 2 + 5;
+
+    let mut codec = ClientCodec::new();
+    let mut buf = BytesMut::with_capacity(len);
+    for request in requests {
+        let is_data = *request == Request::Data;
+        codec.encode(request.clone().into(), &mut buf)?;
+        if is_data {
+            codec.encode(Frame::Body { chunk: Some(body.to_vec()) }, &mut buf)?;
+            codec.encode(Frame::Body { chunk: None }, &mut buf)?;
+        }
+    }
+    Ok(buf.to_vec())
+}
+
+
 /// An `Io` implementation that wraps a secure or insecure transport into a
 /// single type.
 pub enum ClientIo<T> {
@@ -329,6 +935,254 @@ where T: AsyncWrite + 'static, TlsStream<T>: AsyncWrite + Write
     }
 }
 
+/// Tracks an in-progress `DATA` command in `ClientTransport`
+#[derive(Clone,Copy)]
+enum DataState {
+    /// No `DATA` command is in progress
+    Idle,
+    /// Awaiting the `354` reply, after the given number of earlier replies
+    AwaitingStartInput(usize),
+    /// The server rejected `DATA`, so the body is dropped
+    Rejected,
+}
+
+
+/// The transport used by `ClientProto`
+///
+/// This wraps the framed connection, and holds back the body of a `DATA`
+/// command until the server has replied with `354`. If the server replies with
+/// anything else, the body is dropped, and the reply is the `DATA` response.
+pub struct ClientTransport<T> {
+    inner: Framed<ClientIo<T>, ClientCodec>,
+    held: VecDeque<Frame<Request, Vec<u8>, IoError>>,
+    in_flight: usize,
+    data: DataState,
+    reply_timeout: Option<(Duration, Handle)>,
+    reply_timer: Option<Timeout>,
+}
+
+impl<T> ClientTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    /// Wrap a framed connection, on which the handshake has completed
+    pub fn new(inner: Framed<ClientIo<T>, ClientCodec>) -> Self {
+        ClientTransport {
+            inner,
+            held: VecDeque::new(),
+            in_flight: 0,
+            data: DataState::Idle,
+            reply_timeout: None,
+            reply_timer: None,
+        }
+    }
+
+    /// Limit the time to wait for a reply
+    ///
+    /// The timer starts when the transport waits for a reply to a request it
+    /// sent, and restarts with every reply received. If it runs out, even in
+    /// the middle of a reply, reading fails with a `TimedOut` error. No timer
+    /// runs while no reply is awaited.
+    pub fn set_reply_timeout(&mut self, timeout: Option<Duration>, handle: &Handle) {
+        self.reply_timeout = timeout.map(|timeout| (timeout, handle.clone()));
+        self.reply_timer = None;
+    }
+
+    /// Unwrap the framed connection
+    pub fn into_inner(self) -> Framed<ClientIo<T>, ClientCodec> {
+        self.inner
+    }
+
+    /// Send a message body using `DATA`, and read the final reply
+    ///
+    /// The body is only sent once the server replies with `354`, and is dot
+    /// stuffed and terminated by the codec. An empty body is sent as only the
+    /// terminating `.` line.
+    ///
+    /// If the server rejects `DATA` or the message, the error is
+    /// `SmtpError::Rejected`, with the reply.
+    pub fn send_data(self, body: Vec<u8>) -> Box<Future<Item = (Response, Self), Error = SmtpError>> {
+        let frames = vec![
+            Request::Data.into(),
+            Frame::Body { chunk: Some(body) },
+            Frame::Body { chunk: None },
+        ];
+        Box::new(self.send_all(stream::iter_ok::<_, IoError>(frames))
+            .and_then(|(transport, _)| transport.into_future().map_err(|(err, _)| err))
+            .map_err(SmtpError::from)
+            .and_then(|(response, transport)| {
+                match response {
+                    Some(Frame::Message { message, .. }) => {
+                        Ok((SmtpError::from_response(message)?, transport))
+                    },
+                    _ => Err(SmtpError::from(IoError::new(
+                        IoErrorKind::InvalidData, "connection closed during data"))),
+                }
+            }))
+    }
+
+    /// Send `QUIT`, read the reply, and close the connection
+    ///
+    /// Fails if the reply is not `221`, in which case the connection is not
+    /// closed cleanly. A negative reply is `SmtpError::Rejected`.
+    pub fn quit(self) -> Box<Future<Item = Response, Error = SmtpError>> {
+        Box::new(self.send(Request::Quit.into())
+            .and_then(|transport| transport.into_future().map_err(|(err, _)| err))
+            .map_err(SmtpError::from)
+            .and_then(|(response, transport)| {
+                let message = match response {
+                    Some(Frame::Message { message, .. }) => SmtpError::from_response(message)?,
+                    _ => return Err(SmtpError::from(IoError::new(
+                        IoErrorKind::InvalidData, "connection closed before quit reply"))),
+                };
+                if !message.is_closing() {
+                    return Err(SmtpError::from(IoError::new(
+                        IoErrorKind::InvalidData, "unexpected quit reply")));
+                }
+                Ok((message, transport))
+            })
+            .and_then(|(response, transport)| {
+                transport.shutdown().map(move |_| response).map_err(SmtpError::from)
+            }))
+    }
+
+    /// Send `QUIT`, and close the connection without waiting for the reply
+    ///
+    /// This is for when the outcome of the session is already known, and the
+    /// goodbye of the server doesn't matter.
+    pub fn quit_without_reply(self) -> Box<Future<Item = (), Error = IoError>> {
+        Box::new(self.send(Request::Quit.into())
+            .and_then(|transport| transport.shutdown()))
+    }
+
+    /// Shut down the writing half of the connection
+    fn shutdown(self) -> Box<Future<Item = (), Error = IoError>> {
+        Box::new(tokio_io::io::shutdown(self.inner.into_inner()).map(|_| ()))
+    }
+
+    /// Fail if a reply is awaited, and the reply timeout has run out
+    fn poll_reply_timer(&mut self) -> IoResult<()> {
+        if self.in_flight == 0 {
+            self.reply_timer = None;
+            return Ok(());
+        }
+        if self.reply_timer.is_none() {
+            if let Some((timeout, ref handle)) = self.reply_timeout {
+                self.reply_timer = Some(Timeout::new(timeout, handle)?);
+            }
+        }
+        let elapsed = match self.reply_timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        if elapsed {
+            self.reply_timer = None;
+            return Err(IoError::new(IoErrorKind::TimedOut,
+                "timed out waiting for complete response"));
+        }
+        Ok(())
+    }
+
+    /// Pass held frames to the connection, as far as the `DATA` state allows
+    fn poll_held(&mut self) -> Poll<(), IoError> {
+        loop {
+            match self.data {
+                DataState::AwaitingStartInput(_) => return Ok(Async::Ready(())),
+                DataState::Rejected => {
+                    match self.held.pop_front() {
+                        Some(Frame::Body { chunk: None }) => self.data = DataState::Idle,
+                        Some(_) => {},
+                        None => return Ok(Async::Ready(())),
+                    }
+                },
+                DataState::Idle => {
+                    let frame = match self.held.pop_front() {
+                        Some(frame) => frame,
+                        None => return Ok(Async::Ready(())),
+                    };
+                    let (is_message, is_data) = match frame {
+                        Frame::Message { ref message, .. } => (true, *message == Request::Data),
+                        _ => (false, false),
+                    };
+                    if let AsyncSink::NotReady(frame) = self.inner.start_send(frame)? {
+                        self.held.push_front(frame);
+                        return Ok(Async::NotReady);
+                    }
+                    if is_data {
+                        self.data = DataState::AwaitingStartInput(self.in_flight);
+                    }
+                    if is_message {
+                        self.in_flight += 1;
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<T> Stream for ClientTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type Item = Frame<Response, (), IoError>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        loop {
+            let frame = match self.inner.poll()? {
+                Async::Ready(Some(frame)) => frame,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => {
+                    self.poll_reply_timer()?;
+                    return Ok(Async::NotReady);
+                },
+            };
+            self.reply_timer = None;
+
+            if let Frame::Message { ref message, .. } = frame {
+                if let DataState::AwaitingStartInput(before) = self.data {
+                    if before > 0 {
+                        self.data = DataState::AwaitingStartInput(before - 1);
+                    } else if message.is_start_input() {
+                        // Send the body, and wait for the final reply.
+                        self.data = DataState::Idle;
+                        self.poll_held()?;
+                        self.inner.poll_complete()?;
+                        continue;
+                    } else {
+                        self.data = DataState::Rejected;
+                        self.poll_held()?;
+                    }
+                }
+                self.in_flight = self.in_flight.saturating_sub(1);
+            }
+
+            return Ok(Async::Ready(Some(frame)));
+        }
+    }
+}
+
+impl<T> Sink for ClientTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type SinkItem = Frame<Request, Vec<u8>, IoError>;
+    type SinkError = IoError;
+
+    fn start_send(&mut self, frame: Self::SinkItem) -> StartSend<Self::SinkItem, IoError> {
+        self.held.push_back(frame);
+        self.poll_held()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), IoError> {
+        try_ready!(self.poll_held());
+        self.inner.poll_complete()
+    }
+}
+
+impl<T> Transport for ClientTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{}
+
+
 /// The Tokio client protocol implementation
 ///
 /// Implements an SMTP client using a streaming pipeline protocol.
@@ -384,6 +1238,7 @@ where T: AsyncRead + AsyncWrite + 'static
                             _ => return future::Either::B(future::err(IoError::new(
                                 IoErrorKind::InvalidData, "connection closed during handshake"))),
                         };
+                        let stream = with_ehlo_session(stream, &response);
 
                         if do_auth {
                             return future::Either::A(future::Either::A(
@@ -400,86 +1255,197 @@ where T: AsyncRead + AsyncWrite + 'static
     )
 }
 
+/// Give the codec of a connection the session state after `EHLO`
+fn with_ehlo_session<T>(stream: Framed<ClientIo<T>, ClientCodec>, response: &Response)
+        -> Framed<ClientIo<T>, ClientCodec> {
+    let mut session = SmtpSession::new();
+    session.ehlo(response);
+    if let ClientIo::Secure(_) = *stream.get_ref() {
+        session.set_secure();
+    }
+    let (parts, mut codec) = stream.into_parts_and_codec();
+    codec.set_session(session);
+    Framed::from_parts(parts, codec)
+}
+
 // TODO: Support more authentication mechanisms.
 fn clientauth<T>(stream: Framed<ClientIo<T>, ClientCodec>, params: &ClientParams, features: &[String]) ->
     Box<Future<Item = Framed<ClientIo<T>, ClientCodec>, Error = IoError>>
 where T: AsyncRead + AsyncWrite + 'static
 {
-    if params.auth.is_none() {
-        return Box::new(future::ok(stream))
-    }
+    let (username, password) = match params.auth {
+        Some(ClientAuth { ref username, ref password }) => (username, password),
+        None => return Box::new(future::ok(stream)),
+    };
 
-    if let Some(ref auth_methods) = features.iter()
+    let mut exchange = if let Some(auth_methods) = features.iter()
         .find(|feature| feature.starts_with("AUTH "))
         .map(|feature| feature.split_at(5).1.split(' '))
     {
-        if auth_methods.clone().any(|method| method == "PLAIN") {
-            let authdata = if let Some(ClientAuth { ref username, ref password }) = params.auth {
-                base64::encode(&format!("{}\0{}\0{}", username, username, password))
-            } else { unreachable!(); };
-
-            // Send AUTH PLAIN request.
-            Box::new(stream.send(Request::Auth {
-                method: Some("PLAIN".into()),
-                data: Some(authdata),
-            }.into())
-                     // Await auth response.
-                     .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
-                     .and_then(|(response, stream)| {
-                         let response = match response {
-                             Some(Frame::Message { message, .. }) => message,
-                             _ => return future::err(IoError::new(
-                                 IoErrorKind::InvalidData, "connection closed during auth")),
-                         };
-
-                         // Check auth status.
-                         if !response.code.severity.is_positive() {
-                             return future::err(IoError::new(
-                                 IoErrorKind::InvalidData, "authentication failed"));
-                         }
-
-                         future::ok(stream)
-                     }))
+        let mechanism = if auth_methods.clone().any(|method| method == "PLAIN") {
+            AuthMechanism::Plain
         } else if auth_methods.clone().any(|method| method == "LOGIN") {
-            let (username, password) = if let Some(ref authdata) = params.auth {
-                (base64::encode(&authdata.username), base64::encode(&authdata.password))
-            } else { unreachable!(); };
-
-            // Send AUTH LOGIN request.
-            Box::new(stream.send(Request::Auth {
-                method: Some("LOGIN".into()),
-                data: Some(username),
-            }.into())
-                     // Send password.
-                     .and_then(|stream| stream.send(Request::Auth {
-                         method: None,
-                         data: Some(password)
-                     }.into()))
-                     // Await auth response.
-                     .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
-                     .and_then(|(response, stream)| {
-                         let response = match response {
-                             Some(Frame::Message { message, .. }) => message,
-                             _ => return future::err(IoError::new(
-                                 IoErrorKind::InvalidData, "connection closed during auth")),
-                         };
-
-                         // Check auth status.
-                         if !response.code.severity.is_positive() {
-                             return future::err(IoError::new(
-                                 IoErrorKind::InvalidData, "authentication failed"));
-                         }
-
-                         future::ok(stream)
-                     }))
+            AuthMechanism::Login
         } else {
-            Box::new(future::err(IoError::new(
-                IoErrorKind::InvalidData, "no supported auth methods found")))
-        }
+            return Box::new(future::err(IoError::new(
+                IoErrorKind::InvalidData, "no supported auth methods found")));
+        };
+        AuthExchange::new(mechanism, username.as_str(), password.as_str())
     } else {
-        Box::new(future::err(IoError::new(
-            IoErrorKind::InvalidData, "server does not support auth")))
+        return Box::new(future::err(IoError::new(
+            IoErrorKind::InvalidData, "server does not support auth")));
+    };
+
+    // Send the AUTH request, then answer challenges until done.
+    let request = exchange.start();
+    Box::new(future::loop_fn((stream, request, exchange), |(stream, request, mut exchange)| {
+        stream.send(request.into())
+            // Await auth response.
+            .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
+            .and_then(move |(response, stream)| {
+                let response = match response {
+                    Some(Frame::Message { message, .. }) => message,
+                    _ => return future::err(IoError::new(
+                        IoErrorKind::InvalidData, "connection closed during auth")),
+                };
+
+                // Check auth status.
+                match exchange.next(&response) {
+                    Ok(Some(request)) => future::ok(future::Loop::Continue((stream, request, exchange))),
+                    Ok(None) => future::ok(future::Loop::Break(stream)),
+                    Err(_) => future::err(IoError::new(
+                        IoErrorKind::InvalidData, "authentication failed")),
+                }
+            })
+    }))
+}
+
+/// The error when the server sends no greeting, see `await_greeting`
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub struct NoGreeting;
+
+impl Display for NoGreeting {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for NoGreeting {
+    fn description(&self) -> &str {
+        "server sent no greeting"
+    }
+}
+
+/// An error from an SMTP command
+///
+/// This is the error of `ClientTransport::send_data` and `ClientTransport::quit`,
+/// which tells a rejection by the server apart from a failed connection.
+#[derive(Debug)]
+pub enum SmtpError {
+    /// The server replied with a negative code
+    Rejected { response: Response },
+    /// The connection failed
+    Io(IoError),
+}
+
+impl SmtpError {
+    /// Check a reply, failing if it is negative (`4xx` or `5xx`)
+    ///
+    /// Positive replies are returned as they are.
+    pub fn from_response(response: Response) -> Result<Response, SmtpError> {
+        if response.code.severity.is_positive() {
+            return Ok(response);
+        }
+        Err(SmtpError::Rejected { response })
+    }
+
+    /// Tells if the command was rejected with a `4xx` code, and may succeed
+    /// when retried later
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            SmtpError::Rejected { ref response } =>
+                response.code.severity == Severity::TransientNegativeCompletion,
+            SmtpError::Io(_) => false,
+        }
+    }
+
+    /// Tells if the command was rejected with a `5xx` code, and should not be
+    /// retried as is
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            SmtpError::Rejected { ref response } =>
+                response.code.severity == Severity::PermanentNegativeCompletion,
+            SmtpError::Io(_) => false,
+        }
+    }
+
+    /// The reply that caused the error, if any
+    pub fn response(&self) -> Option<&Response> {
+        match *self {
+            SmtpError::Rejected { ref response } => Some(response),
+            SmtpError::Io(_) => None,
+        }
+    }
+}
+
+impl From<IoError> for SmtpError {
+    fn from(err: IoError) -> SmtpError {
+        SmtpError::Io(err)
+    }
+}
+
+impl Display for SmtpError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            SmtpError::Rejected { ref response } => {
+                write!(f, "{}: {} {}", self.description(), response.code, response.text.join(" "))
+            },
+            SmtpError::Io(ref err) => write!(f, "{}: {}", self.description(), err),
+        }
+    }
+}
+
+impl StdError for SmtpError {
+    fn description(&self) -> &str {
+        match *self {
+            SmtpError::Rejected { .. } => "command rejected",
+            SmtpError::Io(_) => "connection error",
+        }
     }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            SmtpError::Rejected { .. } => None,
+            SmtpError::Io(ref err) => Some(err),
+        }
+    }
+}
+
+/// Wait for the server greeting on a new connection, for at most `timeout`
+///
+/// If the server stays silent, or closes the connection first, this fails
+/// with a `TimedOut` error wrapping `NoGreeting`. A negative greeting is
+/// returned like any other, for the caller to check.
+pub fn await_greeting<T>(io: ClientIo<T>, timeout: Duration, handle: &Handle)
+        -> Box<Future<Item = (Response, Framed<ClientIo<T>, ClientCodec>), Error = IoError>>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    let no_greeting = || IoError::new(IoErrorKind::TimedOut, NoGreeting);
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let greeting = io.framed(ClientCodec::new()).into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(response, stream)| match response {
+            Some(Frame::Message { message, .. }) => Ok((message, stream)),
+            _ => Err(no_greeting()),
+        });
+    Box::new(greeting.select2(timer).then(move |result| match result {
+        Ok(future::Either::A((item, _))) => Ok(item),
+        Ok(future::Either::B(_)) => Err(no_greeting()),
+        Err(future::Either::A((err, _))) | Err(future::Either::B((err, _))) => Err(err),
+    }))
 }
 
 impl ClientProto {
@@ -504,7 +1470,7 @@ impl ClientProto {
     {
         // Perform the handshake.
         Box::new(handshake(ClientIo::Plain(io), params, true, true)
-                 .map(|(_, stream)| stream))
+                 .map(|(_, stream)| ClientTransport::new(stream)))
     }
 
     fn connect_starttls<T>(io: T, params: Arc<ClientParams>) -> ClientBindTransport<T>
@@ -515,8 +1481,7 @@ impl ClientProto {
         // Perform the handshake, and send STARTTLS.
         Box::new(handshake(ClientIo::Plain(io), params.clone(), true, false)
                  .and_then(move |(ehlo_response, stream)| {
-                     let is_supported = None != ehlo_response.text.iter()
-                         .find(|feature| feature.as_str() == "STARTTLS");
+                     let is_supported = Capabilities::from_response(&ehlo_response).starttls();
 
                      if !is_supported {
                          if is_required {
@@ -524,7 +1489,8 @@ impl ClientProto {
                                  IoErrorKind::InvalidData, "server doesn't support starttls"))));
                          }
 
-                         return future::Either::B(future::Either::A(future::ok(stream)));
+                         return future::Either::B(future::Either::A(future::ok(
+                             ClientTransport::new(stream))));
                      }
 
                      future::Either::A(stream.send(Request::StartTls.into())
@@ -566,7 +1532,7 @@ impl ClientProto {
                              .and_then(move |io| {
                                  // Re-do the handshake.
                                  handshake(ClientIo::Secure(io), params, false, true)
-                                     .map(|(_, stream)| stream)
+                                     .map(|(_, stream)| ClientTransport::new(stream))
                              })
                          }))
                  }))
@@ -588,7 +1554,7 @@ impl ClientProto {
             .and_then(move |io| {
                 // Perform the handshake.
                 handshake(ClientIo::Secure(io), params, true, true)
-                    .map(|(_, stream)| stream)
+                    .map(|(_, stream)| ClientTransport::new(stream))
             }))
     }
 }
@@ -659,3 +1625,716 @@ impl Client {
         TokioTcpClient::new(ClientProto(Arc::new(params)))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BytesMut};
+    use client::{BdatStatus, BdatTransfer, ClientCodec, ClientIo, ClientParams, ClientProto,
+                 ClientSecurity, ClientTransport, DataReplies, NoGreeting, SmtpError, await_greeting,
+                 body_frames, transcript};
+    use futures::{future, Async, Future, Sink, Stream};
+    use request::{ClientId, Mailbox, Request};
+    use response::{Response};
+    use session::{SmtpSession};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::sync::{Arc};
+    use std::time::{Duration};
+    use testing::{MockSmtpServer};
+    use tokio_core::reactor::{Core, Timeout};
+    use tokio_io::{AsyncRead};
+    use tokio_io::codec::{Decoder, Encoder};
+    use tokio_proto::streaming::pipeline::{ClientProto as TokioClientProto, Frame};
+
+    fn send_data(transport: &mut ClientTransport<MockSmtpServer>) {
+        for frame in vec![
+            Request::Data.into(),
+            Frame::Body { chunk: Some(b"Hello\r\n".to_vec()) },
+            Frame::Body { chunk: None },
+            Request::Quit.into(),
+        ] {
+            assert!(transport.start_send(frame).unwrap().is_ready());
+        }
+        assert!(transport.poll_complete().unwrap().is_ready());
+    }
+
+    fn next_response(transport: &mut ClientTransport<MockSmtpServer>) -> Option<String> {
+        match transport.poll().unwrap() {
+            Async::Ready(Some(Frame::Message { message, .. })) => Some(message.to_string()),
+            Async::NotReady => None,
+            _ => panic!("unexpected frame"),
+        }
+    }
+
+    #[test]
+    fn test_body() {
+        for (chunks, expect) in vec![
+            (vec![b"Hello\r\n".as_ref()], b"DATA\r\nHello\r\n.\r\n".as_ref()),
+            (vec![b"Hello"], b"DATA\r\nHello\r\n.\r\n"),
+            (vec![b".Hello\r\n.\r\n"], b"DATA\r\n..Hello\r\n..\r\n.\r\n"),
+            (vec![b"Hello\r", b"\n.", b"World\r\n"], b"DATA\r\nHello\r\n..World\r\n.\r\n"),
+            (vec![b"Hello\r\n\r\n.World"], b"DATA\r\nHello\r\n\r\n..World\r\n.\r\n"),
+            (vec![b"Hello\r\r\n.World"], b"DATA\r\nHello\r\r\n..World\r\n.\r\n"),
+            // An empty body is just the terminating dot.
+            (vec![], b"DATA\r\n.\r\n"),
+            (vec![b""], b"DATA\r\n.\r\n"),
+            (vec![b"", b""], b"DATA\r\n.\r\n"),
+        ] {
+            let mut codec = ClientCodec::new();
+            let mut buf = BytesMut::with_capacity(1024);
+            codec.encode(Request::Data.into(), &mut buf).unwrap();
+            for chunk in chunks {
+                codec.encode(Frame::Body { chunk: Some(chunk.to_vec()) }, &mut buf).unwrap();
+            }
+            codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+            assert_eq!(&buf[..], expect);
+        }
+    }
+
+    #[test]
+    fn test_lenient_line_endings() {
+        let input = b"250-mail.example.test\n250-PIPELINING\r\n250 SIZE 1024\n354 Go\n".as_ref();
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::from(input);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        codec.set_lenient_line_endings(true);
+        let mut buf = BytesMut::from(input);
+        let res = codec.decode_raw(&mut buf).unwrap().expect("expected a reply");
+        assert_eq!(res.response.text, vec!["mail.example.test", "PIPELINING", "SIZE 1024"]);
+        assert_eq!(res.raw, b"250-mail.example.test\n250-PIPELINING\r\n250 SIZE 1024\n".to_vec());
+        let res = codec.decode_raw(&mut buf).unwrap().expect("expected a reply");
+        assert!(res.response.is_start_input());
+        assert_eq!(res.raw, b"354 Go\n".to_vec());
+        assert!(buf.is_empty());
+
+        // A line ending split between reads.
+        let mut buf = BytesMut::from(b"250 OK\r".as_ref());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\n");
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reply_timeout() {
+        let mut codec = ClientCodec::new();
+        codec.set_reply_timeout(Some(Duration::from_secs(3600)));
+        let mut buf = BytesMut::from(b"250-First line\r\n".as_ref());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"250-Second line\r\n");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"250 Last line\r\n");
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        // A slow reply fails once more data arrives after the deadline.
+        codec.set_reply_timeout(Some(Duration::from_secs(0)));
+        let mut buf = BytesMut::from(b"250-First line\r\n".as_ref());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"250-Second line\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), IoErrorKind::TimedOut);
+
+        // A complete reply is fine, even if the deadline passed.
+        codec.set_reply_timeout(Some(Duration::from_secs(0)));
+        let mut buf = BytesMut::from(b"250 OK\r\n".as_ref());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_data() {
+        // The body is held until the server replies 354.
+        let server = MockSmtpServer::new()
+            .expect("DATA\r\n", "354 Start mail input\r\n")
+            .expect("Hello\r\n.\r\n", "250 OK\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let mut transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        send_data(&mut transport);
+        assert!(!server.is_done());
+        assert_eq!(next_response(&mut transport), Some("250 OK\r\n".to_string()));
+        assert_eq!(next_response(&mut transport), Some("221 Bye\r\n".to_string()));
+        assert!(server.is_done());
+
+        // If the server rejects DATA, the body is dropped.
+        let server = MockSmtpServer::new()
+            .expect("DATA\r\n", "503 Bad sequence of commands\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let mut transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        send_data(&mut transport);
+        assert_eq!(next_response(&mut transport), Some("503 Bad sequence of commands\r\n".to_string()));
+        assert!(transport.poll_complete().unwrap().is_ready());
+        assert!(server.is_done());
+    }
+
+    #[test]
+    fn test_max_reply_lines() {
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::from("250-Line\r\n".repeat(100).as_bytes());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"250-Line\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), IoErrorKind::InvalidData);
+
+        let mut buf = BytesMut::from(format!("{}250 Last\r\n", "250-Line\r\n".repeat(99)).as_bytes());
+        assert_eq!(codec.decode(&mut buf).unwrap().map(|frame| match frame {
+            Frame::Message { message, .. } => message.text.len(),
+            _ => 0,
+        }), Some(100));
+
+        codec.set_max_reply_lines(2);
+        let mut buf = BytesMut::from(b"250-One\r\n250-Two\r\n250 Three\r\n".as_ref());
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), IoErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_validate_requests() {
+        let injected = || Request::Vrfy("john\r\nRCPT TO:<eve@example.test>".to_string());
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::with_capacity(1024);
+        assert_eq!(codec.encode(injected().into(), &mut buf).unwrap_err().kind(),
+            IoErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        codec.set_validate_requests(false);
+        assert!(codec.encode(injected().into(), &mut buf).is_ok());
+        assert_eq!(&buf[..], b"VRFY john\r\nRCPT TO:<eve@example.test>\r\n".as_ref());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::fmt::{Debug, Write};
+        use std::sync::{Arc, Mutex};
+        use tracing::{Event, Id, Metadata, Subscriber};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Record};
+
+        /// Collects the fields of each event as a line of text
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        struct Fields<'a>(&'a mut String);
+
+        impl<'a> Visit for Fields<'a> {
+            fn record_debug(&mut self, field: &Field, value: &Debug) {
+                write!(self.0, "{}={:?} ", field.name(), value).unwrap();
+            }
+        }
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata) -> bool { true }
+            fn new_span(&self, _: &Attributes) -> Id { Id::from_u64(1) }
+            fn record(&self, _: &Id, _: &Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut line = String::new();
+                event.record(&mut Fields(&mut line));
+                self.0.lock().unwrap().push(line.trim_right().to_string());
+            }
+        }
+
+        let events = Arc::new(Mutex::new(vec![]));
+        ::tracing::subscriber::with_default(Capture(events.clone()), || {
+            let mut codec = ClientCodec::new();
+            let mut buf = BytesMut::with_capacity(1024);
+            let auth = Request::Auth {
+                method: Some("PLAIN".to_string()),
+                data: Some("AGpvaG4Ac2VjcmV0".to_string()),
+            };
+            codec.encode(auth.into(), &mut buf).unwrap();
+            let secret = Request::Auth { method: None, data: Some("c2VjcmV0".to_string()) };
+            codec.encode(secret.into(), &mut buf).unwrap();
+            codec.encode(Request::Quit.into(), &mut buf).unwrap();
+            let mut buf = BytesMut::from(b"235 Authenticated\r\n".as_ref());
+            codec.decode(&mut buf).unwrap();
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(*events, vec![
+            "message=sending request verb=\"AUTH\" request=AUTH PLAIN [redacted]",
+            "message=sending request verb=\"AUTH\" request=AUTH [redacted]",
+            "message=sending request verb=\"QUIT\" request=QUIT",
+            "message=received response code=235",
+        ]);
+        assert!(events.iter().all(|line| !line.contains("AGpvaG4Ac2VjcmV0") && !line.contains("c2VjcmV0")));
+    }
+
+    #[test]
+    fn test_lmtp() {
+        let ehlo = || Request::Ehlo(ClientId::Domain("client.example.test".to_string()));
+        let mut codec = ClientCodec::new();
+        assert!(!codec.is_lmtp());
+        let mut buf = BytesMut::with_capacity(1024);
+        codec.encode(ehlo().into(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"EHLO client.example.test\r\n".as_ref());
+
+        codec.set_lmtp(true);
+        let mut buf = BytesMut::with_capacity(1024);
+        codec.encode(ehlo().into(), &mut buf).unwrap();
+        codec.encode(Request::Quit.into(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"LHLO client.example.test\r\nQUIT\r\n".as_ref());
+    }
+
+    #[test]
+    fn test_data_replies() {
+        let alice: Mailbox = "alice@example.test".parse().unwrap();
+        let bob: Mailbox = "bob@example.test".parse().unwrap();
+        let mut codec = ClientCodec::new();
+        codec.set_lmtp(true);
+        let mut buf = BytesMut::from(b"250 2.1.5 alice delivered\r\n452-4.2.2 bob is\r\n".as_ref());
+        assert!(codec.decode_data_replies(&mut buf).is_err());
+
+        codec.expect_data_replies(vec![alice.clone(), bob.clone()]);
+        assert_eq!(codec.decode_data_replies(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"452 4.2.2 over quota\r\n221 Bye\r\n");
+        let replies = codec.decode_data_replies(&mut buf).unwrap().unwrap();
+        assert_eq!(replies.replies.len(), 2);
+        assert_eq!(replies.get(&alice).unwrap().to_string(), "250 2.1.5 alice delivered\r\n");
+        assert_eq!(replies.get(&bob).unwrap().to_string(), "452-4.2.2 bob is\r\n452 4.2.2 over quota\r\n");
+        assert!(replies.get(&"eve@example.test".parse().unwrap()).is_none());
+        assert!(!replies.all_delivered());
+
+        // The codec is back to decoding single replies.
+        assert_eq!(codec.decode(&mut buf).unwrap().map(|frame| match frame {
+            Frame::Message { message, .. } => message.to_string(),
+            _ => String::new(),
+        }), Some("221 Bye\r\n".to_string()));
+
+        codec.expect_data_replies(vec![alice.clone()]);
+        let mut buf = BytesMut::from(b"250 Ok\r\n".as_ref());
+        assert!(codec.decode_data_replies(&mut buf).unwrap().unwrap().all_delivered());
+
+        codec.expect_data_replies(vec![]);
+        assert_eq!(codec.decode_data_replies(&mut buf).unwrap(), Some(DataReplies { replies: vec![] }));
+
+        // Combined, the first failure decides the code.
+        let replies = DataReplies { replies: vec![
+            (alice.clone(), "250 2.1.5 alice delivered\r\n".parse().unwrap()),
+            (bob.clone(), "452 4.2.2 over quota\r\n".parse().unwrap()),
+        ] };
+        let response = replies.to_response();
+        assert_eq!(response.to_string(), "452-<alice@example.test> 250 2.1.5 alice delivered\r\n\
+            452 <bob@example.test> 452 4.2.2 over quota\r\n");
+    }
+
+    #[test]
+    fn test_lmtp_transport() {
+        // Only the accepted recipients get a reply after the message.
+        let server = MockSmtpServer::new()
+            .expect("MAIL FROM:<john@example.test>\r\n", "250 OK\r\n")
+            .expect("RCPT TO:<alice@example.test>\r\n", "250 OK\r\n")
+            .expect("RCPT TO:<eve@example.test>\r\n", "550 No such user\r\n")
+            .expect("RCPT TO:<bob@example.test>\r\n", "250 OK\r\n")
+            .expect("DATA\r\n", "354 Start mail input\r\n")
+            .expect("Hello\r\n.\r\n", "250 2.1.5 alice delivered\r\n250 2.1.5 bob delivered\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let mut codec = ClientCodec::new();
+        codec.set_lmtp(true);
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(codec));
+        let call = |transport: ClientTransport<MockSmtpServer>, request: &str| {
+            let request: Request = request.parse().unwrap();
+            let transport = transport.send(request.into()).wait().unwrap();
+            match transport.into_future().wait().map_err(|(err, _)| err).unwrap() {
+                (Some(Frame::Message { message, .. }), transport) => (message, transport),
+                _ => panic!("expected a reply"),
+            }
+        };
+        let (_, transport) = call(transport, "MAIL FROM:<john@example.test>\r\n");
+        let (_, transport) = call(transport, "RCPT TO:<alice@example.test>\r\n");
+        let (response, transport) = call(transport, "RCPT TO:<eve@example.test>\r\n");
+        assert_eq!(response.code.to_string(), "550");
+        let (_, transport) = call(transport, "RCPT TO:<bob@example.test>\r\n");
+
+        let (response, transport) = transport.send_data(b"Hello\r\n".to_vec()).wait().unwrap();
+        assert_eq!(response.text, vec![
+            "<alice@example.test> 250 2.1.5 alice delivered",
+            "<bob@example.test> 250 2.1.5 bob delivered",
+        ]);
+        assert_eq!(transport.quit().wait().unwrap().to_string(), "221 Bye\r\n");
+        assert!(server.is_done());
+    }
+
+    #[test]
+    fn test_bdat_transfer() {
+        let reply = |s: &str| -> Response { s.parse().unwrap() };
+        let chunk = |last| Request::Bdat { size: 10, last };
+
+        let mut transfer = BdatTransfer::new();
+        assert!(transfer.reply(reply("250 Ok\r\n")).is_err());
+        transfer.send(&chunk(false)).unwrap();
+        transfer.send(&chunk(false)).unwrap();
+        assert_eq!(transfer.reply(reply("250 2.0.0 10 octets\r\n")).unwrap(), BdatStatus::Continue);
+        transfer.send(&chunk(true)).unwrap();
+        assert!(transfer.send(&chunk(true)).is_err());
+        assert_eq!(transfer.reply(reply("250 2.0.0 20 octets\r\n")).unwrap(), BdatStatus::Continue);
+        assert!(!transfer.is_finished());
+        assert_eq!(transfer.reply(reply("250 2.0.0 Queued\r\n")).unwrap(),
+            BdatStatus::Complete(reply("250 2.0.0 Queued\r\n")));
+        assert!(transfer.is_finished());
+        assert_eq!(transfer.chunks(), 3);
+        assert!(transfer.reply(reply("250 Ok\r\n")).is_err());
+
+        let mut transfer = BdatTransfer::new();
+        transfer.send(&chunk(false)).unwrap();
+        transfer.send(&chunk(false)).unwrap();
+        assert_eq!(transfer.reply(reply("250 Ok\r\n")).unwrap(), BdatStatus::Continue);
+        assert_eq!(transfer.reply(reply("452 4.3.1 Out of space\r\n")).unwrap(),
+            BdatStatus::Aborted(reply("452 4.3.1 Out of space\r\n")));
+        assert!(transfer.is_finished());
+        assert!(transfer.send(&chunk(true)).is_err());
+
+        let mut transfer = BdatTransfer::new();
+        transfer.send(&chunk(true)).unwrap();
+        assert_eq!(transfer.reply(reply("554 Rejected\r\n")).unwrap(),
+            BdatStatus::Aborted(reply("554 Rejected\r\n")));
+        assert!(BdatTransfer::new().send(&Request::Data).is_err());
+    }
+
+    #[test]
+    fn test_transport_reply_timeout() {
+        let mut core = Core::new().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        // The reply stalls halfway, and no more data arrives.
+        let server = MockSmtpServer::new()
+            .expect("RSET\r\n", "250-First line\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let mut transport = ClientTransport::new(
+            ClientIo::Plain(server).framed(ClientCodec::new()));
+        transport.set_reply_timeout(Some(timeout), &core.handle());
+        let reply = transport.send(Request::Rset.into())
+            .and_then(|transport| transport.into_future().map_err(|(err, _)| err));
+        let err = core.run(reply).err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+
+        // Without a request in flight, the transport waits indefinitely.
+        let server = MockSmtpServer::new()
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let mut transport = ClientTransport::new(
+            ClientIo::Plain(server).framed(ClientCodec::new()));
+        transport.set_reply_timeout(Some(Duration::from_millis(0)), &core.handle());
+        let idle = transport.into_future().map_err(|(err, _)| err)
+            .select2(Timeout::new(timeout, &core.handle()).unwrap());
+        assert!(match core.run(idle) {
+            Ok(future::Either::B(_)) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_await_greeting() {
+        let mut core = Core::new().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        let server = MockSmtpServer::new()
+            .greeting("220 mail.example.test ESMTP\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let greeting = await_greeting(ClientIo::Plain(server), timeout, &core.handle());
+        let (response, _) = core.run(greeting).unwrap();
+        assert_eq!(response.to_string(), "220 mail.example.test ESMTP\r\n");
+
+        // A silent server, and one that closes the connection right away.
+        for server in vec![MockSmtpServer::new().expect("QUIT\r\n", "221 Bye\r\n"), MockSmtpServer::new()] {
+            let greeting = await_greeting(ClientIo::Plain(server), timeout, &core.handle());
+            let err = core.run(greeting).err().unwrap();
+            assert_eq!(err.kind(), IoErrorKind::TimedOut);
+            assert_eq!(err.get_ref().and_then(|err| err.downcast_ref::<NoGreeting>()), Some(&NoGreeting));
+        }
+    }
+
+    #[test]
+    fn test_smtp_error() {
+        let reply = |s: &str| -> Response { s.parse().unwrap() };
+
+        let err = SmtpError::from_response(reply("450 Mailbox busy\r\n")).unwrap_err();
+        assert!(err.is_transient());
+        assert!(!err.is_permanent());
+        assert_eq!(err.response(), Some(&reply("450 Mailbox busy\r\n")));
+        assert_eq!(err.to_string(), "command rejected: 450 Mailbox busy");
+
+        let err = SmtpError::from_response(reply("550-No such user\r\n550 Go away\r\n")).unwrap_err();
+        assert!(!err.is_transient());
+        assert!(err.is_permanent());
+        assert_eq!(err.to_string(), "command rejected: 550 No such user Go away");
+
+        for input in vec!["250 OK\r\n", "354 Go ahead\r\n"] {
+            assert_eq!(SmtpError::from_response(reply(input)).ok(), Some(reply(input)));
+        }
+
+        let err = SmtpError::from(IoError::new(IoErrorKind::BrokenPipe, "closed"));
+        assert!(!err.is_transient() && !err.is_permanent());
+        assert_eq!(err.response(), None);
+        assert_eq!(err.to_string(), "connection error: closed");
+    }
+
+    #[test]
+    fn test_quit() {
+        let server = MockSmtpServer::new().expect("QUIT\r\n", "221 Bye\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        assert_eq!(transport.quit().wait().unwrap().to_string(), "221 Bye\r\n");
+        assert!(server.is_done());
+        assert!(server.is_shut_down());
+
+        let server = MockSmtpServer::new();
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        assert!(transport.quit().wait().is_err());
+
+        let server = MockSmtpServer::new().expect("QUIT\r\n", "500 What?\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        let err = transport.quit().wait().unwrap_err();
+        assert_eq!(err.response().map(|response| response.code.numeric()), Some(500));
+        assert!(!server.is_shut_down());
+
+        let server = MockSmtpServer::new().expect("QUIT\r\n", "250 OK\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        assert_eq!(transport.quit().wait().unwrap_err().to_string(), "connection error: unexpected quit reply");
+        assert!(!server.is_shut_down());
+
+        // The reply would block, but is not waited for.
+        let server = MockSmtpServer::new()
+            .expect("QUIT\r\n", "221 Bye\r\n")
+            .expect("NOOP\r\n", "250 Ok\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        transport.quit_without_reply().wait().unwrap();
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn test_transcript() {
+        let requests = vec![
+            Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] },
+            Request::Rcpt { to: "alice@example.test".parse().unwrap(), params: vec![] },
+            Request::Data,
+            Request::Quit,
+        ];
+        assert_eq!(
+            transcript(&requests, Some(b"Subject: Hi\r\n\r\n.\r\n")).unwrap(),
+            b"MAIL FROM:<john@example.test>\r\nRCPT TO:<alice@example.test>\r\n\
+              DATA\r\nSubject: Hi\r\n\r\n..\r\n.\r\nQUIT\r\n".to_vec()
+        );
+        assert_eq!(transcript(&[Request::Quit], None).unwrap(), b"QUIT\r\n".to_vec());
+        assert_eq!(transcript(&[Request::Data], None).unwrap(), b"DATA\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_body_frames() {
+        let encode = |frames: Vec<Frame<Request, Vec<u8>, IoError>>| {
+            let mut codec = ClientCodec::new();
+            let mut buf = BytesMut::with_capacity(1024);
+            for frame in frames {
+                codec.encode(frame, &mut buf).unwrap();
+            }
+            buf.to_vec()
+        };
+
+        let mut session = SmtpSession::new();
+        session.ehlo(&"250-mail.example.test\r\n250 PIPELINING\r\n".parse().unwrap());
+        assert_eq!(encode(body_frames(&session, b".Hello\r\n".to_vec(), 4)),
+            b"DATA\r\n..Hello\r\n.\r\n".to_vec());
+
+        session.ehlo(&"250-mail.example.test\r\n250 CHUNKING\r\n".parse().unwrap());
+        assert_eq!(encode(body_frames(&session, b".Hello\r\n".to_vec(), 4)),
+            b"BDAT 4\r\n.Hel\
+              BDAT 4 LAST\r\nlo\r\n".to_vec());
+        assert_eq!(encode(body_frames(&session, b"Hello".to_vec(), 4)),
+            b"BDAT 4\r\nHellBDAT 1 LAST\r\no".to_vec());
+        assert_eq!(encode(body_frames(&session, vec![], 4)), b"BDAT 0 LAST\r\n".to_vec());
+
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::with_capacity(1024);
+        codec.encode(Request::Bdat { size: 2, last: true }.into(), &mut buf).unwrap();
+        assert!(codec.encode(Frame::Body { chunk: Some(b"abc".to_vec()) }, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_send_data() {
+        let server = MockSmtpServer::new()
+            .expect("DATA\r\n", "354 Start mail input\r\n")
+            .expect("..Hello\r\nWorld\r\n.\r\n", "250 OK\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        let (response, _) = transport.send_data(b".Hello\r\nWorld".to_vec()).wait().unwrap();
+        assert_eq!(response.to_string(), "250 OK\r\n");
+        assert!(server.is_done());
+
+        let server = MockSmtpServer::new()
+            .expect("DATA\r\n", "554 No valid recipients\r\n");
+        let transport = ClientTransport::new(ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        let err = transport.send_data(b"Hello\r\n".to_vec()).wait().err().unwrap();
+        assert!(err.is_permanent());
+        assert_eq!(err.to_string(), "command rejected: 554 No valid recipients");
+        assert!(server.is_done());
+    }
+
+    #[test]
+    fn test() {
+        let batch = || vec![
+            Request::Mail { from: "".parse().unwrap(), params: vec![] },
+            Request::Rcpt { to: "alice@example.test".parse().unwrap(), params: vec![] },
+        ];
+
+        let mut session = SmtpSession::new();
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, batch(), &mut buf).is_err());
+        assert!(ClientCodec::new().encode_batch(&session, vec![Request::Quit], &mut buf).is_ok());
+        assert_eq!(&buf[..], b"QUIT\r\n");
+
+        session.ehlo(&"250-mail.example.test\r\n250 PIPELINING\r\n".parse().unwrap());
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, batch(), &mut buf).is_ok());
+        assert_eq!(&buf[..], b"MAIL FROM:<>\r\nRCPT TO:<alice@example.test>\r\n".as_ref());
+    }
+
+    #[test]
+    fn test_eight_bit_mime() {
+        let mail = || -> Request { "MAIL FROM:<john@example.test> BODY=8BITMIME\r\n".parse().unwrap() };
+        let mut session = SmtpSession::new();
+        session.ehlo(&"250-mail.example.test\r\n250 SIZE 1024\r\n".parse().unwrap());
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, vec![mail()], &mut buf).is_err());
+        assert!(buf.is_empty());
+
+        session.ehlo(&"250-mail.example.test\r\n250 8BITMIME\r\n".parse().unwrap());
+        assert!(ClientCodec::new().encode_batch(&session, vec![mail()], &mut buf).is_ok());
+        assert_eq!(&buf[..], b"MAIL FROM:<john@example.test> BODY=8BITMIME\r\n".as_ref());
+
+        // If checked, 8-bit bodies must be declared, 7-bit ones need not be.
+        let encode = |mail: &str, body: &[u8]| {
+            let mut codec = ClientCodec::new();
+            codec.set_check_eight_bit_body(true);
+            let mut buf = BytesMut::with_capacity(1024);
+            codec.encode(Frame::Message { message: mail.parse().unwrap(), body: false }, &mut buf).unwrap();
+            codec.encode(Request::Data.into(), &mut buf).unwrap();
+            codec.encode(Frame::Body { chunk: Some(body.to_vec()) }, &mut buf)
+        };
+        let err = encode("MAIL FROM:<john@example.test>\r\n", "Grüße\r\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+        assert!(encode("MAIL FROM:<john@example.test>\r\n", b"Hello\r\n").is_ok());
+        assert!(encode("MAIL FROM:<john@example.test> BODY=8BITMIME\r\n", "Grüße\r\n".as_bytes()).is_ok());
+
+        // Otherwise, it depends on the session.
+        let encode = |session: Option<&str>| {
+            let mut codec = ClientCodec::new();
+            if let Some(ehlo) = session {
+                let mut session = SmtpSession::new();
+                session.ehlo(&ehlo.parse().unwrap());
+                codec.set_session(session);
+            }
+            let mut buf = BytesMut::with_capacity(1024);
+            codec.encode(Request::Data.into(), &mut buf).unwrap();
+            codec.encode(Frame::Body { chunk: Some("Grüße\r\n".as_bytes().to_vec()) }, &mut buf)
+        };
+        assert!(encode(None).is_ok());
+        assert!(encode(Some("250-mail.example.test\r\n250 8BITMIME\r\n")).is_ok());
+        let err = encode(Some("250 mail.example.test\r\n")).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_proto_eight_bit_body() {
+        // `Mailer` sends bodies without declaring them, which works as long
+        // as the server supports `8BITMIME`.
+        let send = |ehlo: &str, body: &str| {
+            let server = MockSmtpServer::new()
+                .greeting("220 mail.example.test ESMTP\r\n")
+                .expect("EHLO client.example.test\r\n", ehlo)
+                .expect("MAIL FROM:<john@example.test>\r\n", "250 OK\r\n")
+                .expect("DATA\r\n", "354 Start mail input\r\n")
+                .expect(&format!("{}.\r\n", body), "250 Queued\r\n");
+            let proto = ClientProto(Arc::new(ClientParams {
+                id: ClientId::Domain("client.example.test".to_string()),
+                security: ClientSecurity::None,
+                auth: None,
+            }));
+            let transport = proto.bind_transport(server.clone()).wait().unwrap();
+            let mail = Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] };
+            let transport = transport.send(mail.into()).wait().unwrap();
+            let (response, transport) = transport.into_future().wait().map_err(|(err, _)| err).unwrap();
+            assert!(response.is_some());
+            let result = transport.send_data(body.as_bytes().to_vec()).wait()
+                .map(|(response, _)| response.to_string());
+            (result, server.is_done())
+        };
+
+        let (result, done) = send("250-mail.example.test\r\n250 8BITMIME\r\n", "Grüße\r\n");
+        assert_eq!(result.unwrap(), "250 Queued\r\n");
+        assert!(done);
+
+        let (result, done) = send("250 mail.example.test\r\n", "Hello\r\n");
+        assert_eq!(result.unwrap(), "250 Queued\r\n");
+        assert!(done);
+
+        let (result, done) = send("250 mail.example.test\r\n", "Grüße\r\n");
+        match result.unwrap_err() {
+            SmtpError::Io(err) => assert_eq!(err.kind(), IoErrorKind::InvalidInput),
+            err => panic!("unexpected error: {}", err),
+        }
+        assert!(!done);
+    }
+
+    #[test]
+    fn test_proto_session_checks() {
+        let server = MockSmtpServer::new()
+            .greeting("220 mail.example.test ESMTP\r\n")
+            .expect("EHLO client.example.test\r\n", "250 mail.example.test\r\n")
+            .expect("MAIL FROM:<john@example.test>\r\n", "250 OK\r\n");
+        let proto = ClientProto(Arc::new(ClientParams {
+            id: ClientId::Domain("client.example.test".to_string()),
+            security: ClientSecurity::None,
+            auth: None,
+        }));
+        let transport = proto.bind_transport(server.clone()).wait().unwrap();
+
+        // Without `DSN`, the parameters are removed.
+        let mail: Request = "MAIL FROM:<john@example.test> RET=HDRS\r\n".parse().unwrap();
+        let transport = transport.send(mail.into()).wait().unwrap();
+        let (response, mut transport) = transport.into_future().wait().map_err(|(err, _)| err).unwrap();
+        assert!(response.is_some());
+        assert!(server.is_done());
+
+        let err = transport.start_send(Request::StartTls.into()).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+        let mail: Request = "MAIL FROM:<john@example.test> BODY=8BITMIME\r\n".parse().unwrap();
+        let err = transport.start_send(mail.into()).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_dsn() {
+        let batch = || vec![
+            "MAIL FROM:<john@example.test> RET=HDRS ENVID=QQ314159\r\n".parse().unwrap(),
+            "RCPT TO:<alice@example.test> NOTIFY=NEVER\r\n".parse().unwrap(),
+        ];
+
+        let mut session = SmtpSession::new();
+        session.ehlo(&"250-mail.example.test\r\n250-PIPELINING\r\n250 DSN\r\n".parse().unwrap());
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, batch(), &mut buf).is_ok());
+        assert_eq!(&buf[..], b"MAIL FROM:<john@example.test> RET=HDRS ENVID=QQ314159\r\n\
+            RCPT TO:<alice@example.test> NOTIFY=NEVER\r\n".as_ref());
+
+        session.ehlo(&"250-mail.example.test\r\n250 PIPELINING\r\n".parse().unwrap());
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, batch(), &mut buf).is_ok());
+        assert_eq!(&buf[..], b"MAIL FROM:<john@example.test>\r\nRCPT TO:<alice@example.test>\r\n".as_ref());
+    }
+
+    #[test]
+    fn test_starttls() {
+        let mut session = SmtpSession::new();
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, vec![Request::StartTls], &mut buf).is_err());
+
+        session.ehlo(&"250-mail.example.test\r\n250 SIZE 1024\r\n".parse().unwrap());
+        assert!(ClientCodec::new().encode_batch(&session, vec![Request::StartTls], &mut buf).is_err());
+        assert!(buf.is_empty());
+
+        let mut codec = ClientCodec::new();
+        codec.set_force_starttls(true);
+        assert!(codec.encode_batch(&session, vec![Request::StartTls], &mut buf).is_ok());
+        assert_eq!(&buf[..], b"STARTTLS\r\n");
+
+        session.ehlo(&"250-mail.example.test\r\n250 STARTTLS\r\n".parse().unwrap());
+        let mut buf = BytesMut::with_capacity(1024);
+        assert!(ClientCodec::new().encode_batch(&session, vec![Request::StartTls], &mut buf).is_ok());
+        assert_eq!(&buf[..], b"STARTTLS\r\n");
+    }
+}
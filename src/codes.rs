@@ -0,0 +1,119 @@
+//! Named reply codes
+//!
+//! These are the reply codes of RFC 5321, section 4.2, and of the extensions
+//! implemented by this crate. Use `Code::from_numeric` to turn them into a
+//! `Code`, e.g. for `Response::builder`, and `Code::numeric` to compare.
+
+/// `211` System status, or system help reply
+pub const SYSTEM_STATUS: u16 = 211;
+/// `214` Help message
+pub const HELP_MESSAGE: u16 = 214;
+/// `220` Service ready, the greeting
+pub const SERVICE_READY: u16 = 220;
+/// `221` Service closing transmission channel, the reply to `QUIT`
+pub const SERVICE_CLOSING: u16 = 221;
+/// `235` Authentication succeeded (RFC 4954)
+pub const AUTH_SUCCEEDED: u16 = 235;
+/// `250` Requested mail action okay, completed
+pub const OK: u16 = 250;
+/// `251` User not local, will forward
+pub const USER_NOT_LOCAL: u16 = 251;
+/// `252` Cannot verify the user, but will accept the message
+pub const CANNOT_VERIFY: u16 = 252;
+
+/// `334` Authentication challenge (RFC 4954)
+pub const AUTH_CONTINUE: u16 = 334;
+/// `354` Start mail input, the reply to `DATA`
+pub const START_INPUT: u16 = 354;
+
+/// `421` Service not available, closing transmission channel
+pub const SERVICE_UNAVAILABLE: u16 = 421;
+/// `432` A password transition is needed (RFC 4954)
+pub const PASSWORD_TRANSITION_NEEDED: u16 = 432;
+/// `450` Mailbox unavailable, e.g. busy
+pub const MAILBOX_BUSY: u16 = 450;
+/// `451` Local error in processing
+pub const LOCAL_ERROR: u16 = 451;
+/// `452` Insufficient system storage
+pub const INSUFFICIENT_STORAGE: u16 = 452;
+/// `454` Temporary authentication failure (RFC 4954)
+pub const AUTH_TEMPORARY_FAILURE: u16 = 454;
+/// `455` Server unable to accommodate parameters
+pub const PARAMS_NOT_ACCOMMODATED: u16 = 455;
+
+/// `500` Syntax error, command unrecognized
+pub const SYNTAX_ERROR: u16 = 500;
+/// `501` Syntax error in parameters or arguments
+pub const PARAM_SYNTAX_ERROR: u16 = 501;
+/// `502` Command not implemented
+pub const COMMAND_NOT_IMPLEMENTED: u16 = 502;
+/// `503` Bad sequence of commands
+pub const BAD_SEQUENCE: u16 = 503;
+/// `504` Command parameter not implemented
+pub const PARAM_NOT_IMPLEMENTED: u16 = 504;
+/// `530` Authentication required (RFC 4954)
+pub const AUTH_REQUIRED: u16 = 530;
+/// `535` Authentication credentials invalid (RFC 4954)
+pub const AUTH_FAILED: u16 = 535;
+/// `550` Mailbox unavailable, e.g. not found
+pub const MAILBOX_UNAVAILABLE: u16 = 550;
+/// `551` User not local, try another path
+pub const USER_NOT_LOCAL_TRY_FORWARD: u16 = 551;
+/// `552` Exceeded storage allocation
+pub const STORAGE_EXCEEDED: u16 = 552;
+/// `553` Mailbox name not allowed
+pub const MAILBOX_NAME_NOT_ALLOWED: u16 = 553;
+/// `554` Transaction failed, or no SMTP service here
+pub const TRANSACTION_FAILED: u16 = 554;
+/// `555` `MAIL FROM` or `RCPT TO` parameters not recognized or implemented
+pub const PARAMS_NOT_RECOGNIZED: u16 = 555;
+
+
+/// Tells if a code is one of the named codes in this module
+///
+/// Other codes in range are still valid replies, e.g. for `Response::builder`,
+/// but a client may not know what they mean. This can be used to warn about
+/// them.
+pub fn is_defined(code: u16) -> bool {
+    match code {
+        SYSTEM_STATUS | HELP_MESSAGE | SERVICE_READY | SERVICE_CLOSING | AUTH_SUCCEEDED | OK |
+        USER_NOT_LOCAL | CANNOT_VERIFY |
+        AUTH_CONTINUE | START_INPUT |
+        SERVICE_UNAVAILABLE | PASSWORD_TRANSITION_NEEDED | MAILBOX_BUSY | LOCAL_ERROR |
+        INSUFFICIENT_STORAGE | AUTH_TEMPORARY_FAILURE | PARAMS_NOT_ACCOMMODATED |
+        SYNTAX_ERROR | PARAM_SYNTAX_ERROR | COMMAND_NOT_IMPLEMENTED | BAD_SEQUENCE |
+        PARAM_NOT_IMPLEMENTED | AUTH_REQUIRED | AUTH_FAILED | MAILBOX_UNAVAILABLE |
+        USER_NOT_LOCAL_TRY_FORWARD | STORAGE_EXCEEDED | MAILBOX_NAME_NOT_ALLOWED |
+        TRANSACTION_FAILED | PARAMS_NOT_RECOGNIZED => true,
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use codes;
+    use response::{Code};
+
+    #[test]
+    fn test() {
+        assert_eq!(codes::SERVICE_READY, 220);
+        assert_eq!(codes::OK, 250);
+        assert_eq!(codes::START_INPUT, 354);
+        assert_eq!(codes::MAILBOX_UNAVAILABLE, 550);
+
+        for code in vec![codes::SERVICE_CLOSING, codes::AUTH_CONTINUE, codes::LOCAL_ERROR, codes::AUTH_FAILED] {
+            let parsed = Code::from_numeric(code).unwrap();
+            assert_eq!(parsed.to_string(), code.to_string());
+            assert_eq!(parsed.numeric(), code);
+        }
+        for code in vec![0, 99, 199, 600, 1000] {
+            assert_eq!(Code::from_numeric(code), None);
+        }
+
+        assert!(codes::is_defined(codes::OK));
+        assert!(codes::is_defined(555));
+        assert!(!codes::is_defined(299));
+        assert!(!codes::is_defined(700));
+    }
+}
@@ -0,0 +1,75 @@
+//! Errors shared by the request and response parsers
+
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+
+/// The reason parsing failed
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub enum ParseErrorKind {
+    /// The input ended before parsing was complete
+    Incomplete,
+    /// A response code is not a valid three digit code
+    InvalidCode,
+    /// The lines of a response have different codes
+    MismatchedCode,
+    /// The command verb is not known
+    UnknownCommand,
+    /// A `:` was expected after `FROM` or `TO`
+    ExpectedColon,
+    /// A mailbox is not a valid `<path>`
+    InvalidPath,
+    /// A required command argument is missing or empty
+    MissingArgument,
+    /// A mailbox contains non-ASCII characters, but `SMTPUTF8` is not in use
+    NonAsciiAddress,
+    /// An extension parameter is not valid
+    InvalidParam,
+    /// An extension parameter is given more than once
+    DuplicateParam,
+    /// A command line is longer than `MAX_COMMAND_LINE_LEN`
+    LineTooLong,
+    /// The input does not follow the expected syntax
+    InvalidSyntax,
+}
+
+
+/// An error from parsing a request or response
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub struct ParseError {
+    /// The byte offset in the input where parsing failed
+    pub offset: usize,
+    /// The reason parsing failed
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub fn new(offset: usize, kind: ParseErrorKind) -> Self {
+        ParseError { offset, kind }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} at offset {}", self.description(), self.offset)
+    }
+}
+
+impl StdError for ParseError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ParseErrorKind::Incomplete => "incomplete input",
+            ParseErrorKind::InvalidCode => "invalid response code",
+            ParseErrorKind::MismatchedCode => "mismatched response code",
+            ParseErrorKind::UnknownCommand => "unknown command",
+            ParseErrorKind::ExpectedColon => "expected colon",
+            ParseErrorKind::InvalidPath => "invalid path",
+            ParseErrorKind::MissingArgument => "missing argument",
+            ParseErrorKind::NonAsciiAddress => "non-ASCII address without SMTPUTF8",
+            ParseErrorKind::InvalidParam => "invalid parameter",
+            ParseErrorKind::DuplicateParam => "duplicate parameter",
+            ParseErrorKind::LineTooLong => "command line too long",
+            ParseErrorKind::InvalidSyntax => "syntax error",
+        }
+    }
+}
@@ -46,6 +46,7 @@
 
 extern crate emailaddress;
 extern crate base64;
+#[macro_use]
 extern crate futures;
 extern crate native_tls;
 #[macro_use]
@@ -58,10 +59,35 @@ extern crate tokio_io;
 extern crate tokio_tls;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "resolve")]
+extern crate dns_lookup;
+#[cfg(feature = "scram")]
+extern crate hmac;
+#[cfg(feature = "lettre-interop")]
+extern crate lettre;
+#[cfg(feature = "scram")]
+extern crate sha2;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "random-envid")]
+extern crate uuid;
 
+pub mod auth;
+pub mod capabilities;
 pub mod client;
+pub mod codes;
+pub mod error;
+pub mod line;
 pub mod request;
+#[cfg(feature = "resolve")]
+pub mod resolve;
 pub mod response;
+pub mod server;
+pub mod session;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod uri;
+mod addr;
 mod util;
 
 use client::{ClientParams, ClientAuth, ClientProto, ClientSecurity, ClientTlsParams};
@@ -116,10 +142,7 @@ impl Mailer {
             .and_then(move |service| {
                 let mut reqs = Vec::with_capacity(4);
                 reqs.push(service.call(
-                    Message::WithoutBody(SmtpRequest::Mail {
-                        from: return_path,
-                        params: vec![],
-                    })
+                    Message::WithoutBody(SmtpRequest::mail(return_path, &recipients, vec![]))
                 ));
                 for recipient in recipients {
                     reqs.push(service.call(
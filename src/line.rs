@@ -0,0 +1,171 @@
+//! Reading CRLF-terminated lines
+//!
+//! SMTP requests and replies are lines terminated by CRLF. The `LineReader`
+//! type reads such lines from a connection, and `split_line` does the same
+//! for a buffer, for use in decoders.
+
+use bytes::{BytesMut};
+use futures::{Async, Poll, Stream};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use tokio_io::{AsyncRead};
+
+
+/// Split the first complete line off a buffer, without its terminator
+///
+/// Returns `None` if the buffer does not contain a complete line yet. In
+/// strict mode, a LF that is not preceded by a CR is an error. Otherwise, a
+/// bare LF also ends a line.
+pub fn split_line(buf: &mut BytesMut, strict: bool) -> IoResult<Option<Vec<u8>>> {
+    let pos = match buf.iter().position(|&byte| byte == b'\n') {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let has_cr = pos > 0 && buf[pos - 1] == b'\r';
+    if strict && !has_cr {
+        return Err(IoError::new(IoErrorKind::InvalidData, "bare LF in line"));
+    }
+    let line = buf.split_to(pos + 1);
+    let len = if has_cr { pos - 1 } else { pos };
+    Ok(Some(line[..len].to_vec()))
+}
+
+
+/// A stream of lines read from a connection
+///
+/// Each line is yielded without its CRLF terminator. The reader is strict by
+/// default, so a bare LF fails the stream, see `set_strict`. If the
+/// connection is closed in the middle of a line, the stream fails with an
+/// `UnexpectedEof` error.
+pub struct LineReader<R> {
+    io: R,
+    buf: BytesMut,
+    strict: bool,
+}
+
+impl<R: AsyncRead> LineReader<R> {
+    /// Create a strict reader for a connection
+    pub fn new(io: R) -> Self {
+        LineReader { io, buf: BytesMut::new(), strict: true }
+    }
+
+    /// Set whether a bare LF fails the stream, or ends a line
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Tells if a bare LF fails the stream
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Data read from the connection, but not yet yielded as a line
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Returns the connection, dropping any buffered data
+    pub fn into_inner(self) -> R {
+        self.io
+    }
+}
+
+impl<R: AsyncRead> Stream for LineReader<R> {
+    type Item = Vec<u8>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, IoError> {
+        loop {
+            if let Some(line) = split_line(&mut self.buf, self.strict)? {
+                return Ok(Async::Ready(Some(line)));
+            }
+            self.buf.reserve(1024);
+            if try_ready!(self.io.read_buf(&mut self.buf)) == 0 {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                return Err(IoError::new(IoErrorKind::UnexpectedEof, "connection closed mid-line"));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BytesMut};
+    use futures::{Future, Stream};
+    use line::{LineReader, split_line};
+    use std::collections::{VecDeque};
+    use std::io::{ErrorKind as IoErrorKind, Read, Result as IoResult};
+    use tokio_io::{AsyncRead};
+
+    /// Returns each chunk from a separate read
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let chunk = match self.0.pop_front() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            assert!(chunk.len() <= buf.len());
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl AsyncRead for Chunks {}
+
+    fn reader(chunks: Vec<&'static [u8]>) -> LineReader<Chunks> {
+        LineReader::new(Chunks(chunks.into_iter().collect()))
+    }
+
+    #[test]
+    fn test() {
+        let lines = reader(vec![b"EHLO exa", b"mple.test\r", b"\nMAIL FROM:<>\r\n\r\nQU", b"IT\r\n"])
+            .collect().wait().unwrap();
+        assert_eq!(lines, vec![
+            b"EHLO example.test".to_vec(),
+            b"MAIL FROM:<>".to_vec(),
+            b"".to_vec(),
+            b"QUIT".to_vec(),
+        ]);
+
+        let lines = reader(vec![b"NOOP\r\n", b"RSET\nQUIT\r\n"]);
+        assert!(lines.is_strict());
+        let (line, lines) = lines.into_future().wait().map_err(|(err, _)| err).unwrap();
+        assert_eq!(line, Some(b"NOOP".to_vec()));
+        let err = lines.into_future().wait().err().unwrap().0;
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "bare LF in line");
+
+        let mut lines = reader(vec![b"NOOP\r\n", b"RSET\nQUIT\r\n"]);
+        lines.set_strict(false);
+        assert_eq!(lines.collect().wait().unwrap(), vec![
+            b"NOOP".to_vec(),
+            b"RSET".to_vec(),
+            b"QUIT".to_vec(),
+        ]);
+
+        let err = reader(vec![b"NOOP\r\nQU"]).collect().wait().err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_split_line() {
+        let mut buf = BytesMut::from(b"250 OK\r\n354 Go".as_ref());
+        assert_eq!(split_line(&mut buf, true).unwrap(), Some(b"250 OK".to_vec()));
+        assert_eq!(split_line(&mut buf, true).unwrap(), None);
+        assert_eq!(&buf[..], b"354 Go");
+        buf.extend_from_slice(b" ahead\r");
+        assert_eq!(split_line(&mut buf, true).unwrap(), None);
+        buf.extend_from_slice(b"\n");
+        assert_eq!(split_line(&mut buf, true).unwrap(), Some(b"354 Go ahead".to_vec()));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(b"\n250 OK\r\n".as_ref());
+        assert!(split_line(&mut buf, true).is_err());
+        assert_eq!(split_line(&mut buf, false).unwrap(), Some(vec![]));
+        assert_eq!(split_line(&mut buf, false).unwrap(), Some(b"250 OK".to_vec()));
+    }
+}
@@ -5,13 +5,27 @@
 
 // FIXME: Add parsing.
 
+use addr::{MAX_DOMAIN_LEN, MAX_LOCAL_LEN, parse_addr_spec};
+use capabilities::{Capabilities};
+use codes;
 use emailaddress::{EmailAddress, AddrError};
-use std::io::{Error as IoError};
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use error::{ParseError, ParseErrorKind};
+#[cfg(feature = "lettre-interop")]
+use lettre::{Envelope};
+use response::{Response};
+use session::{SmtpSession};
+use std::borrow::{Cow};
+use std::cmp::{Ordering};
+use std::error::{Error as StdError};
+use std::io::{Write};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult, Write as FmtWrite};
+use std::hash::{Hash, Hasher};
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::{FromStr};
+use std::str::{FromStr, from_utf8};
 use tokio_proto::streaming::pipeline::{Frame};
-use util::{XText};
+use util::{XText, decode_xtext, decode_xtext_bytes};
+#[cfg(feature = "random-envid")]
+use uuid::{Uuid};
 
 
 /// Client identifier, the parameter to `EHLO`
@@ -27,6 +41,57 @@ pub enum ClientId {
     Other { tag: String, value: String },
 }
 
+impl ClientId {
+    /// Create a custom identifier, validating the tag
+    ///
+    /// The tag must not be empty, and may not contain `:` or whitespace.
+    pub fn other<S: Into<String>>(tag: S, value: S) -> Result<ClientId, ClientIdError> {
+        let tag = tag.into();
+        if tag.is_empty() {
+            return Err(ClientIdError::EmptyTag);
+        }
+        if tag.chars().any(|c| c == ':' || c.is_whitespace()) {
+            return Err(ClientIdError::InvalidTag);
+        }
+        Ok(ClientId::Other { tag, value: value.into() })
+    }
+
+    /// Parse the server identity from a `220` greeting or `250` `EHLO` reply
+    ///
+    /// This is the first word of the reply. An address literal such as
+    /// `[192.0.2.1]` or `[IPv6:2001:db8::1]` becomes an address; anything
+    /// else is parsed like the argument to `EHLO`. Returns `None` for other
+    /// codes, or if the reply has no text.
+    pub fn from_response(response: &Response) -> Option<ClientId> {
+        match response.code.numeric() {
+            codes::SERVICE_READY | codes::OK => {},
+            _ => return None,
+        }
+        let word = response.ehlo_domain()?;
+        Some(parse_address_literal(word).unwrap_or_else(|| parse_client_id(word)))
+    }
+
+    /// Tells if both identifiers name the same host
+    ///
+    /// Unlike `==`, domains are compared case-insensitively, and an address
+    /// literal in brackets, such as `[192.0.2.1]`, equals the address. Custom
+    /// identifiers have a case-insensitive tag, and must have equal values.
+    pub fn same_host(&self, other: &ClientId) -> bool {
+        match (self.normalized(), other.normalized()) {
+            (ClientId::Domain(a), ClientId::Domain(b)) => a.eq_ignore_ascii_case(&b),
+            (ClientId::Other { tag: a_tag, value: a_value }, ClientId::Other { tag: b_tag, value: b_value }) => {
+                a_tag.eq_ignore_ascii_case(&b_tag) && a_value == b_value
+            },
+            (a, b) => a == b,
+        }
+    }
+
+    /// Turn an address literal that was parsed as another kind into an address
+    fn normalized(&self) -> ClientId {
+        parse_address_literal(&self.to_string()).unwrap_or_else(|| self.clone())
+    }
+}
+
 impl Display for ClientId {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
@@ -39,10 +104,143 @@ impl Display for ClientId {
 }
 
 
+/// Errors from validating a `ClientId`
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum ClientIdError {
+    /// The tag of a custom identifier is empty
+    EmptyTag,
+    /// The tag of a custom identifier contains `:` or whitespace
+    InvalidTag,
+}
+
+impl Display for ClientIdError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for ClientIdError {
+    fn description(&self) -> &str {
+        match *self {
+            ClientIdError::EmptyTag => "client id tag is empty",
+            ClientIdError::InvalidTag => "client id tag contains invalid characters",
+        }
+    }
+}
+
+
 /// A mailbox specified in `MAIL FROM` or `RCPT TO`
-#[derive(PartialEq,Clone,Debug)]
+///
+/// Address literal domains, such as `[192.0.2.1]` or `[IPv6:2001:db8::1]`,
+/// are kept as is, including the brackets. So are quoted local parts, such as
+/// `"John Doe"`, including the quotes.
+///
+/// Mailboxes can be used as `HashMap` keys and in sorted collections. They are
+/// ordered by local part, then domain, with the null path first.
+///
+/// The domainless `<Postmaster>` recipient (RFC 5321, section 4.5.1) is
+/// stored with the local part `Postmaster` and an empty domain, which no
+/// other address has. See `Mailbox::postmaster`.
+///
+/// The null path is `Mailbox(None)`, see `Mailbox::null`. An address with an
+/// empty local part and domain also displays as `<>`, and is treated as the
+/// null path when comparing, hashing and ordering.
+#[derive(Clone,Debug)]
 pub struct Mailbox(pub Option<EmailAddress>);
 
+impl Mailbox {
+    /// The null path `<>`, e.g. the return path of a bounce
+    pub fn null() -> Mailbox {
+        Mailbox(None)
+    }
+
+    /// Tells if this is the null path
+    pub fn is_null(&self) -> bool {
+        self.parts().is_none()
+    }
+
+    /// Whether this mailbox requires the `SMTPUTF8` extension
+    ///
+    /// This is the case if the address contains any non-ASCII characters.
+    pub fn needs_smtputf8(&self) -> bool {
+        match self.0 {
+            Some(ref email) => !email.local.is_ascii() || !email.domain.is_ascii(),
+            None => false,
+        }
+    }
+
+    /// The domainless `<Postmaster>` mailbox
+    ///
+    /// Servers must accept mail for it, without requiring a domain.
+    pub fn postmaster() -> Mailbox {
+        Mailbox(Some(EmailAddress {
+            local: "Postmaster".to_string(),
+            domain: String::new(),
+        }))
+    }
+
+    /// Tells if this is the postmaster, with or without a domain
+    ///
+    /// The local part `Postmaster` is case-insensitive.
+    pub fn is_postmaster(&self) -> bool {
+        self.0.as_ref().map_or(false, |email| email.local.eq_ignore_ascii_case("Postmaster"))
+    }
+
+    /// Tells if this is the domainless `<Postmaster>` mailbox
+    pub fn is_domainless_postmaster(&self) -> bool {
+        self.0.as_ref().map_or(false, |email| email.domain.is_empty()) && self.is_postmaster()
+    }
+
+    /// Parse an address using the RFC 5321 mailbox grammar
+    ///
+    /// Unlike `FromStr`, which accepts anything with an `@`, this requires a
+    /// dot-atom or quoted local part, and a valid domain or address literal.
+    /// Like `FromStr`, the empty null path and a bare `Postmaster` are also
+    /// accepted.
+    pub fn parse_strict(string: &str) -> Result<Mailbox, AddrError> {
+        if string.is_empty() || string.eq_ignore_ascii_case("Postmaster") {
+            return string.parse();
+        }
+        let (local, domain) = parse_addr_spec(string)
+            .map_err(|msg| AddrError { msg: msg.to_string() })?;
+        Ok(EmailAddress { local: local.to_string(), domain: domain.to_string() }.into())
+    }
+
+    fn parts(&self) -> Option<(&str, &str)> {
+        self.0.as_ref()
+            .map(|email| (email.local.as_str(), email.domain.as_str()))
+            .filter(|&(local, domain)| !local.is_empty() || !domain.is_empty())
+    }
+}
+
+// Comparing, hashing and ordering all use the same two strings, with an empty
+// address as the null path.
+impl PartialEq for Mailbox {
+    fn eq(&self, other: &Mailbox) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for Mailbox {}
+
+impl Hash for Mailbox {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.parts().hash(state)
+    }
+}
+
+impl PartialOrd for Mailbox {
+    fn partial_cmp(&self, other: &Mailbox) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Mailbox {
+    fn cmp(&self, other: &Mailbox) -> Ordering {
+        self.parts().cmp(&other.parts())
+    }
+}
+
 impl From<EmailAddress> for Mailbox {
     fn from(addr: EmailAddress) -> Self {
         Mailbox(Some(addr))
@@ -52,9 +250,12 @@ impl From<EmailAddress> for Mailbox {
 impl FromStr for Mailbox {
     type Err = AddrError;
 
+    /// Parse an address, the empty null path, or a bare `Postmaster`
     fn from_str(string: &str) -> Result<Mailbox, AddrError> {
         if string.is_empty() {
             Ok(Mailbox(None))
+        } else if string.eq_ignore_ascii_case("Postmaster") {
+            Ok(Mailbox::postmaster())
         } else {
             Ok(EmailAddress::new(string)?.into())
         }
@@ -64,6 +265,7 @@ impl FromStr for Mailbox {
 impl Display for Mailbox {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match self.0 {
+            Some(ref email) if email.domain.is_empty() => write!(f, "<{}>", email.local),
             Some(ref email) => write!(f, "<{}>", email),
             None => f.write_str("<>"),
         }
@@ -71,12 +273,74 @@ impl Display for Mailbox {
 }
 
 
+/// A view of a `MAIL FROM` or `RCPT TO` parameter, regardless of its type
+///
+/// The value is in decoded form, i.e. not xtext encoded. Invalid UTF-8 in the
+/// value of an unknown parameter is replaced, like `String::from_utf8_lossy`.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct ParamRef<'a> {
+    pub keyword: &'a str,
+    pub value: Option<Cow<'a, str>>,
+}
+
+
 /// A `MAIL FROM` extension parameter
 #[derive(PartialEq,Eq,Clone,Debug)]
 pub enum MailParam {
     Body(MailBodyParam),
     Size(usize),
-    Other { keyword: String, value: Option<String> },
+    SmtpUtf8,
+    /// The identity that submitted the message (RFC 4954), decoded, or `<>`
+    /// if it is unknown
+    Auth(String),
+    /// The envelope identifier for delivery status notifications (RFC 3461),
+    /// decoded
+    Envid(String),
+    /// Any other parameter, see `other`
+    ///
+    /// The value is xtext decoded, and may be any bytes, not only UTF-8.
+    Other { keyword: String, value: Option<Vec<u8>> },
+}
+
+impl MailParam {
+    /// Create a parameter of an extension this crate does not know
+    ///
+    /// The keyword must be an `esmtp-keyword` (RFC 5321, section 4.1.2): a
+    /// letter or digit, followed by letters, digits and hyphens, such as
+    /// `X-VENDOR-FLAG`. Fails otherwise.
+    pub fn other<S: Into<String>>(keyword: S, value: Option<Vec<u8>>) -> Result<MailParam, ()> {
+        let keyword = keyword.into();
+        if !is_esmtp_keyword(&keyword) {
+            return Err(());
+        }
+        Ok(MailParam::Other { keyword, value })
+    }
+
+    /// Create the `SIZE` parameter for the given message body
+    ///
+    /// The size is that of the body as sent on the wire, i.e. including the
+    /// extra dots added to lines starting with a dot. This may be slightly more
+    /// than the size defined by RFC 1870, but is never less.
+    pub fn size_for(body: &[u8]) -> MailParam {
+        let stuffed = body.windows(3).filter(|window| *window == b"\r\n.").count() +
+            if body.starts_with(b".") { 1 } else { 0 };
+        MailParam::Size(body.len() + stuffed)
+    }
+
+    /// A view of the keyword and value of this parameter
+    pub fn as_param_ref(&self) -> ParamRef {
+        let (keyword, value) = match *self {
+            MailParam::Body(ref value) => ("BODY", Some(Cow::Owned(value.to_string()))),
+            MailParam::Size(size) => ("SIZE", Some(Cow::Owned(size.to_string()))),
+            MailParam::SmtpUtf8 => ("SMTPUTF8", None),
+            MailParam::Auth(ref value) => ("AUTH", Some(Cow::Borrowed(value.as_str()))),
+            MailParam::Envid(ref value) => ("ENVID", Some(Cow::Borrowed(value.as_str()))),
+            MailParam::Other { ref keyword, ref value } => {
+                (keyword.as_str(), value.as_ref().map(|value| String::from_utf8_lossy(value)))
+            },
+        };
+        ParamRef { keyword, value }
+    }
 }
 
 impl Display for MailParam {
@@ -84,6 +348,9 @@ impl Display for MailParam {
         match *self {
             MailParam::Body(ref value) => write!(f, "BODY={}", value),
             MailParam::Size(size) => write!(f, "SIZE={}", size),
+            MailParam::SmtpUtf8 => f.write_str("SMTPUTF8"),
+            MailParam::Auth(ref value) => write!(f, "AUTH={}", XText(value.as_bytes())),
+            MailParam::Envid(ref value) => write!(f, "ENVID={}", XText(value.as_bytes())),
             MailParam::Other { ref keyword, value: Some(ref value) } => {
                 write!(f, "{}={}", keyword, XText(value))
             },
@@ -94,6 +361,41 @@ impl Display for MailParam {
     }
 }
 
+impl FromStr for MailParam {
+    type Err = ();
+
+    /// Parse a parameter in `KEYWORD[=VALUE]` form
+    ///
+    /// The keyword is validated like in `MailParam::other`, and the value of
+    /// unknown parameters is xtext decoded.
+    fn from_str(s: &str) -> Result<MailParam, ()> {
+        let mut parts = s.splitn(2, '=');
+        let keyword = parts.next().unwrap_or("");
+        let value = parts.next();
+        if !is_esmtp_keyword(keyword) {
+            return Err(());
+        }
+
+        match (keyword.to_ascii_uppercase().as_str(), value) {
+            ("BODY", Some(value)) => Ok(MailParam::Body(value.parse()?)),
+            ("SIZE", Some(value)) => Ok(MailParam::Size(value.parse().map_err(|_| ())?)),
+            ("SMTPUTF8", None) => Ok(MailParam::SmtpUtf8),
+            ("AUTH", Some(value)) => Ok(MailParam::Auth(decode_xtext(value).ok_or(())?)),
+            ("ENVID", Some(value)) => Ok(MailParam::Envid(decode_xtext(value).ok_or(())?)),
+            ("BODY", None) | ("SIZE", None) | ("SMTPUTF8", Some(_)) | ("AUTH", None) |
+            ("ENVID", None) => Err(()),
+            (_, Some(value)) => Ok(MailParam::Other {
+                keyword: keyword.to_string(),
+                value: Some(decode_xtext_bytes(value).ok_or(())?),
+            }),
+            (_, None) => Ok(MailParam::Other {
+                keyword: keyword.to_string(),
+                value: None,
+            }),
+        }
+    }
+}
+
 
 /// Values for the `BODY` parameter to `MAIL FROM`
 #[derive(PartialEq,Eq,Clone,Debug)]
@@ -102,6 +404,24 @@ pub enum MailBodyParam {
     SevenBit,
     /// `8BITMIME`
     EightBitMime,
+    /// `BINARYMIME`
+    ///
+    /// Per RFC 3030, a message declared this way must be transferred using
+    /// `BDAT`, not `DATA`. The client codec refuses to send `DATA` for it.
+    BinaryMime,
+}
+
+impl FromStr for MailBodyParam {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MailBodyParam, ()> {
+        match s.to_ascii_uppercase().as_str() {
+            "7BIT" => Ok(MailBodyParam::SevenBit),
+            "8BITMIME" => Ok(MailBodyParam::EightBitMime),
+            "BINARYMIME" => Ok(MailBodyParam::BinaryMime),
+            _ => Err(()),
+        }
+    }
 }
 
 impl Display for MailBodyParam {
@@ -109,6 +429,67 @@ impl Display for MailBodyParam {
         match *self {
             MailBodyParam::SevenBit => f.write_str("7BIT"),
             MailBodyParam::EightBitMime => f.write_str("8BITMIME"),
+            MailBodyParam::BinaryMime => f.write_str("BINARYMIME"),
+        }
+    }
+}
+
+
+/// Values for the `NOTIFY` parameter to `RCPT TO` (RFC 3461)
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum NotifyParam {
+    /// `NEVER`
+    Never,
+    /// Any combination of `SUCCESS`, `FAILURE` and `DELAY`
+    ///
+    /// If none of the conditions are set, this is equivalent to `Never`.
+    Conditions { success: bool, failure: bool, delay: bool },
+}
+
+impl FromStr for NotifyParam {
+    type Err = ();
+
+    /// Parse the parameter value
+    ///
+    /// `NEVER` cannot be combined with other values, and values cannot be
+    /// repeated.
+    fn from_str(s: &str) -> Result<NotifyParam, ()> {
+        if s.eq_ignore_ascii_case("NEVER") {
+            return Ok(NotifyParam::Never);
+        }
+
+        let (mut success, mut failure, mut delay) = (false, false, false);
+        for value in s.split(',') {
+            let flag = match value.to_ascii_uppercase().as_str() {
+                "SUCCESS" => &mut success,
+                "FAILURE" => &mut failure,
+                "DELAY" => &mut delay,
+                _ => return Err(()),
+            };
+            if *flag {
+                return Err(());
+            }
+            *flag = true;
+        }
+        Ok(NotifyParam::Conditions { success, failure, delay })
+    }
+}
+
+impl Display for NotifyParam {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            NotifyParam::Conditions { success, failure, delay } if success || failure || delay => {
+                let values = [(success, "SUCCESS"), (failure, "FAILURE"), (delay, "DELAY")];
+                let values = values.iter().filter(|&&(set, _)| set).map(|&(_, value)| value);
+                for (idx, value) in values.enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(value)?;
+                }
+                Ok(())
+            },
+            _ => f.write_str("NEVER"),
         }
     }
 }
@@ -117,12 +498,90 @@ impl Display for MailBodyParam {
 /// A `RCPT TO` extension parameter
 #[derive(PartialEq,Eq,Clone,Debug)]
 pub enum RcptParam {
-    Other { keyword: String, value: Option<String> },
+    Notify(NotifyParam),
+    /// The original recipient, as an address type (e.g. `rfc822`) and address
+    ///
+    /// Only the address is xtext encoded, the address type is sent as is.
+    Orcpt { addr_type: String, addr: String },
+    /// Any other parameter, see `MailParam::other`
+    Other { keyword: String, value: Option<Vec<u8>> },
+}
+
+impl FromStr for RcptParam {
+    type Err = ();
+
+    /// Parse a parameter in `KEYWORD[=VALUE]` form
+    ///
+    /// The keyword is validated like in `MailParam::other`, and the value of
+    /// unknown parameters is xtext decoded.
+    fn from_str(s: &str) -> Result<RcptParam, ()> {
+        let mut parts = s.splitn(2, '=');
+        let keyword = parts.next().unwrap_or("");
+        let value = parts.next();
+        if !is_esmtp_keyword(keyword) {
+            return Err(());
+        }
+
+        match (keyword.to_ascii_uppercase().as_str(), value) {
+            ("NOTIFY", Some(value)) => Ok(RcptParam::Notify(value.parse()?)),
+            ("NOTIFY", None) => Err(()),
+            ("ORCPT", Some(value)) => {
+                let mut parts = value.splitn(2, ';');
+                match (parts.next(), parts.next()) {
+                    (Some(addr_type), Some(addr)) if !addr_type.is_empty() => Ok(RcptParam::Orcpt {
+                        addr_type: addr_type.to_string(),
+                        addr: decode_xtext(addr).ok_or(())?,
+                    }),
+                    _ => Err(()),
+                }
+            },
+            ("ORCPT", None) => Err(()),
+            (_, Some(value)) => Ok(RcptParam::Other {
+                keyword: keyword.to_string(),
+                value: Some(decode_xtext_bytes(value).ok_or(())?),
+            }),
+            (_, None) => Ok(RcptParam::Other {
+                keyword: keyword.to_string(),
+                value: None,
+            }),
+        }
+    }
+}
+
+impl RcptParam {
+    /// Create a parameter of an extension this crate does not know
+    ///
+    /// The keyword is validated like `MailParam::other`.
+    pub fn other<S: Into<String>>(keyword: S, value: Option<Vec<u8>>) -> Result<RcptParam, ()> {
+        let keyword = keyword.into();
+        if !is_esmtp_keyword(&keyword) {
+            return Err(());
+        }
+        Ok(RcptParam::Other { keyword, value })
+    }
+
+    /// A view of the keyword and value of this parameter
+    pub fn as_param_ref(&self) -> ParamRef {
+        let (keyword, value) = match *self {
+            RcptParam::Notify(ref value) => ("NOTIFY", Some(Cow::Owned(value.to_string()))),
+            RcptParam::Orcpt { ref addr_type, ref addr } => {
+                ("ORCPT", Some(Cow::Owned(format!("{};{}", addr_type, addr))))
+            },
+            RcptParam::Other { ref keyword, ref value } => {
+                (keyword.as_str(), value.as_ref().map(|value| String::from_utf8_lossy(value)))
+            },
+        };
+        ParamRef { keyword, value }
+    }
 }
 
 impl Display for RcptParam {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
+            RcptParam::Notify(ref value) => write!(f, "NOTIFY={}", value),
+            RcptParam::Orcpt { ref addr_type, ref addr } => {
+                write!(f, "ORCPT={};{}", addr_type, XText(addr.as_bytes()))
+            },
             RcptParam::Other { ref keyword, value: Some(ref value) } => {
                 write!(f, "{}={}", keyword, XText(value))
             },
@@ -134,77 +593,1078 @@ impl Display for RcptParam {
 }
 
 
+/// Errors from validating a `Request`
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum RequestError {
+    /// A CR or LF character would be sent within the command line
+    ControlCharacter,
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for RequestError {
+    fn description(&self) -> &str {
+        match *self {
+            RequestError::ControlCharacter => "request contains a CR or LF character",
+        }
+    }
+}
+
+
+/// The longest command line allowed by RFC 5321, including the CRLF
+pub const MAX_COMMAND_LINE_LEN: usize = 512;
+
+
+/// A problem found when building a request, see `MailBuilder::build_validated`
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum BuildError {
+    /// A parameter keyword is empty, or contains characters other than
+    /// letters, digits and `-`
+    InvalidKeyword(String),
+    /// A parameter is given more than once, e.g. two conflicting `RET` values
+    DuplicateParam(String),
+    /// The command line is longer than `MAX_COMMAND_LINE_LEN`, with its length
+    LineTooLong(usize),
+    /// The request fails `Request::validate`
+    Invalid(RequestError),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            BuildError::InvalidKeyword(ref keyword) | BuildError::DuplicateParam(ref keyword) =>
+                write!(f, "{}: {}", self.description(), keyword),
+            BuildError::LineTooLong(len) => write!(f, "{}: {} octets", self.description(), len),
+            BuildError::Invalid(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl StdError for BuildError {
+    fn description(&self) -> &str {
+        match *self {
+            BuildError::InvalidKeyword(_) => "invalid parameter keyword",
+            BuildError::DuplicateParam(_) => "duplicate parameter",
+            BuildError::LineTooLong(_) => "command line too long",
+            BuildError::Invalid(ref err) => err.description(),
+        }
+    }
+}
+
+
+/// Session state that affects how requests are parsed
+#[derive(PartialEq,Eq,Clone,Debug,Default)]
+pub struct ParseOptions {
+    /// Whether the transaction was started with `SMTPUTF8`
+    pub smtputf8: bool,
+}
+
+
+/// The buffer given to `Request::write_into` cannot hold the request
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub struct BufferTooSmall {
+    /// The number of bytes needed
+    pub needed: usize,
+}
+
+impl Display for BufferTooSmall {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "buffer too small, {} bytes needed", self.needed)
+    }
+}
+
+impl StdError for BufferTooSmall {
+    fn description(&self) -> &str {
+        "buffer too small for request"
+    }
+}
+
+
+/// Builds a `MAIL FROM` request
+///
+/// Parameters are added in order. `build` adds `SMTPUTF8` if the addresses
+/// need it, like `Request::mail`.
+#[derive(Clone,Debug)]
+pub struct MailBuilder {
+    from: Mailbox,
+    recipients: Vec<Mailbox>,
+    params: Vec<MailParam>,
+}
+
+impl MailBuilder {
+    /// Start building a request with the given return path
+    pub fn new(from: Mailbox) -> Self {
+        MailBuilder { from, recipients: vec![], params: vec![] }
+    }
+
+    /// Add a parameter
+    pub fn param(mut self, param: MailParam) -> Self {
+        self.params.push(param);
+        self
+    }
+
+    /// Set the recipients, used to decide whether `SMTPUTF8` is needed
+    pub fn recipients(mut self, recipients: &[Mailbox]) -> Self {
+        self.recipients = recipients.to_vec();
+        self
+    }
+
+    /// Add `BODY=8BITMIME` if the message needs it and the server supports it
+    ///
+    /// Otherwise no `BODY` parameter is added, which means `7BIT`. A message
+    /// that needs 8-bit transport must then be converted to 7-bit first.
+    pub fn auto_body(self, caps: &Capabilities, eight_bit_needed: bool) -> Self {
+        if eight_bit_needed && caps.eight_bit_mime() {
+            self.param(MailParam::Body(MailBodyParam::EightBitMime))
+        } else {
+            self
+        }
+    }
+
+    /// Like `auto_body`, for the capabilities negotiated on a session
+    ///
+    /// `BODY=8BITMIME` is only added if `SmtpSession::eight_bit_mime` is set.
+    pub fn auto_body_for_session(self, session: &SmtpSession, eight_bit_needed: bool) -> Self {
+        match session.capabilities() {
+            Some(caps) => self.auto_body(caps, eight_bit_needed),
+            None => self,
+        }
+    }
+
+    /// Add an `ENVID` parameter, to identify the transaction in DSNs
+    pub fn envid<S: Into<String>>(self, envid: S) -> Self {
+        self.param(MailParam::Envid(envid.into()))
+    }
+
+    /// Add an `ENVID` parameter with a new random identifier
+    ///
+    /// The identifier is a UUID in hexadecimal, which needs no xtext encoding.
+    #[cfg(feature = "random-envid")]
+    pub fn envid_random(self) -> Self {
+        self.envid(Uuid::new_v4().to_simple().to_string())
+    }
+
+    /// Finish the request
+    pub fn build(self) -> Request {
+        Request::mail(self.from, &self.recipients, self.params)
+    }
+
+    /// Finish the request, checking it for problems
+    ///
+    /// All problems are collected and returned together, rather than only
+    /// the first one found.
+    pub fn build_validated(self) -> Result<Request, Vec<BuildError>> {
+        let request = self.build();
+        let mut errors = vec![];
+        let mut seen: Vec<String> = vec![];
+        for param in request.params() {
+            let keyword = param.keyword.to_ascii_uppercase();
+            if !is_esmtp_keyword(&keyword) {
+                errors.push(BuildError::InvalidKeyword(param.keyword.to_string()));
+            } else if seen.contains(&keyword) {
+                if !errors.contains(&BuildError::DuplicateParam(keyword.clone())) {
+                    errors.push(BuildError::DuplicateParam(keyword));
+                }
+            } else {
+                seen.push(keyword);
+            }
+        }
+        let len = request.to_bytes().len();
+        if len > MAX_COMMAND_LINE_LEN {
+            errors.push(BuildError::LineTooLong(len));
+        }
+        if let Err(err) = request.validate() {
+            errors.push(BuildError::Invalid(err));
+        }
+        if errors.is_empty() {
+            Ok(request)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+
 /// Represents a complete request
-#[derive(PartialEq,Clone,Debug)]
+///
+/// The `Debug` output masks `AUTH` data, which may contain credentials.
+#[derive(PartialEq,Clone)]
 pub enum Request {
     Ehlo(ClientId),
+    /// The LMTP (RFC 2033) replacement for `EHLO`
+    Lhlo(ClientId),
     StartTls,
     Auth { method: Option<String>, data: Option<String> },
+    /// A `MAIL FROM` request
+    ///
+    /// Parameters are serialized in the order of `params`, and parsing keeps
+    /// the order they were sent in. The order is never changed, so callers
+    /// control it.
     Mail { from: Mailbox, params: Vec<MailParam> },
+    /// A `RCPT TO` request, with parameters in order like `Mail`
     Rcpt { to: Mailbox, params: Vec<RcptParam> },
     Data,
+    /// A `BDAT` chunk (RFC 3030), followed by `size` octets of the message
+    Bdat { size: usize, last: bool },
+    Rset,
+    Vrfy(String),
+    Expn(String),
+    /// Reverse the connection for on-demand relay (RFC 2645), for the given
+    /// domains or for all of the client's domains
+    ///
+    /// An empty list of domains is sent like `None`.
+    Atrn(Option<Vec<String>>),
     Quit,
 }
 
-impl Display for Request {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match *self {
-            Request::Ehlo(ref id) => writeln!(f, "EHLO {}\r", id),
-            Request::StartTls => f.write_str("STARTTLS\r\n"),
-            Request::Auth { ref method, ref data } => {
-                match (method, data) {
-                    (&Some(ref method), &Some(ref data)) =>
-                        writeln!(f, "AUTH {} {}\r", method, data),
-                    (&Some(ref method), &None) =>
-                        writeln!(f, "AUTH {}\r", method),
-                    (&None, &Some(ref data)) =>
-                        writeln!(f, "{}\r", data),
-                    _ => unreachable!(),
-                }
-            },
-            Request::Mail { ref from, ref params } => {
-                write!(f, "MAIL FROM:{}", from)?;
-                for param in params {
-                    write!(f, " {}", param)?;
-                }
-                f.write_str("\r\n")
-            },
-            Request::Rcpt { ref to, ref params } => {
-                write!(f, "RCPT TO:{}", to)?;
-                for param in params {
-                    write!(f, " {}", param)?;
-                }
-                f.write_str("\r\n")
+impl Request {
+    /// Create a `MAIL FROM` request for a transaction
+    ///
+    /// If the return path or any of the recipients contain non-ASCII
+    /// characters, the `SMTPUTF8` parameter is added if not already present.
+    pub fn mail(from: Mailbox, recipients: &[Mailbox], mut params: Vec<MailParam>) -> Request {
+        let needs_smtputf8 = from.needs_smtputf8() ||
+            recipients.iter().any(|rcpt| rcpt.needs_smtputf8());
+        if needs_smtputf8 && !params.contains(&MailParam::SmtpUtf8) {
+            params.push(MailParam::SmtpUtf8);
+        }
+        Request::Mail { from, params }
+    }
+
+    /// Create the `MAIL FROM` and `RCPT TO` requests to send a bounce
+    ///
+    /// A bounce (RFC 3464) is sent from the null return path to the return
+    /// path of the original message, and requests no delivery status
+    /// notifications of its own, using `NOTIFY=NEVER`. That parameter requires
+    /// the server to advertise `DSN`.
+    ///
+    /// Mail from the null return path is never bounced, so this returns no
+    /// requests if the original sender is null.
+    pub fn bounce_envelope(original_sender: Mailbox) -> Vec<Request> {
+        if original_sender.is_null() {
+            return vec![];
+        }
+        vec![
+            Request::mail(Mailbox::null(), &[original_sender.clone()], vec![]),
+            Request::Rcpt {
+                to: original_sender,
+                params: vec![RcptParam::Notify(NotifyParam::Never)],
             },
-            Request::Data => {
-                f.write_str("DATA\r\n")
+        ]
+    }
+
+    /// Create the `MAIL FROM` and `RCPT TO` requests for a `lettre` envelope
+    ///
+    /// Fails if any of the addresses in the envelope can't be parsed as a
+    /// `Mailbox`.
+    #[cfg(feature = "lettre-interop")]
+    pub fn from_envelope(envelope: &Envelope) -> Result<Vec<Request>, AddrError> {
+        let from = match envelope.from() {
+            Some(addr) => addr.to_string().parse()?,
+            None => Mailbox(None),
+        };
+        let recipients = envelope.to().iter()
+            .map(|addr| addr.to_string().parse())
+            .collect::<Result<Vec<Mailbox>, _>>()?;
+
+        let mut reqs = Vec::with_capacity(recipients.len() + 1);
+        reqs.push(Request::mail(from, &recipients, vec![]));
+        reqs.extend(recipients.into_iter().map(|to| Request::Rcpt { to, params: vec![] }));
+        Ok(reqs)
+    }
+
+    /// Parse a single command line, checking it against session state
+    ///
+    /// Unlike `from_str`, this rejects non-ASCII addresses unless `SMTPUTF8`
+    /// is in use: for `MAIL FROM`, the request itself must have the
+    /// parameter, and for `RCPT TO`, the transaction must have been started
+    /// with it.
+    pub fn parse_with(s: &str, opts: &ParseOptions) -> Result<Request, ParseError> {
+        let request = s.parse()?;
+        match request {
+            Request::Mail { ref from, ref params }
+                    if from.needs_smtputf8() && !params.contains(&MailParam::SmtpUtf8) => {
+                return Err(ParseError::new("MAIL FROM:<".len(), ParseErrorKind::NonAsciiAddress));
             },
-            Request::Quit => {
-                f.write_str("QUIT\r\n")
+            Request::Rcpt { ref to, .. } if to.needs_smtputf8() && !opts.smtputf8 => {
+                return Err(ParseError::new("RCPT TO:<".len(), ParseErrorKind::NonAsciiAddress));
             },
+            _ => {},
         }
+        Ok(request)
     }
-}
 
-impl From<Request> for Frame<Request, Vec<u8>, IoError> {
-    fn from(request: Request) -> Self {
-        let has_body = request == Request::Data;
-        Frame::Message {
-            message: request,
-            body: has_body,
+    /// Parse the command line at the start of a buffer
+    ///
+    /// The line must end with CRLF. Returns the request, and the number of
+    /// bytes it took up, so the rest of the buffer can be parsed next.
+    pub fn parse_prefix(buf: &[u8]) -> Result<(Request, usize), ParseError> {
+        let len = match buf.windows(2).position(|window| window == b"\r\n") {
+            Some(pos) => pos + 2,
+            None => return Err(ParseError::new(buf.len(), ParseErrorKind::Incomplete)),
+        };
+        let line = from_utf8(&buf[..len])
+            .map_err(|err| ParseError::new(err.valid_up_to(), ParseErrorKind::InvalidSyntax))?;
+        Ok((line.parse()?, len))
+    }
+
+    /// Parse a single command line, and check that it is acceptable
+    ///
+    /// This is `parse_with`, followed by checks a server should make before
+    /// acting on a request: the line, including CRLF, must fit in
+    /// `MAX_COMMAND_LINE_LEN`, the local part and domain of an address must
+    /// fit in 64 and 255 octets, and each parameter may be given only once.
+    pub fn parse_validated(buf: &[u8], opts: &ParseOptions) -> Result<Request, ParseError> {
+        if buf.len() > MAX_COMMAND_LINE_LEN {
+            return Err(ParseError::new(MAX_COMMAND_LINE_LEN, ParseErrorKind::LineTooLong));
         }
+        let s = from_utf8(buf)
+            .map_err(|err| ParseError::new(err.valid_up_to(), ParseErrorKind::InvalidSyntax))?;
+        let request = Request::parse_with(s, opts)?;
+        let ((mailbox, path_start), keyword) = match request {
+            Request::Mail { ref from, .. } => ((from, "MAIL FROM:<".len()), "FROM"),
+            Request::Rcpt { ref to, .. } => ((to, "RCPT TO:<".len()), "TO"),
+            _ => return Ok(request),
+        };
+        if let Some((local, domain)) = mailbox.parts() {
+            if local.len() > MAX_LOCAL_LEN || domain.len() > MAX_DOMAIN_LEN {
+                return Err(ParseError::new(path_start, ParseErrorKind::InvalidPath));
+            }
+        }
+
+        // Parameter offsets are not kept in the request, so find them again.
+        let line = if s.ends_with("\r\n") { &s[..s.len() - 2] } else { s };
+        let (_, params) = parse_path_args(line, "MAIL".len(), keyword)?;
+        let mut seen: Vec<String> = vec![];
+        for (offset, param) in params {
+            let keyword = param.split('=').next().unwrap_or("").to_ascii_uppercase();
+            if seen.contains(&keyword) {
+                return Err(ParseError::new(offset, ParseErrorKind::DuplicateParam));
+            }
+            seen.push(keyword);
+        }
+        Ok(request)
     }
-}
 
+    /// Parse a line sent in response to an `AUTH` challenge
+    ///
+    /// These lines have no verb, and are the base64 SASL response, which may
+    /// be empty, or `*` to cancel the exchange (RFC 4954). The result is an
+    /// `Auth` request without a method. A server parses lines this way after
+    /// sending a `334` reply.
+    pub fn parse_auth_continuation(s: &str) -> Result<Request, ParseError> {
+        if !s.ends_with("\r\n") {
+            return Err(ParseError::new(s.len(), ParseErrorKind::Incomplete));
+        }
+        let data = &s[..s.len() - 2];
+        if data != "*" {
+            let invalid = data.bytes().position(|byte| {
+                !(byte.is_ascii_alphanumeric() || byte == b'+' || byte == b'/' || byte == b'=')
+            });
+            if let Some(offset) = invalid {
+                return Err(ParseError::new(offset, ParseErrorKind::InvalidSyntax));
+            }
+        }
+        Ok(Request::Auth { method: None, data: Some(data.to_string()) })
+    }
 
-#[cfg(test)]
-mod tests {
-    use request::{ClientId, MailBodyParam, MailParam, RcptParam, Request};
+    /// Tells if this is the `*` line that cancels an `AUTH` exchange
+    pub fn is_auth_cancel(&self) -> bool {
+        match *self {
+            Request::Auth { method: None, data: Some(ref data) } => data == "*",
+            _ => false,
+        }
+    }
 
-    #[test]
-    fn test() {
-        for (input, expect) in vec![
-            (
+    /// The command verb, in uppercase
+    ///
+    /// `AUTH` continuation lines have no verb on the wire, but are part of
+    /// `AUTH`, so this returns `AUTH` for them too.
+    pub fn verb(&self) -> &'static str {
+        match *self {
+            Request::Ehlo(_) => "EHLO",
+            Request::Lhlo(_) => "LHLO",
+            Request::StartTls => "STARTTLS",
+            Request::Auth { .. } => "AUTH",
+            Request::Mail { .. } => "MAIL",
+            Request::Rcpt { .. } => "RCPT",
+            Request::Data => "DATA",
+            Request::Bdat { .. } => "BDAT",
+            Request::Rset => "RSET",
+            Request::Vrfy(_) => "VRFY",
+            Request::Expn(_) => "EXPN",
+            Request::Atrn(_) => "ATRN",
+            Request::Quit => "QUIT",
+        }
+    }
+
+    /// Tells if the request is followed by a message body
+    ///
+    /// This is the case for `DATA` and `BDAT`. The body of `DATA` is only
+    /// sent once the server replies with `354`.
+    pub fn expects_body(&self) -> bool {
+        match *self {
+            Request::Data | Request::Bdat { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Copy a `MAIL FROM` request with a different sender, e.g. when relaying
+    ///
+    /// All parameters, such as `SIZE`, `BODY` and the DSN parameters, are
+    /// kept as they are, in order. Other requests are returned unchanged.
+    pub fn rewrite_sender(&self, new_from: Mailbox) -> Request {
+        match *self {
+            Request::Mail { ref params, .. } => Request::Mail { from: new_from, params: params.clone() },
+            _ => self.clone(),
+        }
+    }
+
+    /// Tells if the request must be the last in a pipelined group
+    ///
+    /// The reply to `EHLO`, `LHLO`, `STARTTLS` and `DATA` changes how the
+    /// following input is read, so the client must wait for it before sending
+    /// more (RFC 2920, section 3.1).
+    pub fn ends_pipeline_group(&self) -> bool {
+        match *self {
+            Request::Ehlo(_) | Request::Lhlo(_) | Request::StartTls | Request::Data => true,
+            _ => false,
+        }
+    }
+
+    /// Serialize the request as it is sent on the wire
+    ///
+    /// Addresses are written as raw UTF-8, which is 8-bit data under
+    /// `SMTPUTF8`. Parameter values are xtext encoded, so the value of an
+    /// unknown parameter may hold any bytes, even if they are not UTF-8.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write!(bytes, "{}", self).expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    /// The length of the request on the wire, including CRLF
+    ///
+    /// This is the length of `to_bytes`, but is computed without allocating.
+    pub fn serialized_len(&self) -> usize {
+        struct Counter(usize);
+        impl FmtWrite for Counter {
+            fn write_str(&mut self, s: &str) -> FmtResult {
+                self.0 += s.len();
+                Ok(())
+            }
+        }
+        let mut counter = Counter(0);
+        write!(counter, "{}", self).expect("counting cannot fail");
+        counter.0
+    }
+
+    /// Serialize the request into a buffer, without allocating
+    ///
+    /// Returns the number of bytes written, which is `serialized_len`. If the
+    /// buffer is too small, the error says how large it must be, and the
+    /// contents of the buffer are unspecified.
+    pub fn write_into(&self, buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        // Writing continues past the end of the buffer, only counting.
+        struct SliceWriter<'a> {
+            buf: &'a mut [u8],
+            len: usize,
+        }
+        impl<'a> FmtWrite for SliceWriter<'a> {
+            fn write_str(&mut self, s: &str) -> FmtResult {
+                let end = self.len + s.len();
+                if end <= self.buf.len() {
+                    self.buf[self.len..end].copy_from_slice(s.as_bytes());
+                }
+                self.len = end;
+                Ok(())
+            }
+        }
+        let mut writer = SliceWriter { buf, len: 0 };
+        write!(writer, "{}", self).expect("writing to a slice cannot fail");
+        if writer.len > writer.buf.len() {
+            return Err(BufferTooSmall { needed: writer.len });
+        }
+        Ok(writer.len)
+    }
+
+    /// Check that the request is sent as a single command line
+    ///
+    /// Values that come from untrusted input, such as addresses, keywords and
+    /// the `EHLO` domain, are written as is. A CR or LF in any of them would
+    /// end the command early, and inject another. Parameter values are xtext
+    /// encoded, and cannot contain them on the wire.
+    pub fn validate(&self) -> Result<(), RequestError> {
+        let bytes = self.to_bytes();
+        let line = &bytes[..bytes.len() - 2];
+        if line.iter().any(|&byte| byte == b'\r' || byte == b'\n') {
+            return Err(RequestError::ControlCharacter);
+        }
+        Ok(())
+    }
+
+    /// Iterate the parameters of a `MAIL FROM` or `RCPT TO` request
+    ///
+    /// Other requests have no parameters.
+    pub fn params<'a>(&'a self) -> Box<Iterator<Item = ParamRef<'a>> + 'a> {
+        match *self {
+            Request::Mail { ref params, .. } => Box::new(params.iter().map(MailParam::as_param_ref)),
+            Request::Rcpt { ref params, .. } => Box::new(params.iter().map(RcptParam::as_param_ref)),
+            _ => Box::new(None.into_iter()),
+        }
+    }
+
+    /// The priority of a `MAIL FROM` request, from its `MT-PRIORITY` parameter
+    ///
+    /// Priorities range from -9 to 9 (RFC 6710), and higher values are sent
+    /// first. Requests without the parameter, or with an invalid value, have
+    /// priority 0, as do requests other than `MAIL FROM`. This can be used to
+    /// order queued transactions, e.g. in a `BinaryHeap`.
+    pub fn priority(&self) -> i8 {
+        match *self {
+            Request::Mail { ref params, .. } => {
+                params.iter().filter_map(|param| match *param {
+                    MailParam::Other { ref keyword, value: Some(ref value) }
+                        if keyword.eq_ignore_ascii_case("MT-PRIORITY") => {
+                        from_utf8(value).ok()
+                            .and_then(|value| value.parse::<i8>().ok())
+                            .filter(|prio| -9 <= *prio && *prio <= 9)
+                    },
+                    _ => None,
+                }).next().unwrap_or(0)
+            },
+            _ => 0,
+        }
+    }
+
+    /// Remove the parameters of the extensions a server did not advertise
+    ///
+    /// Sending a parameter that the server does not support gets the request
+    /// rejected. Dropping it may still change how the message is handled: for
+    /// example, non-ASCII addresses cannot be used without `SMTPUTF8`.
+    ///
+    /// Requests other than `MAIL FROM` and `RCPT TO` are returned as is.
+    pub fn downgrade_to(&self, caps: &Capabilities) -> Request {
+        self.retain_params(
+            |param| match *param {
+                MailParam::Body(MailBodyParam::BinaryMime) => caps.has("BINARYMIME"),
+                MailParam::Body(_) => caps.has("8BITMIME"),
+                _ => caps.has(param_extension(param.as_param_ref().keyword)),
+            },
+            |param| caps.has(param_extension(param.as_param_ref().keyword)))
+    }
+
+    /// Remove the DSN parameters (RFC 3461), for a server without `DSN`
+    ///
+    /// These are `RET` and `ENVID` of `MAIL FROM`, and `NOTIFY` and `ORCPT` of
+    /// `RCPT TO`. Other parameters are kept, unlike with `downgrade_to`.
+    pub fn without_dsn(&self) -> Request {
+        self.retain_params(
+            |param| param_extension(param.as_param_ref().keyword) != "DSN",
+            |param| param_extension(param.as_param_ref().keyword) != "DSN")
+    }
+
+    /// Keep only the `MAIL FROM` or `RCPT TO` parameters matching a predicate
+    fn retain_params<M, R>(&self, keep_mail: M, keep_rcpt: R) -> Request
+            where M: Fn(&MailParam) -> bool, R: Fn(&RcptParam) -> bool {
+        match *self {
+            Request::Mail { ref from, ref params } => Request::Mail {
+                from: from.clone(),
+                params: params.iter().filter(|param| keep_mail(param)).cloned().collect(),
+            },
+            Request::Rcpt { ref to, ref params } => Request::Rcpt {
+                to: to.clone(),
+                params: params.iter().filter(|param| keep_rcpt(param)).cloned().collect(),
+            },
+            ref request => request.clone(),
+        }
+    }
+
+    /// The domain of a `RCPT TO` request, e.g. to route the recipient
+    ///
+    /// Returns `None` for the domainless `<Postmaster>`, which is delivered
+    /// locally, and for requests other than `RCPT TO`. The domain is returned
+    /// as sent, so an address literal keeps its brackets.
+    pub fn rcpt_domain(&self) -> Option<&str> {
+        match *self {
+            Request::Rcpt { ref to, .. } => to.parts()
+                .map(|(_, domain)| domain)
+                .filter(|domain| !domain.is_empty()),
+            _ => None,
+        }
+    }
+
+    /// The original recipient of a `RCPT TO` request, if specified
+    ///
+    /// Returns the address type and the decoded address from the `ORCPT`
+    /// parameter.
+    pub fn orcpt(&self) -> Option<(&str, &str)> {
+        match *self {
+            Request::Rcpt { ref params, .. } => {
+                params.iter().filter_map(|param| match *param {
+                    RcptParam::Orcpt { ref addr_type, ref addr } => {
+                        Some((addr_type.as_str(), addr.as_str()))
+                    },
+                    _ => None,
+                }).next()
+            },
+            _ => None,
+        }
+    }
+
+    /// Compare requests, ignoring the order of `MAIL` and `RCPT` parameters
+    ///
+    /// The derived `PartialEq` is sensitive to parameter order.
+    pub fn eq_ignoring_param_order(&self, other: &Request) -> bool {
+        match (self, other) {
+            (&Request::Mail { from: ref a, params: ref a_params },
+             &Request::Mail { from: ref b, params: ref b_params }) => {
+                a == b && same_items(a_params, b_params)
+            },
+            (&Request::Rcpt { to: ref a, params: ref a_params },
+             &Request::Rcpt { to: ref b, params: ref b_params }) => {
+                a == b && same_items(a_params, b_params)
+            },
+            _ => self == other,
+        }
+    }
+}
+
+/// The return path of a transaction, given its requests in order
+///
+/// This is the path of the last `MAIL FROM`, unless the transaction was
+/// aborted after it by `RSET`, `EHLO` or `LHLO`.
+pub fn transaction_sender(requests: &[Request]) -> Option<Mailbox> {
+    current_transaction(requests).first().and_then(|request| match *request {
+        Request::Mail { ref from, .. } => Some(from.clone()),
+        _ => None,
+    })
+}
+
+/// The recipients of a transaction, given its requests in order
+///
+/// Like `transaction_sender`, only the `RCPT TO` requests of the last
+/// transaction are included, in order. Rejected recipients are not known
+/// from requests alone, so they are included too.
+pub fn transaction_recipients(requests: &[Request]) -> Vec<Mailbox> {
+    current_transaction(requests).iter().filter_map(|request| match *request {
+        Request::Rcpt { ref to, .. } => Some(to.clone()),
+        _ => None,
+    }).collect()
+}
+
+/// The requests since the start of the last transaction
+///
+/// The slice starts at `MAIL FROM`, or is empty if there is no transaction.
+fn current_transaction(requests: &[Request]) -> &[Request] {
+    let start = requests.iter().rposition(|request| match *request {
+        Request::Mail { .. } | Request::Rset | Request::Ehlo(_) | Request::Lhlo(_) => true,
+        _ => false,
+    });
+    match start {
+        Some(idx) if requests[idx].verb() == "MAIL" => &requests[idx..],
+        _ => &[],
+    }
+}
+
+/// Parses the command lines in a buffer, such as a captured transcript
+///
+/// Each item is the result of parsing one line with `Request::parse_prefix`,
+/// so a malformed line is an error, and parsing continues with the next one.
+/// Error offsets are from the start of the buffer. If the buffer ends with an
+/// incomplete line, the last item is an `Incomplete` error.
+///
+/// Message bodies and `AUTH` continuation lines are not told apart from
+/// commands, so the transcript should only contain command lines.
+#[derive(Clone,Debug)]
+pub struct RequestParser<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> RequestParser<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        RequestParser { buf, offset: 0 }
+    }
+
+    /// The offset of the next line to parse
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for RequestParser<'a> {
+    type Item = Result<Request, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.buf[self.offset..];
+        if rest.is_empty() {
+            return None;
+        }
+        let start = self.offset;
+        self.offset += match rest.windows(2).position(|window| window == b"\r\n") {
+            Some(pos) => pos + 2,
+            None => rest.len(),
+        };
+        Some(Request::parse_prefix(rest)
+            .map(|(request, _)| request)
+            .map_err(|err| ParseError::new(start + err.offset, err.kind)))
+    }
+}
+
+/// Tells if both slices contain the same items, in any order
+fn same_items<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+    let count = |items: &[T], item: &T| items.iter().filter(|other| *other == item).count();
+    a.len() == b.len() && a.iter().all(|item| count(a, item) == count(b, item))
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Request::Ehlo(ref id) => write!(f, "EHLO {}\r\n", id),
+            Request::Lhlo(ref id) => write!(f, "LHLO {}\r\n", id),
+            Request::StartTls => f.write_str("STARTTLS\r\n"),
+            Request::Auth { ref method, ref data } => {
+                match (method, data) {
+                    (&Some(ref method), &Some(ref data)) =>
+                        write!(f, "AUTH {} {}\r\n", method, data),
+                    (&Some(ref method), &None) =>
+                        write!(f, "AUTH {}\r\n", method),
+                    (&None, &Some(ref data)) =>
+                        write!(f, "{}\r\n", data),
+                    _ => unreachable!(),
+                }
+            },
+            Request::Mail { ref from, ref params } => {
+                write!(f, "MAIL FROM:{}", from)?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                f.write_str("\r\n")
+            },
+            Request::Rcpt { ref to, ref params } => {
+                write!(f, "RCPT TO:{}", to)?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                f.write_str("\r\n")
+            },
+            Request::Data => {
+                f.write_str("DATA\r\n")
+            },
+            Request::Bdat { size, last } => {
+                write!(f, "BDAT {}", size)?;
+                if last {
+                    f.write_str(" LAST")?;
+                }
+                f.write_str("\r\n")
+            },
+            Request::Rset => {
+                f.write_str("RSET\r\n")
+            },
+            Request::Vrfy(ref arg) => {
+                write!(f, "VRFY {}\r\n", arg)
+            },
+            Request::Expn(ref arg) => {
+                write!(f, "EXPN {}\r\n", arg)
+            },
+            Request::Atrn(Some(ref domains)) if !domains.is_empty() => {
+                f.write_str("ATRN")?;
+                for (idx, domain) in domains.iter().enumerate() {
+                    f.write_str(if idx == 0 { " " } else { "," })?;
+                    f.write_str(domain)?;
+                }
+                f.write_str("\r\n")
+            },
+            Request::Atrn(_) => {
+                f.write_str("ATRN\r\n")
+            },
+            Request::Quit => {
+                f.write_str("QUIT\r\n")
+            },
+        }
+    }
+}
+
+impl Debug for Request {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Request::Ehlo(ref id) => f.debug_tuple("Ehlo").field(id).finish(),
+            Request::Lhlo(ref id) => f.debug_tuple("Lhlo").field(id).finish(),
+            Request::StartTls => f.write_str("StartTls"),
+            Request::Auth { ref method, ref data } => {
+                f.debug_struct("Auth")
+                    .field("method", method)
+                    .field("data", &data.as_ref().map(|_| "<redacted>"))
+                    .finish()
+            },
+            Request::Mail { ref from, ref params } => {
+                f.debug_struct("Mail").field("from", from).field("params", params).finish()
+            },
+            Request::Rcpt { ref to, ref params } => {
+                f.debug_struct("Rcpt").field("to", to).field("params", params).finish()
+            },
+            Request::Data => f.write_str("Data"),
+            Request::Bdat { size, last } => {
+                f.debug_struct("Bdat").field("size", &size).field("last", &last).finish()
+            },
+            Request::Rset => f.write_str("Rset"),
+            Request::Vrfy(ref arg) => f.debug_tuple("Vrfy").field(arg).finish(),
+            Request::Expn(ref arg) => f.debug_tuple("Expn").field(arg).finish(),
+            Request::Atrn(ref domains) => f.debug_tuple("Atrn").field(domains).finish(),
+            Request::Quit => f.write_str("Quit"),
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = ParseError;
+
+    /// Parse a single command line, with or without the final CRLF
+    ///
+    /// The continuation lines of `AUTH` cannot be told apart from commands,
+    /// and are not parsed.
+    fn from_str(s: &str) -> Result<Request, ParseError> {
+        let line = if s.ends_with("\r\n") { &s[..s.len() - 2] } else { s };
+        let verb_len = line.find(' ').unwrap_or_else(|| line.len());
+        let args = &line[verb_len..];
+
+        match line[..verb_len].to_ascii_uppercase().as_str() {
+            verb @ "EHLO" | verb @ "LHLO" => {
+                let arg = args.trim();
+                if arg.is_empty() {
+                    return Err(ParseError::new(verb_len, ParseErrorKind::MissingArgument));
+                }
+                let id = parse_client_id(arg);
+                Ok(if verb == "EHLO" { Request::Ehlo(id) } else { Request::Lhlo(id) })
+            },
+            "AUTH" => {
+                let mut words = args.get(1..).unwrap_or("").splitn(2, ' ');
+                match (words.next(), words.next()) {
+                    (Some(method), data) if !method.is_empty() => Ok(Request::Auth {
+                        method: Some(method.to_string()),
+                        data: data.map(|data| data.to_string()),
+                    }),
+                    _ => Err(ParseError::new(verb_len, ParseErrorKind::InvalidSyntax)),
+                }
+            },
+            "MAIL" => {
+                let (from, params) = parse_path_args(line, verb_len, "FROM")?;
+                let params = params.into_iter()
+                    .map(|(offset, param)| param.parse()
+                        .map_err(|_| ParseError::new(offset, ParseErrorKind::InvalidParam)))
+                    .collect::<Result<_, _>>()?;
+                Ok(Request::Mail { from, params })
+            },
+            "RCPT" => {
+                let (to, params) = parse_path_args(line, verb_len, "TO")?;
+                let params = params.into_iter()
+                    .map(|(offset, param)| param.parse()
+                        .map_err(|_| ParseError::new(offset, ParseErrorKind::InvalidParam)))
+                    .collect::<Result<_, _>>()?;
+                Ok(Request::Rcpt { to, params })
+            },
+            "BDAT" => {
+                let mut words = args.get(1..).unwrap_or("").split(' ');
+                let size = words.next()
+                    .and_then(|size| if size.bytes().all(|c| c.is_ascii_digit()) { size.parse().ok() } else { None })
+                    .ok_or_else(|| ParseError::new(verb_len, ParseErrorKind::InvalidSyntax))?;
+                let last = match words.next() {
+                    None => false,
+                    Some(word) if word.eq_ignore_ascii_case("LAST") => true,
+                    Some(_) => return Err(ParseError::new(verb_len, ParseErrorKind::InvalidSyntax)),
+                };
+                if words.next().is_some() {
+                    return Err(ParseError::new(verb_len, ParseErrorKind::InvalidSyntax));
+                }
+                Ok(Request::Bdat { size, last })
+            },
+            verb @ "VRFY" | verb @ "EXPN" => {
+                let arg = args.trim();
+                if arg.is_empty() {
+                    return Err(ParseError::new(verb_len, ParseErrorKind::MissingArgument));
+                }
+                Ok(if verb == "VRFY" {
+                    Request::Vrfy(arg.to_string())
+                } else {
+                    Request::Expn(arg.to_string())
+                })
+            },
+            "ATRN" => {
+                let arg = args.trim();
+                if arg.is_empty() {
+                    return Ok(Request::Atrn(None));
+                }
+                let domains: Vec<String> = arg.split(',')
+                    .map(|domain| domain.trim().to_string())
+                    .collect();
+                if domains.iter().any(|domain| domain.is_empty() || domain.contains(' ')) {
+                    return Err(ParseError::new(verb_len, ParseErrorKind::InvalidSyntax));
+                }
+                Ok(Request::Atrn(Some(domains)))
+            },
+            verb @ "STARTTLS" | verb @ "DATA" | verb @ "RSET" | verb @ "QUIT" => {
+                if !args.is_empty() {
+                    return Err(ParseError::new(verb_len, ParseErrorKind::InvalidSyntax));
+                }
+                Ok(match verb {
+                    "STARTTLS" => Request::StartTls,
+                    "DATA" => Request::Data,
+                    "RSET" => Request::Rset,
+                    _ => Request::Quit,
+                })
+            },
+            _ => Err(ParseError::new(0, ParseErrorKind::UnknownCommand)),
+        }
+    }
+}
+
+/// Parse an IPv4 or IPv6 address literal in brackets
+fn parse_address_literal(s: &str) -> Option<ClientId> {
+    if s.len() > 2 && s.starts_with('[') && s.ends_with(']') {
+        match parse_client_id(&s[1..s.len() - 1]) {
+            id @ ClientId::Ipv4(_) | id @ ClientId::Ipv6(_) => return Some(id),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Parse the argument to `EHLO`, the inverse of `ClientId`'s `Display`
+fn parse_client_id(s: &str) -> ClientId {
+    if let Ok(addr) = s.parse() {
+        return ClientId::Ipv4(addr);
+    }
+    if s.len() > 5 && s[..5].eq_ignore_ascii_case("IPv6:") {
+        if let Ok(addr) = s[5..].parse() {
+            return ClientId::Ipv6(addr);
+        }
+    }
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(tag), Some(value)) if !tag.is_empty() => ClientId::Other {
+            tag: tag.to_string(),
+            value: value.to_string(),
+        },
+        _ => ClientId::Domain(s.to_string()),
+    }
+}
+
+/// Find the `>` closing a path, skipping over a quoted local part
+fn find_path_end(s: &str) -> Option<usize> {
+    let (mut quoted, mut escaped) = (false, false);
+    for (idx, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some(idx),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Parse ` KEYWORD:<path> [params]` following the verb of `MAIL` or `RCPT`
+///
+/// The path is found first, so a quoted local part may contain `>` or spaces.
+/// The rest of the line must be empty or start with a space, and is split
+/// into parameters. Returns the mailbox, and the parameters with their
+/// offsets in the line.
+fn parse_path_args<'a>(line: &'a str, start: usize, keyword: &str)
+        -> Result<(Mailbox, Vec<(usize, &'a str)>), ParseError> {
+    let mut offset = start + 1;
+    let has_keyword = line[start..].starts_with(' ') &&
+        line.get(offset..offset + keyword.len())
+            .map_or(false, |word| word.eq_ignore_ascii_case(keyword));
+    if !has_keyword {
+        return Err(ParseError::new(start, ParseErrorKind::InvalidSyntax));
+    }
+    offset += keyword.len();
+
+    if !line[offset..].starts_with(':') {
+        return Err(ParseError::new(offset, ParseErrorKind::ExpectedColon));
+    }
+    offset += 1;
+
+    let path_len = match find_path_end(&line[offset..]) {
+        Some(idx) if line[offset..].starts_with('<') => idx + 1,
+        _ => return Err(ParseError::new(offset, ParseErrorKind::InvalidPath)),
+    };
+    let mailbox = line[offset + 1..offset + path_len - 1].parse()
+        .map_err(|_| ParseError::new(offset + 1, ParseErrorKind::InvalidPath))?;
+    offset += path_len;
+
+    let mut params = vec![];
+    let rest = &line[offset..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return Err(ParseError::new(offset, ParseErrorKind::InvalidSyntax));
+    }
+    for param in rest.split(' ') {
+        if !param.is_empty() {
+            params.push((offset, param));
+        }
+        offset += param.len() + 1;
+    }
+    Ok((mailbox, params))
+}
+
+/// Tells if a parameter keyword is `(ALPHA / DIGIT) *(ALPHA / DIGIT / "-")`
+fn is_esmtp_keyword(keyword: &str) -> bool {
+    keyword.bytes().next().map_or(false, |byte| byte.is_ascii_alphanumeric()) &&
+        keyword.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+}
+
+/// The extension that defines a `MAIL FROM` or `RCPT TO` parameter
+///
+/// Parameters are usually named after their extension, except for these.
+fn param_extension(keyword: &str) -> &str {
+    match keyword.to_ascii_uppercase().as_str() {
+        "BODY" => "8BITMIME",
+        "RET" | "ENVID" | "NOTIFY" | "ORCPT" => "DSN",
+        _ => keyword,
+    }
+}
+
+/// Convert a request to a message frame, with a body if it is `DATA` or `BDAT`
+///
+/// The body error type is up to the transport; the client uses `IoError`.
+impl<E> From<Request> for Frame<Request, Vec<u8>, E> {
+    fn from(request: Request) -> Self {
+        let has_body = request.expects_body();
+        Frame::Message {
+            message: request,
+            body: has_body,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use capabilities::{Capabilities};
+    use emailaddress::{EmailAddress};
+    use error::{ParseError, ParseErrorKind};
+    use request::{BufferTooSmall, BuildError, ClientId, ClientIdError, MailBuilder, Mailbox,
+                  MailBodyParam, MailParam, NotifyParam, ParamRef, ParseOptions, RcptParam,
+                  Request, RequestError, RequestParser, transaction_recipients,
+                  transaction_sender};
+    use response::{Response};
+    use session::{SmtpSession};
+    use std::cmp::{Reverse};
+    use std::collections::{BTreeSet, BinaryHeap, HashMap};
+    use std::io::{Error as IoError};
+    use std::str::{from_utf8};
+    use tokio_proto::streaming::pipeline::{Frame};
+
+    #[test]
+    fn test() {
+        for (input, expect) in vec![
+            (
                 Request::Ehlo(
                     ClientId::Domain("foobar.example".to_string())
                 ),
@@ -216,6 +1676,12 @@ mod tests {
                 ),
                 "EHLO 127.0.0.1\r\n",
             ),
+            (
+                Request::Lhlo(
+                    ClientId::Domain("foobar.example".to_string())
+                ),
+                "LHLO foobar.example\r\n",
+            ),
             (
                 Request::StartTls,
                 "STARTTLS\r\n",
@@ -239,12 +1705,21 @@ mod tests {
                         },
                         MailParam::Other {
                             keyword: "X-VALUE".to_string(),
-                            value: Some("+".to_string()),
+                            value: Some(b"+".to_vec()),
                         },
                     ],
                 },
                 "MAIL FROM:<> BODY=8BITMIME SIZE=1024 X-FLAG X-VALUE=+2B\r\n",
             ),
+            (
+                Request::Mail {
+                    from: "".parse().unwrap(),
+                    params: vec![
+                        MailParam::Body(MailBodyParam::BinaryMime),
+                    ],
+                },
+                "MAIL FROM:<> BODY=BINARYMIME\r\n",
+            ),
             (
                 Request::Mail {
                     from: "john@example.test".parse().unwrap(),
@@ -269,7 +1744,7 @@ mod tests {
                         },
                         RcptParam::Other {
                             keyword: "X-VALUE".to_string(),
-                            value: Some("+".to_string()),
+                            value: Some(b"+".to_vec()),
                         },
                     ],
                 },
@@ -286,6 +1761,34 @@ mod tests {
                 Request::Data,
                 "DATA\r\n",
             ),
+            (
+                Request::Bdat { size: 1024, last: false },
+                "BDAT 1024\r\n",
+            ),
+            (
+                Request::Bdat { size: 0, last: true },
+                "BDAT 0 LAST\r\n",
+            ),
+            (
+                Request::Rset,
+                "RSET\r\n",
+            ),
+            (
+                Request::Vrfy("john".to_string()),
+                "VRFY john\r\n",
+            ),
+            (
+                Request::Expn("staff".to_string()),
+                "EXPN staff\r\n",
+            ),
+            (
+                Request::Atrn(None),
+                "ATRN\r\n",
+            ),
+            (
+                Request::Atrn(Some(vec!["example.test".to_string(), "example.org".to_string()])),
+                "ATRN example.test,example.org\r\n",
+            ),
             (
                 Request::Quit,
                 "QUIT\r\n",
@@ -293,5 +1796,1264 @@ mod tests {
         ] {
             assert_eq!(input.to_string(), expect);
         }
+
+        for (input, expect) in vec![
+            ("7BIT", Ok(MailBodyParam::SevenBit)),
+            ("8BITMIME", Ok(MailBodyParam::EightBitMime)),
+            ("BINARYMIME", Ok(MailBodyParam::BinaryMime)),
+            ("binarymime", Ok(MailBodyParam::BinaryMime)),
+            ("BINARY", Err(())),
+        ] {
+            assert_eq!(input.parse::<MailBodyParam>(), expect);
+        }
+
+        for (tag, expect) in vec![
+            ("x-custom", Ok(ClientId::Other {
+                tag: "x-custom".to_string(),
+                value: "abc".to_string(),
+            })),
+            ("", Err(ClientIdError::EmptyTag)),
+            ("x:custom", Err(ClientIdError::InvalidTag)),
+            ("x custom", Err(ClientIdError::InvalidTag)),
+            ("x\tcustom", Err(ClientIdError::InvalidTag)),
+        ] {
+            assert_eq!(ClientId::other(tag, "abc"), expect);
+        }
+        assert_eq!(ClientId::other("x-custom", "abc").unwrap().to_string(), "x-custom:abc");
+
+        for (input, expect) in vec![
+            ("NOTIFY=NEVER", Ok(RcptParam::Notify(NotifyParam::Never))),
+            ("NOTIFY=SUCCESS,FAILURE", Ok(RcptParam::Notify(NotifyParam::Conditions {
+                success: true,
+                failure: true,
+                delay: false,
+            }))),
+            ("notify=delay", Ok(RcptParam::Notify(NotifyParam::Conditions {
+                success: false,
+                failure: false,
+                delay: true,
+            }))),
+            ("NOTIFY=NEVER,SUCCESS", Err(())),
+            ("NOTIFY=SUCCESS,NEVER", Err(())),
+            ("NOTIFY=SUCCESS,SUCCESS", Err(())),
+            ("NOTIFY=", Err(())),
+            ("NOTIFY", Err(())),
+            ("X-FLAG", Ok(RcptParam::Other {
+                keyword: "X-FLAG".to_string(),
+                value: None,
+            })),
+            ("X-VALUE=a+2Bb", Ok(RcptParam::Other {
+                keyword: "X-VALUE".to_string(),
+                value: Some(b"a+b".to_vec()),
+            })),
+            ("=value", Err(())),
+            ("ORCPT=rfc822;user+2Bext@example.test", Ok(RcptParam::Orcpt {
+                addr_type: "rfc822".to_string(),
+                addr: "user+ext@example.test".to_string(),
+            })),
+            ("ORCPT=rfc822", Err(())),
+            ("ORCPT=;user@example.test", Err(())),
+        ] {
+            let result = input.parse::<RcptParam>();
+            if let Ok(ref param) = result {
+                assert!(param.to_string().eq_ignore_ascii_case(input));
+            }
+            assert_eq!(result, expect);
+        }
+
+        let mail = Request::Mail {
+            from: "".parse().unwrap(),
+            params: vec![
+                MailParam::Body(MailBodyParam::EightBitMime),
+                MailParam::Size(1024),
+                MailParam::SmtpUtf8,
+                MailParam::Other { keyword: "X-VALUE".to_string(), value: Some(b"+".to_vec()) },
+            ],
+        };
+        assert_eq!(mail.params().collect::<Vec<_>>(), vec![
+            ParamRef { keyword: "BODY", value: Some("8BITMIME".into()) },
+            ParamRef { keyword: "SIZE", value: Some("1024".into()) },
+            ParamRef { keyword: "SMTPUTF8", value: None },
+            ParamRef { keyword: "X-VALUE", value: Some("+".into()) },
+        ]);
+        assert_eq!(Request::Quit.params().count(), 0);
+
+        let rcpt = Request::Rcpt {
+            to: "user@example.test".parse().unwrap(),
+            params: vec![
+                "NOTIFY=FAILURE".parse().unwrap(),
+                "ORCPT=rfc822;user+2Bext@example.test".parse().unwrap(),
+            ],
+        };
+        assert_eq!(rcpt.orcpt(), Some(("rfc822", "user+ext@example.test")));
+        assert_eq!(rcpt.params().collect::<Vec<_>>(), vec![
+            ParamRef { keyword: "NOTIFY", value: Some("FAILURE".into()) },
+            ParamRef { keyword: "ORCPT", value: Some("rfc822;user+ext@example.test".into()) },
+        ]);
+        assert_eq!(Request::Rcpt { to: "".parse().unwrap(), params: vec![] }.orcpt(), None);
+        assert_eq!(Request::Quit.orcpt(), None);
+
+        for (body, expect) in vec![
+            (b"".as_ref(), 0),
+            (b"Hello\r\n", 7),
+            (b".Hello\r\n", 9),
+            (b"Hello\r\n.\r\n..\r\nWorld\r\n", 23),
+        ] {
+            assert_eq!(MailParam::size_for(body), MailParam::Size(expect));
+        }
+
+        let ascii: Mailbox = "john@example.test".parse().unwrap();
+        let unicode: Mailbox = "jøhn@exämple.test".parse().unwrap();
+        for (from, recipients, params, expect) in vec![
+            (ascii.clone(), vec![ascii.clone()], vec![], vec![]),
+            (unicode.clone(), vec![ascii.clone()], vec![], vec![MailParam::SmtpUtf8]),
+            (ascii.clone(), vec![ascii.clone(), unicode.clone()],
+                vec![MailParam::Size(1024)],
+                vec![MailParam::Size(1024), MailParam::SmtpUtf8]),
+            (unicode.clone(), vec![], vec![MailParam::SmtpUtf8], vec![MailParam::SmtpUtf8]),
+        ] {
+            assert_eq!(
+                Request::mail(from.clone(), &recipients, params),
+                Request::Mail { from, params: expect }
+            );
+        }
+        let mail = |params| Request::Mail { from: "".parse().unwrap(), params };
+        let body = MailParam::Body(MailBodyParam::SevenBit);
+        let size = MailParam::Size(1);
+        for (a, b, expect) in vec![
+            (mail(vec![size.clone(), body.clone()]), mail(vec![body.clone(), size.clone()]), true),
+            (mail(vec![size.clone(), body.clone()]), mail(vec![size.clone(), body.clone()]), true),
+            (mail(vec![size.clone(), size.clone()]), mail(vec![size.clone(), body.clone()]), false),
+            (mail(vec![size.clone()]), mail(vec![size.clone(), body.clone()]), false),
+            (mail(vec![]), Request::Quit, false),
+        ] {
+            assert_eq!(a.eq_ignoring_param_order(&b), expect);
+            assert_eq!(b.eq_ignoring_param_order(&a), expect);
+        }
+        assert!(mail(vec![size.clone(), body.clone()]) != mail(vec![body.clone(), size.clone()]));
+
+        let rcpt = |params| Request::Rcpt { to: "".parse().unwrap(), params };
+        let flag = RcptParam::Other { keyword: "X-FLAG".to_string(), value: None };
+        let value = RcptParam::Other { keyword: "X-VALUE".to_string(), value: Some(b"1".to_vec()) };
+        assert!(rcpt(vec![flag.clone(), value.clone()])
+            .eq_ignoring_param_order(&rcpt(vec![value.clone(), flag.clone()])));
+        assert!(!rcpt(vec![flag.clone()]).eq_ignoring_param_order(&rcpt(vec![value.clone()])));
+
+        assert_eq!(
+            Request::mail(unicode, &[], vec![]).to_string(),
+            "MAIL FROM:<jøhn@exämple.test> SMTPUTF8\r\n"
+        );
+
+        let eight_bit = Request::Mail {
+            from: "jøhn@example.test".parse().unwrap(),
+            params: vec![
+                MailParam::SmtpUtf8,
+                MailParam::Other { keyword: "X-NAME".to_string(), value: Some("jøhn".as_bytes().to_vec()) },
+            ],
+        };
+        assert_eq!(
+            eight_bit.to_bytes(),
+            b"MAIL FROM:<j\xc3\xb8hn@example.test> SMTPUTF8 X-NAME=j+C3+B8hn\r\n".to_vec()
+        );
+        assert_eq!(Request::Quit.to_bytes(), b"QUIT\r\n".to_vec());
+
+        // Values that are not UTF-8 are kept, and survive a round trip.
+        let raw = Request::Rcpt {
+            to: "alice@example.test".parse().unwrap(),
+            params: vec![RcptParam::Other { keyword: "X-RAW".to_string(), value: Some(b"\xff\xfe".to_vec()) }],
+        };
+        let bytes = raw.to_bytes();
+        assert_eq!(bytes, b"RCPT TO:<alice@example.test> X-RAW=+FF+FE\r\n".to_vec());
+        assert_eq!(from_utf8(&bytes).unwrap().parse::<Request>(), Ok(raw));
+
+        for input in vec![
+            "EHLO foobar.example\r\n",
+            "EHLO 127.0.0.1\r\n",
+            "EHLO IPv6:::1\r\n",
+            "EHLO x-custom:abc\r\n",
+            "LHLO foobar.example\r\n",
+            "STARTTLS\r\n",
+            "AUTH PLAIN\r\n",
+            "AUTH PLAIN am9obgBqb2huAHNlY3JldA==\r\n",
+            "MAIL FROM:<>\r\n",
+            "MAIL FROM:<john@example.test> BODY=8BITMIME SIZE=1024 X-VALUE=a+2Bb\r\n",
+            "MAIL FROM:<john@example.test> AUTH=john+2Bsubmit@example.test\r\n",
+            "MAIL FROM:<john@example.test> AUTH=<>\r\n",
+            "RCPT TO:<alice@example.test> NOTIFY=SUCCESS,FAILURE\r\n",
+            "DATA\r\n",
+            "BDAT 1024\r\n",
+            "BDAT 12 LAST\r\n",
+            "RSET\r\n",
+            "VRFY John Doe <john@example.test>\r\n",
+            "EXPN staff\r\n",
+            "ATRN\r\n",
+            "ATRN example.test\r\n",
+            "ATRN example.test,example.org\r\n",
+            "QUIT\r\n",
+        ] {
+            assert_eq!(input.parse::<Request>().unwrap().to_string(), input);
+        }
+        for (input, domain) in vec![
+            ("user@[192.0.2.1]", "[192.0.2.1]"),
+            ("user@[IPv6:2001:db8::1]", "[IPv6:2001:db8::1]"),
+            // The validator doesn't check domains, so this is accepted too.
+            ("user@192.0.2.1", "192.0.2.1"),
+        ] {
+            let mailbox = input.parse::<Mailbox>().unwrap();
+            assert_eq!(mailbox.0.as_ref().unwrap().domain, domain);
+            assert_eq!(mailbox.to_string(), format!("<{}>", input));
+
+            let mail = format!("MAIL FROM:<{}>\r\n", input);
+            assert_eq!(mail.parse::<Request>(), Ok(Request::Mail { from: mailbox, params: vec![] }));
+        }
+
+        for input in vec![
+            "john@example.test",
+            "John.Doe@Example.TEST",
+            "jøhn@exämple.test",
+            "\"john doe\"@example.test",
+            "user@[192.0.2.1]",
+            "a@b@example.test",
+        ] {
+            let from_addr = Mailbox::from(EmailAddress::new(input).unwrap());
+            let from_str = input.parse::<Mailbox>().unwrap();
+            assert_eq!(from_addr.to_string(), from_str.to_string());
+            assert_eq!(from_addr, from_str);
+        }
+
+        for value in vec!["=", "a=b", " ", "a b", "+", "+2B", "\x01", "\t", "\x7f", "~!", "jøhn", ""] {
+            let mail = MailParam::Other { keyword: "X-VALUE".to_string(), value: Some(value.as_bytes().to_vec()) };
+            let encoded = mail.to_string();
+            assert!(encoded["X-VALUE=".len()..].bytes().all(|c| c > b' ' && c < 0x7f && c != b'='));
+            assert_eq!(encoded.parse::<MailParam>(), Ok(mail));
+
+            let rcpt = RcptParam::Other { keyword: "X-VALUE".to_string(), value: Some(value.as_bytes().to_vec()) };
+            assert_eq!(rcpt.to_string(), encoded);
+            assert_eq!(encoded.parse::<RcptParam>(), Ok(rcpt));
+        }
+
+        #[derive(Debug)]
+        struct CustomError;
+        for (request, expect_body) in vec![(Request::Data, true), (Request::Quit, false)] {
+            match Frame::<Request, Vec<u8>, CustomError>::from(request.clone()) {
+                Frame::Message { message, body } => {
+                    assert_eq!(message, request);
+                    assert_eq!(body, expect_body);
+                },
+                _ => panic!("expected a message frame"),
+            }
+        }
+        let frame: Frame<Request, Vec<u8>, IoError> = Request::Data.into();
+        assert!(match frame { Frame::Message { body: true, .. } => true, _ => false });
+
+        for input in vec![
+            "\"John Doe\"@example.test",
+            "\"john>doe\"@example.test",
+            "\"john\\\"doe>\"@example.test",
+            "\"john@home\"@example.test",
+        ] {
+            let mailbox = input.parse::<Mailbox>().unwrap();
+            assert_eq!(mailbox.to_string(), format!("<{}>", input));
+            let rcpt = format!("RCPT TO:<{}> NOTIFY=NEVER\r\n", input);
+            let request = rcpt.parse::<Request>().unwrap();
+            assert_eq!(request, Request::Rcpt {
+                to: mailbox,
+                params: vec![RcptParam::Notify(NotifyParam::Never)],
+            });
+            assert_eq!(request.to_string(), rcpt);
+        }
+
+        let mut counts = HashMap::new();
+        for input in vec!["alice@example.test", "bob@example.test", "alice@example.test", ""] {
+            *counts.entry(input.parse::<Mailbox>().unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&"alice@example.test".parse().unwrap()], 2);
+        assert_eq!(counts[&Mailbox(None)], 1);
+        let sorted = counts.into_iter().map(|(mailbox, _)| mailbox).collect::<BTreeSet<_>>()
+            .into_iter().map(|mailbox| mailbox.to_string()).collect::<Vec<_>>();
+        assert_eq!(sorted, vec!["<>", "<alice@example.test>", "<bob@example.test>"]);
+
+        let utf8 = ParseOptions { smtputf8: true };
+        let ascii = ParseOptions::default();
+        let unicode_rcpt = "RCPT TO:<jøhn@exämple.test>\r\n";
+        assert_eq!(Request::parse_with(unicode_rcpt, &utf8).unwrap().to_string(), unicode_rcpt);
+        assert_eq!(Request::parse_with(unicode_rcpt, &ascii),
+            Err(ParseError::new(9, ParseErrorKind::NonAsciiAddress)));
+        assert!(Request::parse_with("RCPT TO:<john@example.test>\r\n", &ascii).is_ok());
+        assert!(Request::parse_with("MAIL FROM:<jøhn@example.test> SMTPUTF8\r\n", &ascii).is_ok());
+        assert_eq!(Request::parse_with("MAIL FROM:<jøhn@example.test>\r\n", &utf8),
+            Err(ParseError::new(11, ParseErrorKind::NonAsciiAddress)));
+
+        assert_eq!("EHLO  foobar.example \r\n".parse::<Request>(),
+            Ok(Request::Ehlo(ClientId::Domain("foobar.example".to_string()))));
+        assert_eq!("atrn  example.test, example.org \r\n".parse::<Request>(),
+            Ok(Request::Atrn(Some(vec!["example.test".to_string(), "example.org".to_string()]))));
+        assert_eq!("lhlo foobar.example\r\n".parse::<Request>(),
+            Ok(Request::Lhlo(ClientId::Domain("foobar.example".to_string()))));
+        assert_eq!("mail from:<> smtputf8".parse::<Request>(), Ok(Request::Mail {
+            from: Mailbox(None),
+            params: vec![MailParam::SmtpUtf8],
+        }));
+
+        for (input, offset, kind) in vec![
+            ("MAIL FROM <john@example.test>\r\n", 9, ParseErrorKind::ExpectedColon),
+            ("MAIL TO:<john@example.test>\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("MAIL FROM:john@example.test\r\n", 10, ParseErrorKind::InvalidPath),
+            ("MAIL FROM:<john>\r\n", 11, ParseErrorKind::InvalidPath),
+            ("MAIL FROM:<> SIZE=big\r\n", 13, ParseErrorKind::InvalidParam),
+            ("MAIL FROM:<>BODY=7BIT\r\n", 12, ParseErrorKind::InvalidSyntax),
+            ("RCPT TO:<alice@example.test> NOTIFY=NEVER,DELAY\r\n", 29, ParseErrorKind::InvalidParam),
+            ("RCPT TO <alice@example.test>\r\n", 7, ParseErrorKind::ExpectedColon),
+            ("EHLO\r\n", 4, ParseErrorKind::MissingArgument),
+            ("EHLO \r\n", 4, ParseErrorKind::MissingArgument),
+            ("EHLO \t \r\n", 4, ParseErrorKind::MissingArgument),
+            ("LHLO\r\n", 4, ParseErrorKind::MissingArgument),
+            ("QUIT now\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("VRFY\r\n", 4, ParseErrorKind::MissingArgument),
+            ("ATRN example.test,\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("ATRN a.test b.test\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("BDAT\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("BDAT -1\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("BDAT 12 FIRST\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("HELP\r\n", 0, ParseErrorKind::UnknownCommand),
+        ] {
+            assert_eq!(input.parse::<Request>(), Err(ParseError::new(offset, kind)));
+        }
+    }
+
+    #[test]
+    fn test_same_host() {
+        let parse = |arg: &str| match format!("EHLO {}\r\n", arg).parse::<Request>() {
+            Ok(Request::Ehlo(id)) => id,
+            other => panic!("unexpected {:?}", other),
+        };
+        for (a, b, expect) in vec![
+            ("Host.Example", "host.example", true),
+            ("host.example", "host.example", true),
+            ("host.example", "other.example", false),
+            ("[127.0.0.1]", "127.0.0.1", true),
+            ("[127.0.0.1]", "[127.0.0.1]", true),
+            ("[127.0.0.1]", "[127.0.0.2]", false),
+            ("[IPv6:2001:db8::1]", "ipv6:2001:DB8:0::1", true),
+            ("[IPv6:2001:db8::1]", "[2001:db8::1]", false),
+            ("X-Tag:value", "x-tag:value", true),
+            ("x-tag:value", "x-tag:Value", false),
+            ("127.0.0.1", "localhost", false),
+        ] {
+            assert_eq!(parse(a).same_host(&parse(b)), expect, "{} {}", a, b);
+            assert_eq!(parse(b).same_host(&parse(a)), expect, "{} {}", b, a);
+        }
+        assert_ne!(parse("[127.0.0.1]"), ClientId::Ipv4("127.0.0.1".parse().unwrap()));
+        assert!(parse("[127.0.0.1]").same_host(&ClientId::Ipv4("127.0.0.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_client_id_from_response() {
+        for (input, expect) in vec![
+            ("220 mail.example.test ESMTP ready\r\n", Some(ClientId::Domain("mail.example.test".to_string()))),
+            ("250-mail.example.test Hello\r\n250 PIPELINING\r\n",
+                Some(ClientId::Domain("mail.example.test".to_string()))),
+            ("220 [192.0.2.1] ESMTP\r\n", Some(ClientId::Ipv4("192.0.2.1".parse().unwrap()))),
+            ("250 [IPv6:2001:db8::1]\r\n", Some(ClientId::Ipv6("2001:db8::1".parse().unwrap()))),
+            ("220 [not-an-address] ESMTP\r\n", Some(ClientId::Domain("[not-an-address]".to_string()))),
+            ("220\r\n", None),
+            ("554 mail.example.test No service\r\n", None),
+        ] {
+            let response: Response = input.parse().unwrap();
+            assert_eq!(ClientId::from_response(&response), expect);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_sender() {
+        let mail: Request = "MAIL FROM:<john@example.test> SIZE=1024 BODY=8BITMIME RET=HDRS \
+                             ENVID=QQ314159\r\n".parse().unwrap();
+        let bounce = Mailbox::null();
+        let rewritten = mail.rewrite_sender("relay@example.test".parse().unwrap());
+        assert_eq!(rewritten.to_string(),
+            "MAIL FROM:<relay@example.test> SIZE=1024 BODY=8BITMIME RET=HDRS ENVID=QQ314159\r\n");
+        assert_eq!(mail.rewrite_sender(bounce).to_string(),
+            "MAIL FROM:<> SIZE=1024 BODY=8BITMIME RET=HDRS ENVID=QQ314159\r\n");
+        assert_eq!(Request::Rset.rewrite_sender("relay@example.test".parse().unwrap()), Request::Rset);
+    }
+
+    #[test]
+    fn test_write_into() {
+        let request: Request = "MAIL FROM:<jøhn@example.test> SIZE=1024 SMTPUTF8\r\n".parse().unwrap();
+        let expect = request.to_bytes();
+        assert_eq!(request.serialized_len(), expect.len());
+
+        let mut buf = [0u8; 64];
+        assert_eq!(request.write_into(&mut buf), Ok(expect.len()));
+        assert_eq!(&buf[..expect.len()], &expect[..]);
+        let mut exact = vec![0u8; expect.len()];
+        assert_eq!(request.write_into(&mut exact), Ok(expect.len()));
+        assert_eq!(exact, expect);
+
+        let mut small = [0u8; 16];
+        assert_eq!(request.write_into(&mut small), Err(BufferTooSmall { needed: expect.len() }));
+        assert_eq!(Request::Quit.write_into(&mut []), Err(BufferTooSmall { needed: 6 }));
+
+        let atrn = Request::Atrn(Some(vec!["a.test".to_string(), "b.test".to_string()]));
+        assert_eq!(atrn.serialized_len(), "ATRN a.test,b.test\r\n".len());
+        let mut buf = [0u8; 64];
+        assert_eq!(atrn.write_into(&mut buf), Ok(20));
+        assert_eq!(&buf[..20], b"ATRN a.test,b.test\r\n");
+        assert_eq!(atrn.write_into(&mut buf[..10]), Err(BufferTooSmall { needed: 20 }));
+
+        let rcpt: Request = "RCPT TO:<alice@example.test> NOTIFY=SUCCESS,DELAY\r\n".parse().unwrap();
+        assert_eq!(rcpt.write_into(&mut buf), Ok(rcpt.serialized_len()));
+        assert_eq!(&buf[..rcpt.serialized_len()], b"RCPT TO:<alice@example.test> NOTIFY=SUCCESS,DELAY\r\n".as_ref());
+        assert_eq!(BufferTooSmall { needed: 6 }.to_string(), "buffer too small, 6 bytes needed");
+    }
+
+    #[test]
+    fn test_expects_body() {
+        for (request, expect) in vec![
+            (Request::Data, true),
+            (Request::Bdat { size: 12, last: false }, true),
+            (Request::Bdat { size: 0, last: true }, true),
+            (Request::Rset, false),
+            (Request::Mail { from: Mailbox::null(), params: vec![] }, false),
+        ] {
+            assert_eq!(request.expects_body(), expect);
+            match Frame::<Request, Vec<u8>, IoError>::from(request) {
+                Frame::Message { body, .. } => assert_eq!(body, expect),
+                _ => panic!("expected a message frame"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_other_param() {
+        for keyword in vec!["X-FOO-BAR", "X-SomeVendor-Flag", "XFOO", "8BITMIME-X", "X--FOO"] {
+            let param = MailParam::Other { keyword: keyword.to_string(), value: None };
+            assert_eq!(MailParam::other(keyword, None), Ok(param.clone()));
+            assert_eq!(keyword.parse(), Ok(param));
+            let param = RcptParam::Other { keyword: keyword.to_string(), value: Some(b"1".to_vec()) };
+            assert_eq!(RcptParam::other(keyword, Some(b"1".to_vec())), Ok(param.clone()));
+            assert_eq!(format!("{}=1", keyword).parse(), Ok(param));
+        }
+        for keyword in vec!["-FOO", "", "X_FOO", "X.FOO", "X FOO", "X-FØØ"] {
+            assert_eq!(MailParam::other(keyword, None), Err(()));
+            assert_eq!(RcptParam::other(keyword, None), Err(()));
+            assert_eq!(keyword.parse::<MailParam>(), Err(()));
+            assert_eq!(format!("{}=1", keyword).parse::<RcptParam>(), Err(()));
+        }
+        assert_eq!("MAIL FROM:<> -FOO\r\n".parse::<Request>(),
+            Err(ParseError::new(13, ParseErrorKind::InvalidParam)));
+    }
+
+    #[test]
+    fn test_orcpt() {
+        for (addr_type, addr, encoded) in vec![
+            ("rfc822", "user+ext@example.test", "ORCPT=rfc822;user+2Bext@example.test"),
+            ("rfc822", "\"a;b\"@example.test", "ORCPT=rfc822;\"a;b\"@example.test"),
+            ("RFC822", "a=b+c;d@example.test", "ORCPT=RFC822;a+3Db+2Bc;d@example.test"),
+            ("utf-8", "jøhn@example.test", "ORCPT=utf-8;j+C3+B8hn@example.test"),
+        ] {
+            let param = RcptParam::Orcpt { addr_type: addr_type.to_string(), addr: addr.to_string() };
+            assert_eq!(param.to_string(), encoded);
+            assert_eq!(encoded.parse::<RcptParam>(), Ok(param.clone()));
+            assert_eq!(param.as_param_ref().value.unwrap(), format!("{};{}", addr_type, addr));
+        }
+    }
+
+    #[test]
+    fn test_rcpt_domain() {
+        for (input, expect) in vec![
+            ("RCPT TO:<alice@example.test>\r\n", Some("example.test")),
+            ("RCPT TO:<alice@Mail.Example.test> NOTIFY=NEVER\r\n", Some("Mail.Example.test")),
+            ("RCPT TO:<alice@[192.0.2.1]>\r\n", Some("[192.0.2.1]")),
+            ("RCPT TO:<postmaster@example.test>\r\n", Some("example.test")),
+            ("RCPT TO:<Postmaster>\r\n", None),
+            ("MAIL FROM:<john@example.test>\r\n", None),
+        ] {
+            assert_eq!(input.parse::<Request>().unwrap().rcpt_domain(), expect, "{}", input);
+        }
+        assert_eq!(Request::Rcpt { to: Mailbox::postmaster(), params: vec![] }.rcpt_domain(), None);
+        assert_eq!(Request::Rcpt { to: Mailbox::null(), params: vec![] }.rcpt_domain(), None);
+        assert_eq!(Request::Quit.rcpt_domain(), None);
+    }
+
+    #[test]
+    fn test_mail_builder() {
+        let from: Mailbox = "john@example.test".parse().unwrap();
+        let eight_bit = Capabilities::from_response(
+            &"250-mail.example.test\r\n250-8BITMIME\r\n250 SIZE 1024\r\n".parse().unwrap());
+        let seven_bit = Capabilities::from_response(
+            &"250-mail.example.test\r\n250 SIZE 1024\r\n".parse().unwrap());
+
+        for (caps, needed, expect) in vec![
+            (&eight_bit, true, "MAIL FROM:<john@example.test> SIZE=10 BODY=8BITMIME\r\n"),
+            (&eight_bit, false, "MAIL FROM:<john@example.test> SIZE=10\r\n"),
+            (&seven_bit, true, "MAIL FROM:<john@example.test> SIZE=10\r\n"),
+            (&seven_bit, false, "MAIL FROM:<john@example.test> SIZE=10\r\n"),
+        ] {
+            let mail = MailBuilder::new(from.clone())
+                .param(MailParam::Size(10))
+                .auto_body(caps, needed)
+                .build();
+            assert_eq!(mail.to_string(), expect);
+        }
+
+        let mail = MailBuilder::new(from)
+            .recipients(&["jøhn@example.test".parse().unwrap()])
+            .auto_body(&eight_bit, true)
+            .build();
+        assert_eq!(mail.to_string(), "MAIL FROM:<john@example.test> BODY=8BITMIME SMTPUTF8\r\n");
+
+        let mut session = SmtpSession::new();
+        let build = |session: &SmtpSession| {
+            MailBuilder::new("john@example.test".parse().unwrap())
+                .auto_body_for_session(session, true)
+                .build()
+                .to_string()
+        };
+        assert_eq!(build(&session), "MAIL FROM:<john@example.test>\r\n");
+        session.ehlo(&"250-mail.example.test\r\n250 SIZE 1024\r\n".parse().unwrap());
+        assert_eq!(build(&session), "MAIL FROM:<john@example.test>\r\n");
+        session.ehlo(&"250-mail.example.test\r\n250 8BITMIME\r\n".parse().unwrap());
+        assert_eq!(build(&session), "MAIL FROM:<john@example.test> BODY=8BITMIME\r\n");
+    }
+
+    #[test]
+    fn test_path_params() {
+        let two_params = vec![MailParam::Size(10), MailParam::Body(MailBodyParam::EightBitMime)];
+        for (input, from) in vec![
+            ("MAIL FROM:<a@example.test> SIZE=10 BODY=8BITMIME\r\n", "a@example.test"),
+            ("MAIL FROM:<\"a> b\"@example.test> SIZE=10 BODY=8BITMIME\r\n", "\"a> b\"@example.test"),
+            ("MAIL FROM:<a@[192.0.2.1]> SIZE=10 BODY=8BITMIME\r\n", "a@[192.0.2.1]"),
+        ] {
+            assert_eq!(input.parse::<Request>(), Ok(Request::Mail {
+                from: from.parse().unwrap(),
+                params: two_params.clone(),
+            }));
+        }
+        assert_eq!("MAIL FROM:<> SIZE=10 BODY=8BITMIME\r\n".parse::<Request>(), Ok(Request::Mail {
+            from: Mailbox::null(),
+            params: two_params.clone(),
+        }));
+        assert_eq!("RCPT TO:<alice@example.test> NOTIFY=NEVER X-FLAG\r\n".parse::<Request>(), Ok(Request::Rcpt {
+            to: "alice@example.test".parse().unwrap(),
+            params: vec![
+                RcptParam::Notify(NotifyParam::Never),
+                RcptParam::Other { keyword: "X-FLAG".to_string(), value: None },
+            ],
+        }));
+
+        // Params must be separated from the path, and each is checked.
+        for (input, offset, kind) in vec![
+            ("MAIL FROM:<a@example.test>SIZE=10 BODY=8BITMIME\r\n", 26, ParseErrorKind::InvalidSyntax),
+            ("RCPT TO:<alice@example.test>NOTIFY=NEVER\r\n", 28, ParseErrorKind::InvalidSyntax),
+            ("MAIL FROM:<a@example.test> SIZE=10 BODY=9BIT\r\n", 35, ParseErrorKind::InvalidParam),
+        ] {
+            assert_eq!(input.parse::<Request>(), Err(ParseError::new(offset, kind)));
+        }
+    }
+
+    #[test]
+    fn test_param_order() {
+        let from: Mailbox = "john@example.test".parse().unwrap();
+        let params = vec![
+            MailParam::Size(1024),
+            MailParam::Other { keyword: "X-B".to_string(), value: Some(b"2".to_vec()) },
+            MailParam::SmtpUtf8,
+            MailParam::Body(MailBodyParam::EightBitMime),
+            MailParam::Other { keyword: "X-A".to_string(), value: None },
+        ];
+        for (order, expect) in vec![
+            (vec![0, 1, 2, 3, 4], "SIZE=1024 X-B=2 SMTPUTF8 BODY=8BITMIME X-A"),
+            (vec![4, 3, 2, 1, 0], "X-A BODY=8BITMIME SMTPUTF8 X-B=2 SIZE=1024"),
+            (vec![2, 0, 4, 1, 3], "SMTPUTF8 SIZE=1024 X-A X-B=2 BODY=8BITMIME"),
+        ] {
+            let params = order.iter().map(|&i| params[i].clone()).collect::<Vec<_>>();
+            let mail = Request::Mail { from: from.clone(), params: params.clone() };
+            let line = format!("MAIL FROM:<john@example.test> {}\r\n", expect);
+            assert_eq!(mail.to_string(), line);
+            match line.parse::<Request>().unwrap() {
+                Request::Mail { params: parsed, .. } => assert_eq!(parsed, params),
+                _ => panic!("expected MAIL"),
+            }
+        }
+
+        let mut params = vec![MailParam::SmtpUtf8];
+        for i in 0..20 {
+            params.push(MailParam::Other { keyword: format!("X-{}", 20 - i), value: None });
+        }
+        let mail = Request::mail(from, &[], params.clone());
+        assert_eq!(mail, Request::Mail { from: "john@example.test".parse().unwrap(), params });
+
+        let rcpt = Request::Rcpt {
+            to: "alice@example.test".parse().unwrap(),
+            params: vec![
+                RcptParam::Orcpt { addr_type: "rfc822".to_string(), addr: "alice@example.test".to_string() },
+                RcptParam::Notify(NotifyParam::Never),
+            ],
+        };
+        assert_eq!(rcpt.to_string(), "RCPT TO:<alice@example.test> \
+            ORCPT=rfc822;alice@example.test NOTIFY=NEVER\r\n");
+    }
+
+    #[test]
+    fn test_debug() {
+        let auth = Request::Auth {
+            method: Some("PLAIN".to_string()),
+            data: Some("AGpvaG4Ac2VjcmV0".to_string()),
+        };
+        assert_eq!(format!("{:?}", auth), "Auth { method: Some(\"PLAIN\"), data: Some(\"<redacted>\") }");
+        let auth = Request::Auth { method: Some("LOGIN".to_string()), data: None };
+        assert_eq!(format!("{:?}", auth), "Auth { method: Some(\"LOGIN\"), data: None }");
+        let auth = Request::Auth { method: None, data: Some("c2VjcmV0".to_string()) };
+        assert_eq!(format!("{:?}", auth), "Auth { method: None, data: Some(\"<redacted>\") }");
+
+        assert_eq!(format!("{:?}", Request::Quit), "Quit");
+        assert_eq!(format!("{:?}", Request::Bdat { size: 12, last: true }), "Bdat { size: 12, last: true }");
+        assert_eq!(format!("{:?}", Request::Vrfy("john".to_string())), "Vrfy(\"john\")");
+        assert_eq!(format!("{:?}", Request::Rcpt { to: Mailbox(None), params: vec![] }),
+            "Rcpt { to: Mailbox(None), params: [] }");
+    }
+
+    #[test]
+    fn test_downgrade_to() {
+        let mail: Request = "MAIL FROM:<jøhn@example.test> BODY=8BITMIME SIZE=1024 SMTPUTF8 \
+            RET=HDRS X-CUSTOM=1\r\n".parse().unwrap();
+        let rcpt: Request = "RCPT TO:<alice@example.test> NOTIFY=NEVER\r\n".parse().unwrap();
+        for (ehlo, expect_mail, expect_rcpt) in vec![
+            (
+                "250 mail.example.test\r\n",
+                "MAIL FROM:<jøhn@example.test>\r\n",
+                "RCPT TO:<alice@example.test>\r\n",
+            ),
+            (
+                "250-mail.example.test\r\n250-8BITMIME\r\n250 DSN\r\n",
+                "MAIL FROM:<jøhn@example.test> BODY=8BITMIME RET=HDRS\r\n",
+                "RCPT TO:<alice@example.test> NOTIFY=NEVER\r\n",
+            ),
+            (
+                "250-mail.example.test\r\n250-SIZE 10240000\r\n250-smtputf8\r\n250 X-CUSTOM\r\n",
+                "MAIL FROM:<jøhn@example.test> SIZE=1024 SMTPUTF8 X-CUSTOM=1\r\n",
+                "RCPT TO:<alice@example.test>\r\n",
+            ),
+        ] {
+            let caps = Capabilities::from_response(&ehlo.parse().unwrap());
+            assert_eq!(mail.downgrade_to(&caps).to_string(), expect_mail);
+            assert_eq!(rcpt.downgrade_to(&caps).to_string(), expect_rcpt);
+        }
+
+        let caps = Capabilities::from_response(&"250-mail.example.test\r\n250 8BITMIME\r\n".parse().unwrap());
+        let binary: Request = "MAIL FROM:<> BODY=BINARYMIME\r\n".parse().unwrap();
+        assert_eq!(binary.downgrade_to(&caps).to_string(), "MAIL FROM:<>\r\n");
+        assert_eq!(Request::Quit.downgrade_to(&caps), Request::Quit);
+    }
+
+    #[test]
+    fn test_without_dsn() {
+        let mail: Request = "MAIL FROM:<john@example.test> SIZE=1024 RET=HDRS ENVID=QQ314159\r\n"
+            .parse().unwrap();
+        let rcpt: Request = "RCPT TO:<alice@example.test> NOTIFY=SUCCESS,FAILURE \
+            ORCPT=rfc822;alice@example.test\r\n".parse().unwrap();
+        assert_eq!(mail.without_dsn().to_string(), "MAIL FROM:<john@example.test> SIZE=1024\r\n");
+        assert_eq!(rcpt.without_dsn().to_string(), "RCPT TO:<alice@example.test>\r\n");
+        assert_eq!(Request::Data.without_dsn(), Request::Data);
+    }
+
+    #[test]
+    fn test_postmaster() {
+        for input in vec!["RCPT TO:<Postmaster>\r\n", "RCPT TO:<postmaster>\r\n", "RCPT TO:<POSTMASTER>\r\n"] {
+            match input.parse::<Request>().unwrap() {
+                Request::Rcpt { ref to, .. } => {
+                    assert_eq!(to, &Mailbox::postmaster());
+                    assert!(to.is_postmaster());
+                    assert!(to.is_domainless_postmaster());
+                },
+                _ => panic!("expected RCPT"),
+            }
+        }
+        assert_eq!(Request::Rcpt { to: Mailbox::postmaster(), params: vec![] }.to_string(),
+            "RCPT TO:<Postmaster>\r\n");
+
+        let with_domain: Mailbox = "postmaster@example.test".parse().unwrap();
+        assert!(with_domain.is_postmaster());
+        assert!(!with_domain.is_domainless_postmaster());
+        assert_ne!(with_domain, Mailbox::postmaster());
+        assert_eq!(with_domain.to_string(), "<postmaster@example.test>");
+
+        let other: Mailbox = "john@example.test".parse().unwrap();
+        assert!(!other.is_postmaster());
+        assert!(!Mailbox(None).is_postmaster());
+        assert!("john".parse::<Mailbox>().is_err());
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let buf = b"RSET\r\nQUIT\r\n";
+        assert_eq!(Request::parse_prefix(buf), Ok((Request::Rset, 6)));
+        assert_eq!(Request::parse_prefix(&buf[6..]), Ok((Request::Quit, 6)));
+        assert_eq!(Request::parse_prefix(b"RSET"), Err(ParseError::new(4, ParseErrorKind::Incomplete)));
+        assert_eq!(Request::parse_prefix(b"RS\xffET\r\n"),
+            Err(ParseError::new(2, ParseErrorKind::InvalidSyntax)));
+    }
+
+    #[test]
+    fn test_parse_validated() {
+        let opts = ParseOptions::default();
+        for input in vec![
+            "MAIL FROM:<john@example.test> SIZE=1024 BODY=8BITMIME\r\n",
+            "RCPT TO:<alice@example.test> NOTIFY=NEVER\r\n",
+            "MAIL FROM:<>\r\n",
+            "QUIT\r\n",
+        ] {
+            assert_eq!(Request::parse_validated(input.as_bytes(), &opts), Ok(input.parse().unwrap()));
+        }
+
+        let long_local = format!("MAIL FROM:<{}@example.test>\r\n", "a".repeat(65));
+        let long_domain = format!("RCPT TO:<alice@{}.test>\r\n", "a".repeat(251));
+        let long_line = format!("RCPT TO:<alice@example.test> ORCPT=rfc822;{}@example.test\r\n",
+            "a".repeat(480));
+        for (input, offset, kind) in vec![
+            ("MAIL FROM:<john@example.test> SIZE=1024 size=2048\r\n", 40, ParseErrorKind::DuplicateParam),
+            ("RCPT TO:<alice@example.test> NOTIFY=NEVER NOTIFY=SUCCESS\r\n", 42,
+                ParseErrorKind::DuplicateParam),
+            ("MAIL FROM:<john@example.test> SMTPUTF8 SMTPUTF8\r\n", 39, ParseErrorKind::DuplicateParam),
+            (&long_local, 11, ParseErrorKind::InvalidPath),
+            (&long_domain, 9, ParseErrorKind::InvalidPath),
+            (&long_line, 512, ParseErrorKind::LineTooLong),
+            ("MAIL FROM:<jøhn@example.test>\r\n", 11, ParseErrorKind::NonAsciiAddress),
+            ("MAIL FROM:<john@example.test\r\n", 10, ParseErrorKind::InvalidPath),
+        ] {
+            assert_eq!(Request::parse_validated(input.as_bytes(), &opts), Err(ParseError::new(offset, kind)),
+                "{}", input);
+        }
+        assert_eq!(Request::parse_validated(b"RS\xffET\r\n", &opts),
+            Err(ParseError::new(2, ParseErrorKind::InvalidSyntax)));
+    }
+
+    #[test]
+    fn test_request_parser() {
+        let transcript = b"EHLO client.example.test\r\nMAIL FROM:<john@example.test>\r\n\
+                           RCPT alice@example.test\r\nRCPT TO:<alice@example.test>\r\n";
+        let mut parser = RequestParser::new(transcript);
+        assert_eq!(parser.next(), Some(Ok(Request::Ehlo(ClientId::Domain("client.example.test".to_string())))));
+        assert_eq!(parser.next(), Some(Ok(Request::Mail {
+            from: "john@example.test".parse().unwrap(),
+            params: vec![],
+        })));
+        assert_eq!(parser.offset(), 57);
+        assert_eq!(parser.next(), Some(Err(ParseError::new(61, ParseErrorKind::InvalidSyntax))));
+        assert_eq!(parser.next(), Some(Ok(Request::Rcpt {
+            to: "alice@example.test".parse().unwrap(),
+            params: vec![],
+        })));
+        assert_eq!(parser.next(), None);
+        assert_eq!(parser.offset(), transcript.len());
+
+        let requests = RequestParser::new(b"RSET\r\nQUI").collect::<Vec<_>>();
+        assert_eq!(requests, vec![Ok(Request::Rset), Err(ParseError::new(9, ParseErrorKind::Incomplete))]);
+        assert_eq!(RequestParser::new(b"").next(), None);
+    }
+
+    #[test]
+    fn test_auth_continuation() {
+        for (input, data) in vec![
+            ("am9obgBqb2huAHNlY3JldA==\r\n", "am9obgBqb2huAHNlY3JldA=="),
+            ("a+b/\r\n", "a+b/"),
+            ("\r\n", ""),
+            ("*\r\n", "*"),
+        ] {
+            let request = Request::parse_auth_continuation(input).unwrap();
+            assert_eq!(request, Request::Auth { method: None, data: Some(data.to_string()) });
+            assert_eq!(request.is_auth_cancel(), data == "*");
+            assert_eq!(request.to_string(), input);
+        }
+        for (input, offset, kind) in vec![
+            ("am9obg==", 8, ParseErrorKind::Incomplete),
+            ("AUTH PLAIN\r\n", 4, ParseErrorKind::InvalidSyntax),
+            ("**\r\n", 0, ParseErrorKind::InvalidSyntax),
+            ("am9o\rbg==\r\n", 4, ParseErrorKind::InvalidSyntax),
+        ] {
+            assert_eq!(Request::parse_auth_continuation(input), Err(ParseError::new(offset, kind)));
+        }
+        assert!(!Request::Auth { method: Some("PLAIN".to_string()), data: Some("*".to_string()) }.is_auth_cancel());
+    }
+
+    #[test]
+    fn test_line_endings() {
+        // Every variant is written as a single line, terminated by CRLF.
+        let id = ClientId::Domain("client.example.test".to_string());
+        for (request, expect) in vec![
+            (Request::Ehlo(id.clone()), "EHLO client.example.test\r\n"),
+            (Request::Ehlo(ClientId::Ipv6("::1".parse().unwrap())), "EHLO IPv6:::1\r\n"),
+            (Request::Lhlo(id), "LHLO client.example.test\r\n"),
+            (Request::StartTls, "STARTTLS\r\n"),
+            (Request::Auth { method: Some("PLAIN".to_string()), data: Some("AGpvaG4Ac2VjcmV0".to_string()) },
+                "AUTH PLAIN AGpvaG4Ac2VjcmV0\r\n"),
+            (Request::Auth { method: Some("LOGIN".to_string()), data: None }, "AUTH LOGIN\r\n"),
+            (Request::Auth { method: None, data: Some("c2VjcmV0".to_string()) }, "c2VjcmV0\r\n"),
+            (Request::Auth { method: None, data: Some("*".to_string()) }, "*\r\n"),
+            (Request::Mail { from: Mailbox::null(), params: vec![MailParam::SmtpUtf8] },
+                "MAIL FROM:<> SMTPUTF8\r\n"),
+            (Request::Rcpt { to: Mailbox::postmaster(), params: vec![] }, "RCPT TO:<Postmaster>\r\n"),
+            (Request::Data, "DATA\r\n"),
+            (Request::Bdat { size: 12, last: true }, "BDAT 12 LAST\r\n"),
+            (Request::Rset, "RSET\r\n"),
+            (Request::Vrfy("john".to_string()), "VRFY john\r\n"),
+            (Request::Expn("staff".to_string()), "EXPN staff\r\n"),
+            (Request::Atrn(Some(vec!["a.test".to_string(), "b.test".to_string()])), "ATRN a.test,b.test\r\n"),
+            (Request::Atrn(Some(vec![])), "ATRN\r\n"),
+            (Request::Atrn(None), "ATRN\r\n"),
+            (Request::Quit, "QUIT\r\n"),
+        ] {
+            let line = request.to_string();
+            assert_eq!(line, expect);
+            assert!(!line[..line.len() - 2].contains(|c| c == '\r' || c == '\n'), "{:?}", line);
+            assert_eq!(request.to_bytes(), line.as_bytes());
+        }
+        assert_eq!(Request::Atrn(Some(vec![])).to_string().parse::<Request>(), Ok(Request::Atrn(None)));
+    }
+
+    #[test]
+    fn test_transaction() {
+        let john: Mailbox = "john@example.test".parse().unwrap();
+        let alice: Mailbox = "alice@example.test".parse().unwrap();
+        let bob: Mailbox = "bob@example.test".parse().unwrap();
+        let mut requests = vec![
+            Request::Ehlo(ClientId::Domain("client.example.test".to_string())),
+            Request::Mail { from: john.clone(), params: vec![] },
+            Request::Rcpt { to: alice.clone(), params: vec![] },
+            Request::Rcpt { to: bob.clone(), params: vec![RcptParam::Notify(NotifyParam::Never)] },
+            Request::Data,
+        ];
+        assert_eq!(transaction_sender(&requests), Some(john.clone()));
+        assert_eq!(transaction_recipients(&requests), vec![alice.clone(), bob.clone()]);
+
+        // Only the last transaction counts.
+        requests.push(Request::Mail { from: Mailbox::null(), params: vec![] });
+        requests.push(Request::Rcpt { to: john.clone(), params: vec![] });
+        assert_eq!(transaction_sender(&requests), Some(Mailbox::null()));
+        assert_eq!(transaction_recipients(&requests), vec![john]);
+
+        requests.push(Request::Rset);
+        assert_eq!(transaction_sender(&requests), None);
+        assert!(transaction_recipients(&requests).is_empty());
+        assert_eq!(transaction_sender(&[]), None);
+        assert!(transaction_recipients(&[Request::Rcpt { to: alice, params: vec![] }]).is_empty());
+    }
+
+    #[test]
+    fn test_build_validated() {
+        let from: Mailbox = "john@example.test".parse().unwrap();
+        let other = |keyword: &str, value: &str| MailParam::Other {
+            keyword: keyword.to_string(),
+            value: Some(value.as_bytes().to_vec()),
+        };
+
+        let mail = MailBuilder::new(from.clone())
+            .param(MailParam::Size(10))
+            .param(other("RET", "HDRS"))
+            .envid("abc")
+            .build_validated();
+        assert_eq!(mail, Ok("MAIL FROM:<john@example.test> SIZE=10 RET=HDRS ENVID=abc\r\n".parse().unwrap()));
+
+        let errors = MailBuilder::new("jo\nhn@example.test".parse().unwrap())
+            .param(other("X_BAD", "1"))
+            .param(other("RET", "HDRS"))
+            .param(other("ret", "FULL"))
+            .param(other("", "1"))
+            .envid("a".repeat(500))
+            .build_validated()
+            .unwrap_err();
+        assert_eq!(errors, vec![
+            BuildError::InvalidKeyword("X_BAD".to_string()),
+            BuildError::DuplicateParam("RET".to_string()),
+            BuildError::InvalidKeyword("".to_string()),
+            BuildError::LineTooLong(568),
+            BuildError::Invalid(RequestError::ControlCharacter),
+        ]);
+        assert_eq!(errors[1].to_string(), "duplicate parameter: RET");
+        assert_eq!(errors[3].to_string(), "command line too long: 568 octets");
+
+        let errors = MailBuilder::new(from)
+            .envid("one")
+            .envid("two")
+            .envid("three")
+            .build_validated()
+            .unwrap_err();
+        assert_eq!(errors, vec![BuildError::DuplicateParam("ENVID".to_string())]);
+    }
+
+    #[test]
+    fn test_envid() {
+        let mail = MailBuilder::new("john@example.test".parse().unwrap())
+            .envid("QQ314159+one=two")
+            .build();
+        assert_eq!(mail.to_string(), "MAIL FROM:<john@example.test> ENVID=QQ314159+2Bone+3Dtwo\r\n");
+        assert_eq!(mail.to_string().parse::<Request>(), Ok(mail));
+
+        // The value is required.
+        assert_eq!("ENVID".parse::<MailParam>(), Err(()));
+        assert_eq!("envid".parse::<MailParam>(), Err(()));
+        assert!("MAIL FROM:<john@example.test> ENVID\r\n".parse::<Request>().is_err());
+    }
+
+    #[cfg(feature = "random-envid")]
+    #[test]
+    fn test_envid_random() {
+        let envid = |request: &Request| match *request {
+            Request::Mail { ref params, .. } => match params[..] {
+                [MailParam::Envid(ref envid)] => envid.clone(),
+                _ => panic!("expected ENVID"),
+            },
+            _ => panic!("expected MAIL"),
+        };
+        let mail = MailBuilder::new("john@example.test".parse().unwrap()).envid_random().build();
+        let id = envid(&mail);
+        assert_eq!(id.len(), 32);
+        assert!(id.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(mail.to_string(), format!("MAIL FROM:<john@example.test> ENVID={}\r\n", id));
+
+        let other = MailBuilder::new(Mailbox::null()).envid_random().build();
+        assert_ne!(envid(&other), id);
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        for input in vec![
+            "john@example.test",
+            "\"John Doe\"@example.test",
+            "john@[192.0.2.1]",
+            "john@[IPv6:2001:db8::1]",
+            "Postmaster",
+            "",
+        ] {
+            assert_eq!(Mailbox::parse_strict(input), input.parse::<Mailbox>());
+        }
+        for input in vec!["a@b@example.test", "jo hn@example.test", "john@exa_mple.test", "john@[::1]"] {
+            assert!(input.parse::<Mailbox>().is_ok(), "{}", input);
+            assert!(Mailbox::parse_strict(input).is_err(), "{}", input);
+        }
+        assert_eq!(Mailbox::parse_strict("john").unwrap_err().msg, "missing '@' character");
+    }
+
+    #[test]
+    fn test_null_mailbox() {
+        let empty = Mailbox(Some(EmailAddress { local: String::new(), domain: String::new() }));
+        for (input, expect) in vec![
+            ("MAIL FROM:<>\r\n", Mailbox::null()),
+            ("MAIL FROM:<> SIZE=10\r\n", Mailbox(None)),
+            ("MAIL FROM:<>\r\n", empty.clone()),
+        ] {
+            match input.parse::<Request>().unwrap() {
+                Request::Mail { ref from, .. } => {
+                    assert_eq!(from, &expect);
+                    assert!(from.0.is_none());
+                    assert!(from.is_null());
+                },
+                _ => panic!("expected MAIL"),
+            }
+        }
+        assert_eq!("".parse::<Mailbox>().unwrap(), Mailbox::null());
+
+        assert!(empty.is_null());
+        assert_eq!(empty, Mailbox::null());
+        assert_eq!(empty.to_string(), "<>");
+        assert_eq!(Request::Mail { from: empty.clone(), params: vec![] },
+            Request::Mail { from: Mailbox::null(), params: vec![] });
+        let mut set = BTreeSet::new();
+        set.insert(empty.clone());
+        set.insert(Mailbox::null());
+        assert_eq!(set.len(), 1);
+        let mut counts = HashMap::new();
+        *counts.entry(empty).or_insert(0) += 1;
+        *counts.entry(Mailbox::null()).or_insert(0) += 1;
+        assert_eq!(counts[&Mailbox(None)], 2);
+
+        let other: Mailbox = "john@example.test".parse().unwrap();
+        assert!(!other.is_null());
+        assert!(!Mailbox::postmaster().is_null());
+        assert_ne!(other, Mailbox::null());
+        assert!(Mailbox::null() < other);
+    }
+
+    #[test]
+    fn test_validate() {
+        for (request, expect) in vec![
+            (Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] }, Ok(())),
+            (
+                Request::Mail {
+                    from: "john@example.test".parse().unwrap(),
+                    params: vec![MailParam::Other {
+                        keyword: "X-TAG\r\nRCPT TO:<eve@example.test>".to_string(),
+                        value: None,
+                    }],
+                },
+                Err(RequestError::ControlCharacter),
+            ),
+            (
+                Request::Rcpt {
+                    to: Mailbox(Some(EmailAddress {
+                        local: "alice".to_string(),
+                        domain: "example.test>\r\nDATA\r\n<x".to_string(),
+                    })),
+                    params: vec![],
+                },
+                Err(RequestError::ControlCharacter),
+            ),
+            (
+                Request::Mail {
+                    from: "john@example.test".parse().unwrap(),
+                    params: vec![MailParam::Other {
+                        keyword: "X-TAG".to_string(),
+                        value: Some(b"a\r\nRSET".to_vec()),
+                    }],
+                },
+                Ok(()),
+            ),
+            (Request::Ehlo(ClientId::Domain("client\n.example.test".to_string())), Err(RequestError::ControlCharacter)),
+            (Request::Vrfy("john\r".to_string()), Err(RequestError::ControlCharacter)),
+            (Request::Quit, Ok(())),
+        ] {
+            assert_eq!(request.validate(), expect);
+        }
+    }
+
+    #[test]
+    fn test_bounce_envelope() {
+        let bounce: Vec<_> = Request::bounce_envelope("john@example.test".parse().unwrap())
+            .iter().map(Request::to_string).collect();
+        assert_eq!(bounce, vec![
+            "MAIL FROM:<>\r\n",
+            "RCPT TO:<john@example.test> NOTIFY=NEVER\r\n",
+        ]);
+
+        let bounce: Vec<_> = Request::bounce_envelope("jøhn@example.test".parse().unwrap())
+            .iter().map(Request::to_string).collect();
+        assert_eq!(bounce, vec![
+            "MAIL FROM:<> SMTPUTF8\r\n",
+            "RCPT TO:<jøhn@example.test> NOTIFY=NEVER\r\n",
+        ]);
+
+        assert_eq!(Request::bounce_envelope(Mailbox(None)), vec![]);
+        // An empty address is the null path as well.
+        let empty = Mailbox(Some(EmailAddress { local: String::new(), domain: String::new() }));
+        assert_eq!(Request::bounce_envelope(empty), vec![]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut queue = BinaryHeap::new();
+        for (seq, input) in vec![
+            "MAIL FROM:<a@example.test>\r\n",
+            "MAIL FROM:<b@example.test> MT-PRIORITY=3\r\n",
+            "MAIL FROM:<c@example.test> MT-PRIORITY=-4\r\n",
+            "MAIL FROM:<d@example.test> mt-priority=3\r\n",
+            "MAIL FROM:<e@example.test> MT-PRIORITY=12\r\n",
+            "MAIL FROM:<f@example.test> SIZE=10 MT-PRIORITY=9\r\n",
+        ].into_iter().enumerate() {
+            let request: Request = input.parse().unwrap();
+            queue.push((request.priority(), Reverse(seq)));
+        }
+        let order: Vec<_> = queue.into_sorted_vec().into_iter().rev()
+            .map(|(prio, Reverse(seq))| (prio, seq))
+            .collect();
+        assert_eq!(order, vec![(9, 5), (3, 1), (3, 3), (0, 0), (0, 4), (-4, 2)]);
+
+        assert_eq!(Request::Rset.priority(), 0);
+        assert_eq!("RCPT TO:<a@example.test>\r\n".parse::<Request>().unwrap().priority(), 0);
+    }
+
+    /// A deterministic xorshift generator, so failures can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn string(&mut self, chars: &[char], min: usize, max: usize) -> String {
+            let len = min + self.below(max - min + 1);
+            (0..len).map(|_| chars[self.below(chars.len())]).collect()
+        }
+    }
+
+    fn random_request(rng: &mut Rng) -> Request {
+        let alnum: Vec<char> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".chars().collect();
+        let local: Vec<char> = "abcdefghijklmnopqrstuvwxyz0123456789.-+_".chars().collect();
+        let value: Vec<char> = "abc019 +=<>:;,.\"\\\x7fø❤".chars().collect();
+        let mailbox = |rng: &mut Rng| -> Mailbox {
+            if rng.below(8) == 0 {
+                return Mailbox(None);
+            }
+            Mailbox(Some(EmailAddress {
+                local: rng.string(&local, 1, 12),
+                domain: format!("{}.{}", rng.string(&alnum, 1, 8), rng.string(&alnum, 2, 3)),
+            }))
+        };
+        let keyword = |rng: &mut Rng| format!("X-{}", rng.string(&alnum, 1, 6));
+        let other_value = |rng: &mut Rng| if rng.below(3) == 0 { None } else { Some(rng.string(&value, 0, 10).into_bytes()) };
+
+        match rng.below(8) {
+            0 | 1 => {
+                let from = mailbox(rng);
+                let params = (0..rng.below(4)).map(|_| match rng.below(4) {
+                    0 => MailParam::Size(rng.below(1 << 20)),
+                    1 => MailParam::Body([
+                        MailBodyParam::SevenBit,
+                        MailBodyParam::EightBitMime,
+                        MailBodyParam::BinaryMime,
+                    ][rng.below(3)].clone()),
+                    2 => MailParam::SmtpUtf8,
+                    _ => MailParam::Other { keyword: keyword(rng), value: other_value(rng) },
+                }).collect();
+                Request::Mail { from, params }
+            },
+            2 | 3 => {
+                let to = match mailbox(rng) {
+                    Mailbox(None) => Mailbox::postmaster(),
+                    to => to,
+                };
+                let params = (0..rng.below(4)).map(|_| match rng.below(3) {
+                    0 => RcptParam::Notify(NotifyParam::Conditions {
+                        success: true,
+                        failure: rng.below(2) == 0,
+                        delay: rng.below(2) == 0,
+                    }),
+                    1 => RcptParam::Orcpt {
+                        addr_type: rng.string(&alnum, 1, 6),
+                        addr: rng.string(&value, 1, 10),
+                    },
+                    _ => RcptParam::Other { keyword: keyword(rng), value: other_value(rng) },
+                }).collect();
+                Request::Rcpt { to, params }
+            },
+            4 => Request::Vrfy(rng.string(&local, 1, 10)),
+            5 => Request::Bdat { size: rng.below(1 << 16), last: rng.below(2) == 0 },
+            6 => Request::Ehlo(ClientId::Domain(format!("{}.test", rng.string(&alnum, 1, 10)))),
+            _ => [Request::Data, Request::Rset, Request::Quit][rng.below(3)].clone(),
+        }
+    }
+
+    /// A command line, split up by a tokenizer that is independent of ours
+    #[derive(PartialEq,Debug)]
+    struct Tokens {
+        verb: String,
+        path: Option<String>,
+        params: Vec<(String, Option<String>)>,
+        rest: String,
+    }
+
+    /// Split a command line on spaces, and decode xtext in parameter values
+    fn tokenize(line: &str) -> Tokens {
+        assert!(line.ends_with("\r\n"), "line not terminated: {:?}", line);
+        let line = &line[..line.len() - 2];
+        assert!(!line.contains('\r') && !line.contains('\n'), "bare CR or LF: {:?}", line);
+
+        let mut words = line.split(' ');
+        let verb = words.next().unwrap().to_string();
+        let prefix = match verb.as_str() {
+            "MAIL" => "FROM:<",
+            "RCPT" => "TO:<",
+            _ => {
+                let rest = line[verb.len()..].trim().to_string();
+                return Tokens { verb, path: None, params: vec![], rest };
+            },
+        };
+
+        let path = words.next().unwrap();
+        assert!(path.starts_with(prefix) && path.ends_with('>'), "bad path: {:?}", line);
+        let path = path[prefix.len()..path.len() - 1].to_string();
+        let params = words.map(|word| {
+            let mut parts = word.splitn(2, '=');
+            let keyword = parts.next().unwrap().to_string();
+            let value = parts.next().map(|value| {
+                let mut bytes = vec![];
+                let mut idx = 0;
+                while idx < value.len() {
+                    if &value[idx..idx + 1] == "+" {
+                        bytes.push(u8::from_str_radix(&value[idx + 1..idx + 3], 16).unwrap());
+                        idx += 3;
+                    } else {
+                        bytes.push(value.as_bytes()[idx]);
+                        idx += 1;
+                    }
+                }
+                String::from_utf8(bytes).unwrap()
+            });
+            (keyword, value)
+        }).collect();
+        Tokens { verb, path: Some(path), params, rest: String::new() }
+    }
+
+    /// What `tokenize` should find for a request
+    fn expected_tokens(request: &Request) -> Tokens {
+        let path = match *request {
+            Request::Mail { ref from, .. } => Some(from),
+            Request::Rcpt { ref to, .. } => Some(to),
+            _ => None,
+        }.map(|mailbox| {
+            let path = mailbox.to_string();
+            path[1..path.len() - 1].to_string()
+        });
+        let rest = match *request {
+            Request::Vrfy(ref arg) => arg.clone(),
+            Request::Bdat { size, last: true } => format!("{} LAST", size),
+            Request::Bdat { size, last: false } => size.to_string(),
+            Request::Ehlo(ref id) => id.to_string(),
+            _ => String::new(),
+        };
+        Tokens {
+            verb: request.verb().to_string(),
+            path,
+            params: request.params()
+                .map(|param| (param.keyword.to_string(), param.value.map(|value| value.into_owned())))
+                .collect(),
+            rest,
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        let mut rng = Rng(0x5eed_cafe_f00d_d00d);
+        for _ in 0..2000 {
+            let request = random_request(&mut rng);
+            let line = request.to_string();
+            assert_eq!(tokenize(&line), expected_tokens(&request), "tokenizing {:?}", line);
+            assert_eq!(line.parse::<Request>(), Ok(request), "parsing {:?}", line);
+        }
+    }
+
+    #[cfg(feature = "lettre-interop")]
+    #[test]
+    fn test_lettre() {
+        use lettre::{EmailAddress, Envelope};
+
+        let envelope = Envelope::new(
+            Some(EmailAddress::new("john@example.test".to_string()).unwrap()),
+            vec![
+                EmailAddress::new("alice@example.test".to_string()).unwrap(),
+                EmailAddress::new("bob@example.test".to_string()).unwrap(),
+            ],
+        ).unwrap();
+        assert_eq!(Request::from_envelope(&envelope).unwrap(), vec![
+            Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] },
+            Request::Rcpt { to: "alice@example.test".parse().unwrap(), params: vec![] },
+            Request::Rcpt { to: "bob@example.test".parse().unwrap(), params: vec![] },
+        ]);
+
+        let envelope = Envelope::new(
+            None,
+            vec![EmailAddress::new("alice@example.test".to_string()).unwrap()],
+        ).unwrap();
+        assert_eq!(Request::from_envelope(&envelope).unwrap(), vec![
+            Request::Mail { from: "".parse().unwrap(), params: vec![] },
+            Request::Rcpt { to: "alice@example.test".parse().unwrap(), params: vec![] },
+        ]);
     }
 }
@@ -0,0 +1,113 @@
+//! Finding the local host name to use as the client identifier
+//!
+//! RFC 5321 asks clients to identify themselves in `EHLO` with their fully
+//! qualified domain name, or an address literal if they have none. This module
+//! is only available with the `resolve` feature.
+
+use dns_lookup::{get_hostname, lookup_addr};
+use futures::{Future};
+use futures::sync::{oneshot};
+use request::{ClientId};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr};
+use std::thread;
+
+
+/// Sources of names for the local host
+pub trait LocalNames {
+    /// Find the name for an address using reverse DNS
+    fn reverse_dns(&self, addr: &IpAddr) -> Option<String>;
+    /// Find the configured host name
+    fn hostname(&self) -> Option<String>;
+}
+
+
+/// Looks up names using the system resolver
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SystemNames;
+
+impl LocalNames for SystemNames {
+    fn reverse_dns(&self, addr: &IpAddr) -> Option<String> {
+        lookup_addr(addr).ok()
+    }
+
+    fn hostname(&self) -> Option<String> {
+        get_hostname().ok()
+    }
+}
+
+
+impl ClientId {
+    /// Find the identifier for the local end of a connection
+    ///
+    /// `local_addr` is the address the connection is made from. The lookups
+    /// block, so they run on a separate thread.
+    pub fn resolve_local(local_addr: IpAddr) -> Box<Future<Item = ClientId, Error = IoError> + Send> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(ClientId::resolve_with(&SystemNames, local_addr));
+        });
+        Box::new(rx.map_err(|_| IoError::new(IoErrorKind::Other, "client id resolution failed")))
+    }
+
+    /// Find the identifier for `local_addr`, using the given name sources
+    ///
+    /// This prefers the reverse DNS name of the address, then the host name,
+    /// and falls back to the address literal. Names are only used if they look
+    /// fully qualified, i.e. contain a dot.
+    pub fn resolve_with<N: LocalNames>(names: &N, local_addr: IpAddr) -> ClientId {
+        let is_fqdn = |name: &String| name.contains('.') && !name.ends_with('.') &&
+            name.parse::<IpAddr>().is_err();
+        names.reverse_dns(&local_addr)
+            .filter(&is_fqdn)
+            .or_else(|| names.hostname().filter(&is_fqdn))
+            .map(ClientId::Domain)
+            .unwrap_or_else(|| match local_addr {
+                IpAddr::V4(addr) => ClientId::Ipv4(addr),
+                IpAddr::V6(addr) => ClientId::Ipv6(addr),
+            })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use request::{ClientId};
+    use resolve::{LocalNames};
+    use std::net::{IpAddr};
+
+    struct MockNames {
+        reverse_dns: Option<&'static str>,
+        hostname: Option<&'static str>,
+    }
+
+    impl LocalNames for MockNames {
+        fn reverse_dns(&self, _: &IpAddr) -> Option<String> {
+            self.reverse_dns.map(|name| name.to_string())
+        }
+
+        fn hostname(&self) -> Option<String> {
+            self.hostname.map(|name| name.to_string())
+        }
+    }
+
+    #[test]
+    fn test() {
+        let v4 = "192.0.2.1".parse().unwrap();
+        let v6 = "2001:db8::1".parse().unwrap();
+        for (reverse_dns, hostname, addr, expect) in vec![
+            (Some("mx.example.test"), Some("host.example.test"), v4,
+                ClientId::Domain("mx.example.test".to_string())),
+            (None, Some("host.example.test"), v4,
+                ClientId::Domain("host.example.test".to_string())),
+            (Some("192.0.2.1"), Some("host.example.test"), v4,
+                ClientId::Domain("host.example.test".to_string())),
+            (Some("mx"), Some("host"), v4, ClientId::Ipv4("192.0.2.1".parse().unwrap())),
+            (None, None, v4, ClientId::Ipv4("192.0.2.1".parse().unwrap())),
+            (None, None, v6, ClientId::Ipv6("2001:db8::1".parse().unwrap())),
+        ] {
+            let names = MockNames { reverse_dns, hostname };
+            assert_eq!(ClientId::resolve_with(&names, addr), expect);
+        }
+    }
+}
@@ -3,7 +3,10 @@
 //! A response consists of a status code, and zero or more lines of text. This
 //! module does not derive any meaning from the response text.
 
+use codes;
+use error::{ParseError, ParseErrorKind};
 use nom::{crlf, ErrorKind as NomErrorKind, IResult as NomResult, Needed};
+use std::error::{Error as StdError};
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::str::{FromStr, from_utf8};
 
@@ -154,6 +157,22 @@ impl Code {
     pub fn parse(input: &[u8]) -> NomResult<&[u8], Code> {
         parse_code(input)
     }
+
+    /// Create a code from its numeric value, such as one from `codes`
+    ///
+    /// Returns `None` if the value is not a valid reply code.
+    pub fn from_numeric(code: u16) -> Option<Code> {
+        if code < 200 || code >= 600 {
+            return None;
+        }
+        code.to_string().parse().ok()
+    }
+
+    /// The numeric value of the code, for comparison with `codes`
+    pub fn numeric(&self) -> u16 {
+        self.severity.numeric() as u16 * 100 + self.category.numeric() as u16 * 10 +
+            self.detail.0 as u16
+    }
 }
 
 impl FromStr for Code {
@@ -174,6 +193,78 @@ impl Display for Code {
 }
 
 
+/// An enhanced status code (RFC 3463), such as `2.1.0`
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub struct EnhancedCode {
+    /// `2`, `4` or `5`
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl FromStr for EnhancedCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EnhancedCode, ()> {
+        let mut parts = s.split('.');
+        let mut next = |max_len: usize| -> Result<u16, ()> {
+            match parts.next() {
+                Some(part) if !part.is_empty() && part.len() <= max_len &&
+                    part.bytes().all(|c| c.is_ascii_digit()) => part.parse().map_err(|_| ()),
+                _ => Err(()),
+            }
+        };
+        let class = next(1)?;
+        let subject = next(3)?;
+        let detail = next(3)?;
+        if parts.next().is_some() || !(class == 2 || class == 4 || class == 5) {
+            return Err(());
+        }
+        Ok(EnhancedCode { class: class as u8, subject, detail })
+    }
+}
+
+impl Display for EnhancedCode {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+
+/// The maximum length of a reply line, including the code and CRLF
+///
+/// See RFC 5321, section 4.5.3.1.5.
+pub const MAX_LINE_LEN: usize = 512;
+
+
+/// Errors from validating a `Response`
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum ResponseError {
+    /// A line is longer than `MAX_LINE_LEN` on the wire
+    LineTooLong,
+    /// `ENHANCEDSTATUSCODES` was advertised, but the reply has no enhanced code
+    MissingEnhancedCode,
+    /// The code is not a three digit reply code
+    InvalidCode,
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for ResponseError {
+    fn description(&self) -> &str {
+        match *self {
+            ResponseError::LineTooLong => "response line too long",
+            ResponseError::MissingEnhancedCode => "response is missing an enhanced status code",
+            ResponseError::InvalidCode => "response code is not a three digit reply code",
+        }
+    }
+}
+
+
 /// Represents a complete response
 ///
 /// The message text is optional, and may be empty.
@@ -188,29 +279,192 @@ impl Response {
         parse_response(input)
     }
 
+    /// Parse a response, and keep the exact bytes it was parsed from
+    pub fn parse_raw(input: &[u8]) -> NomResult<&[u8], RawResponse> {
+        match parse_response(input) {
+            NomResult::Done(rest, response) => {
+                let raw = input[..input.len() - rest.len()].to_vec();
+                NomResult::Done(rest, RawResponse { response, raw })
+            },
+            NomResult::Incomplete(needed) => NomResult::Incomplete(needed),
+            NomResult::Error(err) => NomResult::Error(err),
+        }
+    }
+
+    /// Create a response from a code and lines of text
+    pub fn new(code: Code, text: Vec<String>) -> Response {
+        Response { code, text }
+    }
+
+    /// Start building a response with the given code
+    ///
+    /// A `Code` cannot hold most invalid values, so there is nothing to check
+    /// here: the severity and category are enums of the defined digits, and
+    /// only the detail digit can be out of range, which `build` rejects. Use
+    /// `builder_numeric` to start from a number instead.
+    pub fn builder(code: Code) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response { code, text: vec![] },
+        }
+    }
+
+    /// Start building a response with a numeric code, such as one from `codes`
+    ///
+    /// Fails if the code is not a three digit reply code. RFC 5321 (section
+    /// 4.2.1) defines first digits from 2 to 5 only, the `1yz` replies of
+    /// earlier specifications are gone, and `Severity` cannot represent them.
+    /// So only 200 to 599 are accepted, not 100 to 599.
+    ///
+    /// Codes in range that are not in `codes` are accepted, see
+    /// `codes::is_defined` to warn about those.
+    pub fn builder_numeric(code: u16) -> Result<ResponseBuilder, ResponseError> {
+        Code::from_numeric(code).map(Response::builder).ok_or(ResponseError::InvalidCode)
+    }
+
+    /// Replace the text of the first line, e.g. the domain in a banner
+    ///
+    /// A line is added if the response has no text. Line breaks in the new
+    /// text are replaced by spaces, so the reply cannot be split.
+    pub fn rewrite_first_line(&mut self, new_text: &str) {
+        let line = new_text.replace(|c| c == '\r' || c == '\n', " ");
+        if self.text.is_empty() {
+            self.text.push(line);
+        } else {
+            self.text[0] = line;
+        }
+    }
+
+    /// Replace the code, keeping the text
+    pub fn with_code(self, code: Code) -> Response {
+        Response { code, text: self.text }
+    }
+
+    /// Check that the response can be sent as is
+    ///
+    /// The code must have three digits, and each line, including the code and
+    /// CRLF, must fit in `MAX_LINE_LEN`.
+    pub fn validate(&self) -> Result<(), ResponseError> {
+        if self.code.detail.0 > 9 {
+            return Err(ResponseError::InvalidCode);
+        }
+        // Code, delimiter, text and CRLF.
+        if self.text.iter().any(|line| 3 + 1 + line.len() + 2 > MAX_LINE_LEN) {
+            return Err(ResponseError::LineTooLong);
+        }
+        Ok(())
+    }
+
+    /// Tells if this is the `354` reply to `DATA`, asking for the message body
+    pub fn is_start_input(&self) -> bool {
+        self.code.numeric() == codes::START_INPUT
+    }
+
+    /// Tells if this is the `221` reply, where the server closes the connection
+    pub fn is_closing(&self) -> bool {
+        self.code.numeric() == codes::SERVICE_CLOSING
+    }
+
+    /// Returns all lines of text joined by spaces, e.g. for logging
+    pub fn text(&self) -> String {
+        self.joined_text(" ")
+    }
+
+    /// Returns all lines of text joined by the given separator
+    pub fn joined_text(&self, separator: &str) -> String {
+        self.text.join(separator)
+    }
+
     /// Returns only the first word of the message if possible
     pub fn first_word(&self) -> Option<&str> {
         self.text.get(0).and_then(|line| line.split_whitespace().next())
     }
+
+    /// Returns the enhanced status code (RFC 2034), if the text starts with one
+    ///
+    /// Only a leading `class.subject.detail` word is taken as the code, so dots
+    /// elsewhere in the text are ignored.
+    pub fn enhanced_code(&self) -> Option<EnhancedCode> {
+        self.first_word().and_then(|word| word.parse().ok())
+    }
+
+    /// Returns the enhanced status code, requiring it if it was advertised
+    ///
+    /// If the server advertised `ENHANCEDSTATUSCODES`, see
+    /// `Capabilities::enhanced_status_codes`, every reply after `EHLO` should
+    /// carry one, and its absence is an error. Otherwise, this is the same as
+    /// `enhanced_code`. The greeting and `EHLO` response are exempt, and
+    /// should not be checked this way.
+    pub fn checked_enhanced_code(&self, advertised: bool) -> Result<Option<EnhancedCode>, ResponseError> {
+        match self.enhanced_code() {
+            None if advertised => Err(ResponseError::MissingEnhancedCode),
+            code => Ok(code),
+        }
+    }
+
+    /// Returns the server domain from a greeting or `EHLO` response
+    ///
+    /// This is the first word of the first line, which may be followed by
+    /// free-form greeting text.
+    pub fn ehlo_domain(&self) -> Option<&str> {
+        self.first_word()
+    }
+}
+
+
+/// A parsed response, with the exact bytes it was parsed from
+///
+/// This is useful for logging the reply as the server sent it, because the
+/// parsed form normalizes e.g. an empty last line, or whitespace.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct RawResponse {
+    pub response: Response,
+    /// The bytes as received, including line endings
+    pub raw: Vec<u8>,
+}
+
+
+/// Builds a `Response` line by line
+#[derive(Clone,Debug)]
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    /// Add a line of text
+    pub fn line<S: Into<String>>(mut self, text: S) -> Self {
+        self.response.text.push(text.into());
+        self
+    }
+
+    /// Finish the response, validating the code and line lengths
+    pub fn build(self) -> Result<Response, ResponseError> {
+        self.response.validate()?;
+        Ok(self.response)
+    }
 }
 
 impl FromStr for Response {
-    type Err = ();
+    type Err = ParseError;
 
-    fn from_str(s: &str) -> Result<Response, ()> {
+    fn from_str(s: &str) -> Result<Response, ParseError> {
         match Response::parse(s.as_bytes()) {
             NomResult::Done(_, res) => Ok(res),
-            _ => Err(()),
+            NomResult::Incomplete(_) => Err(ParseError::new(s.len(), ParseErrorKind::Incomplete)),
+            NomResult::Error(_) => Err(locate_error(s.as_bytes())),
         }
     }
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.text.is_empty() {
+            return write!(f, "{}\r\n", &self.code);
+        }
+
         let last_idx = self.text.len() - 1;
         for (i, line) in self.text.iter().enumerate() {
             let delim = if i == last_idx { ' ' } else { '-' };
-            writeln!(f, "{}{}{}\r", &self.code, delim, line)?
+            write!(f, "{}{}{}\r\n", &self.code, delim, line)?
         }
         Ok(())
     }
@@ -282,6 +536,28 @@ named!(parse_code<Code>,
     )
 );
 
+// The text of the last line is optional. This can't be expressed as `opt!`,
+// because that turns an incomplete line into an error.
+fn parse_last_text(input: &[u8]) -> NomResult<&[u8], Option<&[u8]>> {
+    if input.is_empty() {
+        return NomResult::Incomplete(Needed::Size(1));
+    }
+
+    if input[0] != b' ' {
+        return match crlf(input) {
+            NomResult::Done(rest, _) => NomResult::Done(rest, None),
+            NomResult::Incomplete(needed) => NomResult::Incomplete(needed),
+            NomResult::Error(err) => NomResult::Error(err),
+        };
+    }
+
+    let text = &input[1..];
+    match text.windows(2).position(|window| window == b"\r\n") {
+        Some(idx) => NomResult::Done(&text[idx + 2..], Some(&text[..idx])),
+        None => NomResult::Incomplete(Needed::Unknown),
+    }
+}
+
 named!(parse_response<Response>,
     map_res!(
         tuple!(
@@ -296,18 +572,7 @@ named!(parse_response<Response>,
                 )
             ),
             // Parse the final line.
-            tuple!(
-                parse_code,
-                terminated!(
-                    opt!(
-                        preceded!(
-                            char!(' '),
-                            take_until!(b"\r\n".as_ref())
-                        )
-                    ),
-                    crlf
-                )
-            )
+            tuple!(parse_code, parse_last_text)
         ),
         |(lines, (last_code, last_line)): (Vec<_>, _)| {
             // Check that all codes are equal.
@@ -323,21 +588,61 @@ named!(parse_response<Response>,
                 lines.push(text);
             }
 
-            Ok(Response {
-                code: last_code,
-                text: lines.into_iter()
-                    .map(|line| from_utf8(line).map(|s| s.to_string()))
-                    .collect::<Result<Vec<_>, _>>()
-                    .map_err(|_| ())?,
-            })
+            let text = lines.into_iter()
+                .map(|line| from_utf8(line).map(|s| s.to_string()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ())?;
+            Ok(Response { code: last_code, text })
         }
     )
 );
 
 
+// Find where parsing failed, after the parser reported an error.
+fn locate_error(input: &[u8]) -> ParseError {
+    let mut first_code = None;
+    let mut offset = 0;
+    while offset < input.len() {
+        let line = &input[offset..];
+
+        let code = match parse_code(line) {
+            NomResult::Done(_, code) => code,
+            NomResult::Incomplete(_) => return ParseError::new(input.len(), ParseErrorKind::Incomplete),
+            NomResult::Error(_) => {
+                let digit = if parse_severity(line).is_err() { 0 }
+                    else if parse_category(&line[1..]).is_err() { 1 }
+                    else { 2 };
+                return ParseError::new(offset + digit, ParseErrorKind::InvalidCode);
+            },
+        };
+        if *first_code.get_or_insert_with(|| code.clone()) != code {
+            return ParseError::new(offset, ParseErrorKind::MismatchedCode);
+        }
+
+        let text_start = match line.get(3) {
+            Some(&b'-') | Some(&b' ') => 4,
+            Some(&b'\r') => 3,
+            Some(_) => return ParseError::new(offset + 3, ParseErrorKind::InvalidSyntax),
+            None => return ParseError::new(input.len(), ParseErrorKind::Incomplete),
+        };
+        let text = &line[text_start..];
+        let text_len = match text.windows(2).position(|window| window == b"\r\n") {
+            Some(idx) => idx,
+            None => return ParseError::new(input.len(), ParseErrorKind::Incomplete),
+        };
+        offset += text_start + text_len + 2;
+    }
+    ParseError::new(0, ParseErrorKind::InvalidSyntax)
+}
+
+
 #[cfg(test)]
 mod tests {
-    use response::{Category, Code, Detail, Response, Severity};
+    use capabilities::{Capabilities};
+    use codes;
+    use error::{ParseError, ParseErrorKind};
+    use nom::{IResult as NomResult};
+    use response::{Category, Code, Detail, EnhancedCode, Response, ResponseError, Severity};
 
     #[test]
     fn test() {
@@ -391,8 +696,17 @@ mod tests {
             assert_eq!(rest.len(), 0);
             assert_eq!(sub, expect);
             assert_eq!(expect.to_string(), normalized);
+
+            let (_, raw) = Response::parse_raw(input.as_bytes()).unwrap();
+            assert_eq!(raw.response, expect);
+            assert_eq!(raw.raw, input.as_bytes());
         }
 
+        let input = b"250-mail.example.test  Hello \r\n250 OK\r\n354 Go ahead\r\n";
+        let (rest, raw) = Response::parse_raw(input).unwrap();
+        assert_eq!(raw.raw, &b"250-mail.example.test  Hello \r\n250 OK\r\n"[..]);
+        assert_eq!(rest, &b"354 Go ahead\r\n"[..]);
+
         for (word, input) in vec![
             (Some("me".as_ref()), vec!["me", "8BITMIME", "SIZE 42"]),
             (Some("me".as_ref()), vec!["me mo", "8BITMIME", "SIZE 42"]),
@@ -413,5 +727,205 @@ mod tests {
             };
             assert_eq!(sub.first_word(), word);
         }
+
+        for (input, expect) in vec![
+            ("250-mail.example.com Hello\r\n250 PIPELINING\r\n", Some("mail.example.com")),
+            ("220 mail.example.com ESMTP ready\r\n", Some("mail.example.com")),
+            ("250 mail.example.com\r\n", Some("mail.example.com")),
+            ("250\r\n", None),
+        ] {
+            assert_eq!(input.parse::<Response>().unwrap().ehlo_domain(), expect);
+        }
+
+        for (input, expect) in vec![
+            ("354 Start mail input\r\n", true),
+            ("503 Bad sequence of commands\r\n", false),
+            ("334 VXNlcm5hbWU6\r\n", false),
+        ] {
+            assert_eq!(input.parse::<Response>().unwrap().is_start_input(), expect);
+        }
+
+        for (input, expect) in vec![
+            ("250 2.1.0 Sender ok... done.\r\n", Some((2, 1, 0))),
+            ("550-5.7.1 Relaying denied.\r\n550 See policy.\r\n", Some((5, 7, 1))),
+            ("452 4.3.100 Out of space\r\n", Some((4, 3, 100))),
+            ("250 Sender ok... done.\r\n", None),
+            ("250 Ok. 2.1.0\r\n", None),
+            ("250 2.1.0.\r\n", None),
+            ("250 2.1.0.3 Too many parts\r\n", None),
+            ("250 2.1. Empty detail\r\n", None),
+            ("250 3.1.0 Bad class\r\n", None),
+            ("250 2.1000.0 Long subject\r\n", None),
+            ("250 ...\r\n", None),
+            ("250\r\n", None),
+        ] {
+            let code = input.parse::<Response>().unwrap().enhanced_code();
+            assert_eq!(code, expect.map(|(class, subject, detail)| EnhancedCode { class, subject, detail }));
+        }
+        assert_eq!(EnhancedCode { class: 4, subject: 3, detail: 100 }.to_string(), "4.3.100");
+
+        for (input, closing) in vec![
+            ("221 2.0.0 Bye\r\n", true),
+            ("221\r\n", true),
+            ("220 mail.example.test ESMTP\r\n", false),
+            ("421 Shutting down\r\n", false),
+            ("250 Ok\r\n", false),
+        ] {
+            assert_eq!(input.parse::<Response>().unwrap().is_closing(), closing);
+        }
+
+        let caps = Capabilities::from_response(
+            &"250-mail.example.test\r\n250 ENHANCEDSTATUSCODES\r\n".parse().unwrap());
+        assert!(caps.enhanced_status_codes());
+        let with_code = "250 2.1.0 Ok\r\n".parse::<Response>().unwrap();
+        let without_code = "250 Ok\r\n".parse::<Response>().unwrap();
+        assert_eq!(with_code.checked_enhanced_code(caps.enhanced_status_codes()),
+            Ok(Some(EnhancedCode { class: 2, subject: 1, detail: 0 })));
+        assert_eq!(without_code.checked_enhanced_code(caps.enhanced_status_codes()),
+            Err(ResponseError::MissingEnhancedCode));
+        assert_eq!(without_code.checked_enhanced_code(false), Ok(None));
+
+        let reply = "250-mail.example.test\r\n250-PIPELINING\r\n250 SIZE 1024\r\n"
+            .parse::<Response>().unwrap();
+        assert_eq!(reply.text(), "mail.example.test PIPELINING SIZE 1024");
+        assert_eq!(reply.joined_text("\n"), "mail.example.test\nPIPELINING\nSIZE 1024");
+        assert_eq!("250\r\n".parse::<Response>().unwrap().text(), "");
+
+        let mut banner = "220-backend.internal ESMTP\r\n220 Welcome\r\n".parse::<Response>().unwrap();
+        banner.rewrite_first_line("relay.example.test ESMTP");
+        assert_eq!(banner.to_string(), "220-relay.example.test ESMTP\r\n220 Welcome\r\n");
+        banner.rewrite_first_line("evil\r\n250 injected");
+        assert_eq!(banner.to_string(), "220-evil  250 injected\r\n220 Welcome\r\n");
+        let mut empty = "250\r\n".parse::<Response>().unwrap();
+        empty.rewrite_first_line("OK");
+        assert_eq!(empty.to_string(), "250 OK\r\n");
+
+        let rejected = "250 2.1.5 Ok\r\n".parse::<Response>().unwrap()
+            .with_code("550".parse().unwrap());
+        assert_eq!(rejected.to_string(), "550 2.1.5 Ok\r\n");
+
+        let ok = || "250".parse::<Code>().unwrap();
+        let response = Response::builder(ok())
+            .line("mail.example.test")
+            .line("PIPELINING")
+            .build()
+            .unwrap();
+        assert_eq!(response.to_string(), "250-mail.example.test\r\n250 PIPELINING\r\n");
+        assert_eq!(Response::builder(ok()).line("x".repeat(506)).build().map(|_| ()), Ok(()));
+        assert_eq!(Response::builder(ok()).line("OK").line("x".repeat(507)).build(),
+            Err(ResponseError::LineTooLong));
+
+        let response = Response::builder_numeric(250).unwrap().line("OK").build().unwrap();
+        assert_eq!(response.to_string(), "250 OK\r\n");
+        for code in vec![0, 99, 100, 199, 260, 600, 700] {
+            assert_eq!(Response::builder_numeric(code).err(), Some(ResponseError::InvalidCode));
+        }
+        // Undefined codes are in range, and only reported by `is_defined`.
+        assert!(Response::builder_numeric(259).unwrap().build().is_ok());
+        assert!(!codes::is_defined(259));
+        let code = Code {
+            severity: Severity::PositiveCompletion,
+            category: Category::MailSystem,
+            detail: Detail(10),
+        };
+        assert_eq!(Response::builder(code).line("OK").build(), Err(ResponseError::InvalidCode));
+
+        for (input, offset, kind) in vec![
+            ("2x0 OK\r\n", 1, ParseErrorKind::InvalidCode),
+            ("250-First\r\n25a Second\r\n", 13, ParseErrorKind::InvalidCode),
+            ("250-First\r\n251 Second\r\n", 11, ParseErrorKind::MismatchedCode),
+            ("250+First\r\n", 3, ParseErrorKind::InvalidSyntax),
+            ("250-First\r\n", 11, ParseErrorKind::Incomplete),
+            ("250 OK", 6, ParseErrorKind::Incomplete),
+        ] {
+            assert_eq!(input.parse::<Response>(), Err(ParseError::new(offset, kind)));
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let code = Code::from_numeric(250).unwrap();
+        assert_eq!(Response::new(code.clone(), vec![]).to_string().into_bytes(), b"250\r\n".to_vec());
+        let response = Response::new(code, vec!["mail.example.test".to_string(), "PIPELINING".to_string()]);
+        assert_eq!(response.to_string().into_bytes(), b"250-mail.example.test\r\n250 PIPELINING\r\n".to_vec());
+    }
+
+    /// Feeds random and mutated input to the parser, which must not panic.
+    ///
+    /// Any successfully parsed response must also survive a round trip.
+    #[test]
+    fn test_fuzz() {
+        const ALPHABET: &'static [u8] = b"0123456789  --\r\n\r\nabcXYZ.\xff";
+
+        // Simple xorshift, so runs are reproducible.
+        let mut state: u32 = 0x2545_f491;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+
+        let seeds: Vec<&[u8]> = vec![
+            b"250 OK\r\n",
+            b"250-mail.example.test\r\n250-PIPELINING\r\n250 SIZE 1024\r\n",
+            b"354 Start mail input\r\n",
+            b"221\r\n",
+        ];
+
+        for i in 0..20_000 {
+            let input = if i % 2 == 0 {
+                let len = next() % 64;
+                (0..len).map(|_| ALPHABET[next() % ALPHABET.len()]).collect::<Vec<_>>()
+            } else {
+                let mut input = seeds[next() % seeds.len()].to_vec();
+                for _ in 0..(next() % 4 + 1) {
+                    let idx = next() % input.len();
+                    match next() % 3 {
+                        0 => input[idx] = ALPHABET[next() % ALPHABET.len()],
+                        1 => { input.remove(idx); },
+                        _ => input.truncate(idx),
+                    }
+                    if input.is_empty() {
+                        break;
+                    }
+                }
+                input
+            };
+
+            if let NomResult::Done(_, res) = Response::parse(&input) {
+                let normalized = res.to_string();
+                match Response::parse(normalized.as_bytes()) {
+                    NomResult::Done(rest, reparsed) => {
+                        assert_eq!(rest.len(), 0);
+                        assert_eq!(reparsed, res);
+                    },
+                    _ => panic!("round trip failed for {:?}", input),
+                }
+            }
+        }
+
+        // Malformed input is an error.
+        for input in vec![
+            b"OK\r\n".as_ref(),
+            b"2\r\n",
+            b"600 Bad severity\r\n",
+            b"250x\r\n",
+            b"250-First line\r\n251 Second line\r\n",
+            b"250 Invalid \xff\r\n",
+        ] {
+            match Response::parse(input) {
+                NomResult::Error(_) => {},
+                other => panic!("expected error for {:?}, got {:?}", input, other),
+            }
+        }
+
+        // Truncated input needs more data.
+        for input in vec!["", "25", "250 OK", "250 OK\r", "250-First line\r\n25"] {
+            match Response::parse(input.as_bytes()) {
+                NomResult::Incomplete(_) => {},
+                other => panic!("expected incomplete for {:?}, got {:?}", input, other),
+            }
+        }
     }
 }
@@ -0,0 +1,590 @@
+//! Building blocks for SMTP servers
+//!
+//! This module does not implement a complete server, but provides the pieces
+//! a server uses to process client requests.
+
+use bytes::{BufMut, BytesMut};
+use codes;
+use request::{MailParam, ParseOptions, Request};
+use response::{Code, Response};
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::str::{from_utf8};
+use tokio_io::codec::{Decoder, Encoder};
+use tokio_proto::streaming::pipeline::{Frame};
+
+
+/// Rejects commands that are not in a set of permitted verbs
+///
+/// A server checks each request against the filter before processing it, and
+/// replies with the rejection if there is one. This makes it easy to disable
+/// commands like `VRFY` and `EXPN`.
+#[derive(Clone,Debug)]
+pub struct CommandFilter {
+    allowed: Vec<String>,
+}
+
+impl CommandFilter {
+    /// Create a filter permitting the given verbs, which are case-insensitive
+    pub fn new(allowed: &[&str]) -> Self {
+        CommandFilter {
+            allowed: allowed.iter().map(|verb| verb.to_ascii_uppercase()).collect(),
+        }
+    }
+
+    /// Tells if the request is permitted
+    pub fn allows(&self, request: &Request) -> bool {
+        self.allowed.iter().any(|verb| verb == request.verb())
+    }
+
+    /// Check a request, returning the `502` reply if it is not permitted
+    pub fn check(&self, request: &Request) -> Result<(), Response> {
+        if self.allows(request) {
+            Ok(())
+        } else {
+            Err(Response::builder(Code::from_numeric(codes::COMMAND_NOT_IMPLEMENTED).unwrap())
+                .line("Command not implemented")
+                .build()
+                .unwrap())
+        }
+    }
+}
+
+
+/// A command was pipelined after one that must be the last in a group
+///
+/// The `ServerCodec` decodes this as the `InvalidData` error of an error
+/// frame, in place of the offending command, when pipelining is checked. Use
+/// `IoError::get_ref` and `downcast_ref` to tell it apart from parse errors.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct PipeliningError {
+    /// The verb of the command that must be the last in a group
+    pub verb: &'static str,
+}
+
+impl PipeliningError {
+    /// The `503` reply for a server to send
+    pub fn response(&self) -> Response {
+        Response::builder(Code::from_numeric(codes::BAD_SEQUENCE).unwrap())
+            .line("Bad sequence of commands")
+            .build()
+            .unwrap()
+    }
+}
+
+impl Display for PipeliningError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} must be the last command in a group", self.verb)
+    }
+}
+
+impl StdError for PipeliningError {
+    fn description(&self) -> &str {
+        "command pipelined after a command that must be the last in a group"
+    }
+}
+
+
+/// A message body is larger than the limit set with `set_max_size`
+///
+/// Like `PipeliningError`, the `ServerCodec` decodes this as the error of an
+/// error frame, which ends the body.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct SizeExceeded {
+    /// The limit on the size of the body, in octets
+    pub max_size: usize,
+}
+
+impl SizeExceeded {
+    /// The `552` reply for a server to send
+    pub fn response(&self) -> Response {
+        Response::builder(Code::from_numeric(codes::STORAGE_EXCEEDED).unwrap())
+            .line("5.3.4 Message size exceeds fixed maximum message size")
+            .build()
+            .unwrap()
+    }
+}
+
+impl Display for SizeExceeded {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "message body exceeds {} octets", self.max_size)
+    }
+}
+
+impl StdError for SizeExceeded {
+    fn description(&self) -> &str {
+        "message body exceeds the maximum size"
+    }
+}
+
+
+/// What the `ServerCodec` expects the client to send next
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+enum ServerState {
+    /// A command line
+    Command,
+    /// A line in response to an `AUTH` challenge
+    AuthResponse,
+    /// `DATA` was received, and the server has not replied yet
+    AwaitingDataReply,
+    /// The message body after `354`, up to the final `.` line
+    Data,
+    /// The rest of a body that is too large, which is read and dropped
+    DiscardData,
+    /// The given number of octets of a `BDAT` chunk
+    Bdat(usize),
+    /// The end of the current body
+    BodyEnd,
+}
+
+
+/// The codec used to decode client requests and encode server responses
+///
+/// This mirrors `ClientCodec`. Requests are decoded from command lines, and
+/// `DATA` and `BDAT` are followed by their message body, as a single chunk
+/// and the end of the body. The body of `DATA` can be limited in size, see
+/// `set_max_size`.
+///
+/// After `DATA`, no further frames are decoded until the server replies. If
+/// the reply is `354`, the body is read up to the final `.` line, and has dot
+/// stuffing removed. Otherwise, the body ends immediately, and the client is
+/// expected to continue with a command.
+///
+/// After a `334` reply, the next line is decoded as the client's response to
+/// the `AUTH` challenge, see `Request::parse_auth_continuation`.
+///
+/// A command line that cannot be parsed is decoded as an error frame, so the
+/// server can reply and continue.
+///
+/// Optionally, the codec also checks that `EHLO`, `LHLO`, `STARTTLS` and
+/// `DATA` are not followed by more input before their reply, see
+/// `set_check_pipelining`.
+pub struct ServerCodec {
+    state: ServerState,
+    opts: ParseOptions,
+    check_pipelining: bool,
+    max_size: Option<usize>,
+}
+
+impl Default for ServerCodec {
+    fn default() -> Self {
+        ServerCodec {
+            state: ServerState::Command,
+            opts: ParseOptions::default(),
+            check_pipelining: false,
+            max_size: None,
+        }
+    }
+}
+
+impl ServerCodec {
+    pub fn new() -> Self {
+        ServerCodec::default()
+    }
+
+    /// Set whether to reject commands that must be the last in a group, if
+    /// they are followed by more input
+    ///
+    /// Such a command is then decoded as a `PipeliningError`, and the input
+    /// after it is discarded. This is off by default.
+    pub fn set_check_pipelining(&mut self, check: bool) {
+        self.check_pipelining = check;
+    }
+
+    /// Limit the size of a `DATA` body, in octets, after removing dot stuffing
+    ///
+    /// A larger body is read up to the final `.` line without being kept, so
+    /// a client cannot fill up the buffer. The body then ends with a
+    /// `SizeExceeded` error, and the server should reply `552`. Set this to
+    /// the `SIZE` the server advertised. There is no limit by default.
+    pub fn set_max_size(&mut self, max: usize) {
+        self.max_size = Some(max);
+    }
+
+    fn decode_command(&mut self, buf: &mut BytesMut) -> Option<Frame<Request, Vec<u8>, IoError>> {
+        let len = match buf.windows(2).position(|window| window == b"\r\n") {
+            Some(pos) => pos + 2,
+            None => return None,
+        };
+        let line = buf.split_to(len);
+
+        let auth_response = self.state == ServerState::AuthResponse;
+        self.state = ServerState::Command;
+        let request = from_utf8(&line)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "request is not valid UTF-8"))
+            .and_then(|line| {
+                let request = if auth_response {
+                    Request::parse_auth_continuation(line)
+                } else {
+                    Request::parse_with(line, &self.opts)
+                };
+                request.map_err(|err| IoError::new(IoErrorKind::InvalidData, err))
+            });
+        let request = match request {
+            Ok(request) => request,
+            Err(error) => return Some(Frame::Error { error }),
+        };
+        if self.check_pipelining && request.ends_pipeline_group() && !buf.is_empty() {
+            buf.clear();
+            let error = PipeliningError { verb: request.verb() };
+            return Some(Frame::Error { error: IoError::new(IoErrorKind::InvalidData, error) });
+        }
+
+        let body = match request {
+            Request::Mail { ref params, .. } => {
+                self.opts.smtputf8 = params.contains(&MailParam::SmtpUtf8);
+                false
+            },
+            Request::Rset => {
+                self.opts.smtputf8 = false;
+                false
+            },
+            Request::Data => {
+                self.state = ServerState::AwaitingDataReply;
+                true
+            },
+            Request::Bdat { size, .. } => {
+                self.state = ServerState::Bdat(size);
+                true
+            },
+            _ => false,
+        };
+        debug!("C: {:?}", &request);
+        Some(Frame::Message { message: request, body })
+    }
+
+    fn decode_data(&mut self, buf: &mut BytesMut) -> Option<Frame<Request, Vec<u8>, IoError>> {
+        // The CRLF before the final dot is part of the body, unless it is
+        // empty.
+        let len = if buf.starts_with(b".\r\n") {
+            0
+        } else {
+            match buf.windows(5).position(|window| window == b"\r\n.\r\n") {
+                Some(pos) => pos + 2,
+                // The final CRLF and dot may still be in the buffer, and the
+                // CRLF is part of the body.
+                None if self.max_size.map_or(false, |max| unstuffed_len(buf) > max + 3) => {
+                    self.state = ServerState::DiscardData;
+                    return self.discard_data(buf);
+                },
+                None => return None,
+            }
+        };
+        let stuffed = buf.split_to(len + 3);
+
+        // Remove the extra dot from lines starting with a dot.
+        let mut chunk = Vec::with_capacity(len);
+        let mut line_start = true;
+        for &byte in &stuffed[..len] {
+            if !(line_start && byte == b'.') {
+                chunk.push(byte);
+            }
+            line_start = byte == b'\n';
+        }
+        if let Some(max_size) = self.max_size.filter(|&max| chunk.len() > max) {
+            self.state = ServerState::Command;
+            let error = IoError::new(IoErrorKind::InvalidData, SizeExceeded { max_size });
+            return Some(Frame::Error { error });
+        }
+        self.state = ServerState::BodyEnd;
+        Some(Frame::Body { chunk: Some(chunk) })
+    }
+
+    fn discard_data(&mut self, buf: &mut BytesMut) -> Option<Frame<Request, Vec<u8>, IoError>> {
+        match buf.windows(5).position(|window| window == b"\r\n.\r\n") {
+            Some(pos) => {
+                buf.split_to(pos + 5);
+                self.state = ServerState::Command;
+                let error = SizeExceeded { max_size: self.max_size.unwrap_or(0) };
+                Some(Frame::Error { error: IoError::new(IoErrorKind::InvalidData, error) })
+            },
+            None => {
+                // Keep what may be the start of the final dot line.
+                let len = buf.len().saturating_sub(4);
+                buf.split_to(len);
+                None
+            },
+        }
+    }
+}
+
+/// The length of dot stuffed body data, once the extra dots are removed
+fn unstuffed_len(stuffed: &[u8]) -> usize {
+    let mut dots = if stuffed.starts_with(b".") { 1 } else { 0 };
+    dots += stuffed.windows(2).filter(|window| window == b"\n.").count();
+    stuffed.len() - dots
+}
+
+impl Decoder for ServerCodec {
+    type Item = Frame<Request, Vec<u8>, IoError>;
+    type Error = IoError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> IoResult<Option<Self::Item>> {
+        Ok(match self.state {
+            ServerState::Command | ServerState::AuthResponse => self.decode_command(buf),
+            ServerState::AwaitingDataReply => None,
+            ServerState::Data => self.decode_data(buf),
+            ServerState::DiscardData => self.discard_data(buf),
+            ServerState::Bdat(size) if buf.len() >= size => {
+                self.state = ServerState::BodyEnd;
+                Some(Frame::Body { chunk: Some(buf.split_to(size).to_vec()) })
+            },
+            ServerState::Bdat(_) => None,
+            ServerState::BodyEnd => {
+                self.state = ServerState::Command;
+                Some(Frame::Body { chunk: None })
+            },
+        })
+    }
+}
+
+impl Encoder for ServerCodec {
+    type Item = Frame<Response, (), IoError>;
+    type Error = IoError;
+
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> IoResult<()> {
+        debug!("S: {:?}", &frame);
+        match frame {
+            Frame::Message { message, .. } => {
+                match self.state {
+                    ServerState::AwaitingDataReply => {
+                        self.state = if message.is_start_input() {
+                            ServerState::Data
+                        } else {
+                            ServerState::BodyEnd
+                        };
+                    },
+                    ServerState::Command if message.code.numeric() == codes::AUTH_CONTINUE => {
+                        self.state = ServerState::AuthResponse;
+                    },
+                    _ => {},
+                }
+                let bytes = message.to_string().into_bytes();
+                buf.reserve(bytes.len());
+                buf.put_slice(&bytes);
+                Ok(())
+            },
+            Frame::Body { .. } => Ok(()),
+            Frame::Error { error } => Err(error),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BytesMut};
+    use request::{Request};
+    use server::{CommandFilter, PipeliningError, ServerCodec, SizeExceeded};
+    use tokio_io::codec::{Decoder, Encoder};
+    use tokio_proto::streaming::pipeline::{Frame};
+
+    #[test]
+    fn test() {
+        let filter = CommandFilter::new(&["ehlo", "MAIL", "Rcpt", "DATA", "QUIT"]);
+        let mail = Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] };
+        assert_eq!(filter.check(&mail), Ok(()));
+        assert_eq!(filter.check(&Request::Quit), Ok(()));
+
+        for request in vec![Request::Vrfy("john".to_string()), Request::Expn("staff".to_string())] {
+            assert!(!filter.allows(&request));
+            let reply = filter.check(&request).unwrap_err();
+            assert_eq!(reply.to_string(), "502 Command not implemented\r\n");
+        }
+    }
+
+    /// Decode all complete frames, described as strings for comparison
+    fn decode_all(codec: &mut ServerCodec, buf: &mut BytesMut) -> Vec<String> {
+        let mut frames = vec![];
+        while let Some(frame) = codec.decode(buf).unwrap() {
+            frames.push(match frame {
+                Frame::Message { message, body: false } => message.to_string(),
+                Frame::Message { message, body: true } => format!("{} +body", message),
+                Frame::Body { chunk: Some(chunk) } => String::from_utf8(chunk).unwrap(),
+                Frame::Body { chunk: None } => "end".to_string(),
+                Frame::Error { error } => format!("error: {}", error),
+            });
+        }
+        frames
+    }
+
+    fn reply(codec: &mut ServerCodec, response: &str) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(Frame::Message { message: response.parse().unwrap(), body: false }, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_codec() {
+        let mut codec = ServerCodec::new();
+        let mut buf = BytesMut::from(b"MAIL FROM:<john@example.test>\r\nRCPT TO:<alice@example.test>\r\nDATA\r\n\
+            Hello\r\n..World\r\n.\r\nQUIT\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "MAIL FROM:<john@example.test>\r\n",
+            "RCPT TO:<alice@example.test>\r\n",
+            "DATA\r\n +body",
+        ]);
+
+        // The body is only read after the `354` reply.
+        assert_eq!(&reply(&mut codec, "354 Start mail input\r\n")[..], b"354 Start mail input\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "Hello\r\n.World\r\n",
+            "end",
+            "QUIT\r\n",
+        ]);
+        assert!(buf.is_empty());
+
+        // A body arriving in pieces.
+        let mut buf = BytesMut::from(b"DATA\r\n.Hello\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["DATA\r\n +body"]);
+        reply(&mut codec, "354 Go ahead\r\n");
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        buf.extend_from_slice(b".\r");
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        buf.extend_from_slice(b"\nRSET\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "Hello\r\n",
+            "end",
+            "RSET\r\n",
+        ]);
+
+        // An empty body.
+        let mut buf = BytesMut::from(b"DATA\r\n".as_ref());
+        decode_all(&mut codec, &mut buf);
+        reply(&mut codec, "354 Go ahead\r\n");
+        buf.extend_from_slice(b".\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "",
+            "end",
+        ]);
+        let mut buf = BytesMut::from(b"DATA\r\n.\r\nQUIT\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["DATA\r\n +body"]);
+        reply(&mut codec, "354 Go ahead\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "",
+            "end",
+            "QUIT\r\n",
+        ]);
+
+        // A rejected `DATA` has no body.
+        let mut buf = BytesMut::from(b"DATA\r\nQUIT\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["DATA\r\n +body"]);
+        reply(&mut codec, "554 No valid recipients\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "end",
+            "QUIT\r\n",
+        ]);
+
+        // `BDAT` chunks are read as is.
+        let mut buf = BytesMut::from(b"BDAT 8 LAST\r\n.Hello\r".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["BDAT 8 LAST\r\n +body"]);
+        buf.extend_from_slice(b"\nQUIT\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            ".Hello\r\n",
+            "end",
+            "QUIT\r\n",
+        ]);
+
+        // Lines after a `334` challenge are `AUTH` responses.
+        let mut buf = BytesMut::from(b"AUTH LOGIN\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["AUTH LOGIN\r\n"]);
+        reply(&mut codec, "334 VXNlcm5hbWU6\r\n");
+        buf.extend_from_slice(b"am9obg==\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["am9obg==\r\n"]);
+        reply(&mut codec, "334 UGFzc3dvcmQ6\r\n");
+        buf.extend_from_slice(b"*\r\nQUIT\r\n");
+        let frames = decode_all(&mut codec, &mut buf);
+        assert_eq!(frames, vec!["*\r\n", "QUIT\r\n"]);
+        reply(&mut codec, "334 \r\n");
+        buf.extend_from_slice(b"not base64\r\nRSET\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "error: syntax error at offset 3",
+            "RSET\r\n",
+        ]);
+
+        // Bad commands don't end decoding.
+        let mut buf = BytesMut::from(b"HELP\r\nQUIT\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "error: unknown command at offset 0",
+            "QUIT\r\n",
+        ]);
+    }
+
+    #[test]
+    fn test_check_pipelining() {
+        let mut codec = ServerCodec::new();
+        codec.set_check_pipelining(true);
+
+        let mut buf = BytesMut::from(b"DATA\r\nMAIL FROM:<john@example.test>\r\n".as_ref());
+        let error = match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Error { error }) => error,
+            _ => panic!("expected an error frame"),
+        };
+        let error = error.get_ref().and_then(|error| error.downcast_ref::<PipeliningError>()).unwrap();
+        assert_eq!(error, &PipeliningError { verb: "DATA" });
+        assert_eq!(error.response().to_string(), "503 Bad sequence of commands\r\n");
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        let mut buf = BytesMut::from(b"EHLO client.example.test\r\nM".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "error: EHLO must be the last command in a group",
+        ]);
+
+        // Other commands may be pipelined, and a group may end with `DATA`.
+        let mut buf = BytesMut::from(b"MAIL FROM:<john@example.test>\r\nRCPT TO:<alice@example.test>\r\n\
+            DATA\r\n".as_ref());
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "MAIL FROM:<john@example.test>\r\n",
+            "RCPT TO:<alice@example.test>\r\n",
+            "DATA\r\n +body",
+        ]);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut codec = ServerCodec::new();
+        codec.set_max_size(10);
+
+        // A body within the limit, with dot stuffing removed.
+        let mut buf = BytesMut::from(b"DATA\r\n".as_ref());
+        decode_all(&mut codec, &mut buf);
+        reply(&mut codec, "354 Go ahead\r\n");
+        buf.extend_from_slice(b"..Hello\r\n.\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![".Hello\r\n", "end"]);
+
+        // A complete body over the limit.
+        let mut buf = BytesMut::from(b"DATA\r\n".as_ref());
+        decode_all(&mut codec, &mut buf);
+        reply(&mut codec, "354 Go ahead\r\n");
+        buf.extend_from_slice(b"Hello World\r\n.\r\nQUIT\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![
+            "error: message body exceeds 10 octets",
+            "QUIT\r\n",
+        ]);
+
+        // A body streamed over the limit is dropped as it arrives.
+        let mut buf = BytesMut::from(b"DATA\r\n".as_ref());
+        decode_all(&mut codec, &mut buf);
+        reply(&mut codec, "354 Go ahead\r\n");
+        buf.extend_from_slice(b"Hello\r\nWorld\r\n");
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        assert_eq!(buf.len(), 4);
+        buf.extend_from_slice(b".Hello again\r\n.");
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        buf.extend_from_slice(b"\r\nRSET\r\n");
+        let mut error = None;
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            if let Frame::Error { error: err } = frame {
+                error = err.get_ref().and_then(|err| err.downcast_ref::<SizeExceeded>()).cloned();
+                break;
+            }
+        }
+        let error = error.unwrap();
+        assert_eq!(error, SizeExceeded { max_size: 10 });
+        assert_eq!(error.response().to_string(),
+            "552 5.3.4 Message size exceeds fixed maximum message size\r\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["RSET\r\n"]);
+    }
+}
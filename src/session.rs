@@ -0,0 +1,430 @@
+//! State tracking for an SMTP session
+//!
+//! The `SmtpSession` type keeps track of what has been negotiated on a
+//! connection, and of the current mail transaction. It does not perform any
+//! I/O itself, but is consulted and updated by code that does.
+
+use capabilities::{Capabilities};
+use codes;
+use request::{Mailbox, MailParam, ParseOptions, Request};
+use response::{Code, Response};
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+
+/// Errors from checking a request against the session state
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum PolicyError {
+    /// `MAIL FROM` has an `AUTH` parameter, but the client did not authenticate
+    AuthParamWithoutAuthentication,
+    /// `RCPT TO` would exceed the maximum number of recipients
+    TooManyRecipients,
+    /// An address has non-ASCII characters, but `SMTPUTF8` was not requested
+    NonAsciiAddress,
+    /// The client sent more than the maximum number of commands
+    TooManyCommands,
+}
+
+impl PolicyError {
+    /// The reply for a server to send when rejecting the request
+    pub fn response(&self) -> Response {
+        let (code, text) = match *self {
+            PolicyError::AuthParamWithoutAuthentication =>
+                (codes::AUTH_REQUIRED, "Authentication required"),
+            PolicyError::TooManyRecipients =>
+                (codes::INSUFFICIENT_STORAGE, "Too many recipients"),
+            PolicyError::NonAsciiAddress =>
+                (codes::MAILBOX_UNAVAILABLE, "5.6.7 Non-ASCII addresses require SMTPUTF8"),
+            PolicyError::TooManyCommands =>
+                (codes::SERVICE_UNAVAILABLE, "Too many commands, closing connection"),
+        };
+        Response::new(Code::from_numeric(code).expect("reply code is valid"), vec![text.to_string()])
+    }
+}
+
+impl Display for PolicyError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for PolicyError {
+    fn description(&self) -> &str {
+        match *self {
+            PolicyError::AuthParamWithoutAuthentication =>
+                "AUTH parameter requires an authenticated session",
+            PolicyError::TooManyRecipients => "too many recipients",
+            PolicyError::NonAsciiAddress => "non-ASCII address without SMTPUTF8",
+            PolicyError::TooManyCommands => "too many commands",
+        }
+    }
+}
+
+
+/// The state of a single SMTP session
+#[derive(Clone,Debug,Default)]
+pub struct SmtpSession {
+    capabilities: Option<Capabilities>,
+    secure: bool,
+    authenticated: bool,
+    sender: Option<Mailbox>,
+    recipients: Vec<Mailbox>,
+    smtputf8: bool,
+    max_recipients: Option<usize>,
+    commands: usize,
+    max_commands: Option<usize>,
+}
+
+impl SmtpSession {
+    /// Create a session for a new connection
+    pub fn new() -> Self {
+        SmtpSession::default()
+    }
+
+    /// Record the server's response to `EHLO`
+    ///
+    /// This also aborts any transaction in progress.
+    pub fn ehlo(&mut self, response: &Response) {
+        self.capabilities = Some(Capabilities::from_response(response));
+        self.clear_transaction();
+    }
+
+    /// The capabilities advertised in response to `EHLO`
+    ///
+    /// This is `None` until `EHLO` has been completed.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Tells if commands can be pipelined on this session
+    ///
+    /// This requires `EHLO` to have completed, and the server to have
+    /// advertised `PIPELINING`.
+    pub fn can_pipeline(&self) -> bool {
+        self.capabilities.as_ref().map_or(false, |caps| caps.pipelining())
+    }
+
+    /// Tells if the server supports `8BITMIME` (RFC 6152)
+    ///
+    /// Without it, `BODY=8BITMIME` may not be sent, and the message body must
+    /// be 7-bit. This is `false` until `EHLO` has been completed.
+    pub fn eight_bit_mime(&self) -> bool {
+        self.capabilities.as_ref().map_or(false, |caps| caps.eight_bit_mime())
+    }
+
+    /// Mark the connection as secured using TLS
+    pub fn set_secure(&mut self) {
+        self.secure = true;
+    }
+
+    /// Tells if the connection is secured using TLS
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Mark the client as authenticated
+    pub fn set_authenticated(&mut self) {
+        self.authenticated = true;
+    }
+
+    /// Tells if the client has authenticated
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Limit the number of recipients per transaction
+    ///
+    /// Servers must accept at least 100 recipients (RFC 5321, section
+    /// 4.5.3.1.8), so lower limits are only suitable for e.g. submission
+    /// policies. There is no limit by default.
+    pub fn set_max_recipients(&mut self, max: usize) {
+        self.max_recipients = Some(max);
+    }
+
+    /// The maximum number of recipients per transaction, if limited
+    pub fn max_recipients(&self) -> Option<usize> {
+        self.max_recipients
+    }
+
+    /// Limit the number of commands on the connection
+    ///
+    /// Once the client has sent this many commands, `count_command` fails, and
+    /// the server should reply `421` and close the connection. There is no
+    /// limit by default.
+    pub fn with_max_commands(mut self, max: usize) -> Self {
+        self.max_commands = Some(max);
+        self
+    }
+
+    /// The maximum number of commands on the connection, if limited
+    pub fn max_commands(&self) -> Option<usize> {
+        self.max_commands
+    }
+
+    /// The number of commands counted so far
+    pub fn commands(&self) -> usize {
+        self.commands
+    }
+
+    /// Count a command received from the client
+    ///
+    /// This is called for every parsed command, whether or not it is then
+    /// accepted, and fails once the count exceeds the limit set with
+    /// `with_max_commands`. The count is kept across transactions.
+    pub fn count_command(&mut self) -> Result<(), PolicyError> {
+        self.commands += 1;
+        if self.max_commands.map_or(false, |max| self.commands > max) {
+            return Err(PolicyError::TooManyCommands);
+        }
+        Ok(())
+    }
+
+    /// Check that a request is allowed in the current state of the session
+    ///
+    /// The `AUTH` parameter of `MAIL FROM` names the identity that submitted
+    /// the message, and is only allowed once the client has authenticated.
+    /// A `RCPT TO` is rejected once the transaction has the maximum number of
+    /// recipients, see `set_max_recipients`.
+    ///
+    /// Addresses with non-ASCII characters are only allowed in a transaction
+    /// started with the `SMTPUTF8` parameter (RFC 6531). Requests parsed with
+    /// `parse_options` are already checked this way, but requests from other
+    /// sources may not be.
+    pub fn check(&self, request: &Request) -> Result<(), PolicyError> {
+        match *request {
+            Request::Mail { ref from, ref params } => {
+                if from.needs_smtputf8() && !params.contains(&MailParam::SmtpUtf8) {
+                    return Err(PolicyError::NonAsciiAddress);
+                }
+                let has_auth = params.iter().any(|param| match *param {
+                    MailParam::Auth(_) => true,
+                    _ => false,
+                });
+                if has_auth && !self.authenticated {
+                    return Err(PolicyError::AuthParamWithoutAuthentication);
+                }
+            },
+            Request::Rcpt { ref to, .. } => {
+                if to.needs_smtputf8() && !self.smtputf8 {
+                    return Err(PolicyError::NonAsciiAddress);
+                }
+                if self.max_recipients.map_or(false, |max| self.recipients.len() >= max) {
+                    return Err(PolicyError::TooManyRecipients);
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Update the current transaction for a request that was accepted
+    pub fn record(&mut self, request: &Request) {
+        match *request {
+            Request::Mail { ref from, ref params } => {
+                self.clear_transaction();
+                self.sender = Some(from.clone());
+                self.smtputf8 = params.contains(&MailParam::SmtpUtf8);
+            },
+            Request::Rcpt { ref to, .. } => {
+                self.recipients.push(to.clone());
+            },
+            Request::Rset => {
+                self.clear_transaction();
+            },
+            _ => {},
+        }
+    }
+
+    /// The return path of the current transaction
+    pub fn sender(&self) -> Option<&Mailbox> {
+        self.sender.as_ref()
+    }
+
+    /// The recipients accepted in the current transaction
+    pub fn recipients(&self) -> &[Mailbox] {
+        &self.recipients
+    }
+
+    /// Tells if the current transaction was started with `SMTPUTF8`
+    pub fn smtputf8(&self) -> bool {
+        self.smtputf8
+    }
+
+    /// The options for parsing requests in the current transaction
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions { smtputf8: self.smtputf8 }
+    }
+
+    /// Abort the current transaction
+    ///
+    /// This clears the transaction state, and returns the `RSET` request to
+    /// send. As with `RSET` itself, the rest of the session state is preserved.
+    pub fn reset(&mut self) -> Request {
+        self.clear_transaction();
+        Request::Rset
+    }
+
+    fn clear_transaction(&mut self) {
+        self.sender = None;
+        self.recipients.clear();
+        self.smtputf8 = false;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use request::{Mailbox, MailParam, Request};
+    use session::{PolicyError, SmtpSession};
+
+    #[test]
+    fn test() {
+        let mut session = SmtpSession::new();
+        assert!(session.capabilities().is_none());
+        assert!(!session.can_pipeline());
+
+        session.ehlo(&"250-mail.example.test\r\n250 SIZE 1024\r\n".parse().unwrap());
+        assert!(session.capabilities().is_some());
+        assert!(!session.can_pipeline());
+
+        session.ehlo(&"250-mail.example.test\r\n250 PIPELINING\r\n".parse().unwrap());
+        assert!(session.can_pipeline());
+        assert!(!session.eight_bit_mime());
+
+        session.ehlo(&"250-mail.example.test\r\n250 8BITMIME\r\n".parse().unwrap());
+        assert!(session.eight_bit_mime());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut session = SmtpSession::new();
+        session.ehlo(&"250-mail.example.test\r\n250 PIPELINING\r\n".parse().unwrap());
+        session.set_secure();
+        session.set_authenticated();
+
+        let from: Mailbox = "john@example.test".parse().unwrap();
+        let to: Mailbox = "alice@example.test".parse().unwrap();
+        session.record(&Request::Mail { from: from.clone(), params: vec![] });
+        session.record(&Request::Rcpt { to: to.clone(), params: vec![] });
+        assert_eq!(session.sender(), Some(&from));
+        assert_eq!(session.recipients(), &[to]);
+
+        assert_eq!(session.reset(), Request::Rset);
+        assert_eq!(session.sender(), None);
+        assert!(session.recipients().is_empty());
+        assert!(session.can_pipeline());
+        assert!(session.is_secure());
+        assert!(session.is_authenticated());
+    }
+
+    #[test]
+    fn test_smtputf8() {
+        let mut session = SmtpSession::new();
+        let rcpt = "RCPT TO:<jøhn@exämple.test>\r\n";
+        assert!(Request::parse_with(rcpt, &session.parse_options()).is_err());
+
+        session.record(&"MAIL FROM:<> SMTPUTF8\r\n".parse().unwrap());
+        assert!(session.smtputf8());
+        assert!(Request::parse_with(rcpt, &session.parse_options()).is_ok());
+
+        session.reset();
+        assert!(!session.smtputf8());
+        assert!(Request::parse_with(rcpt, &session.parse_options()).is_err());
+    }
+
+    #[test]
+    fn test_max_commands() {
+        let mut session = SmtpSession::new();
+        assert_eq!(session.max_commands(), None);
+        for _ in 0..1000 {
+            assert_eq!(session.count_command(), Ok(()));
+        }
+
+        let mut session = SmtpSession::new().with_max_commands(3);
+        assert_eq!(session.max_commands(), Some(3));
+        for _ in 0..3 {
+            assert_eq!(session.count_command(), Ok(()));
+        }
+        session.reset();
+        assert_eq!(session.count_command(), Err(PolicyError::TooManyCommands));
+        assert_eq!(session.commands(), 4);
+        assert_eq!(PolicyError::TooManyCommands.response().to_string(),
+            "421 Too many commands, closing connection\r\n");
+    }
+
+    #[test]
+    fn test_check_smtputf8() {
+        let rcpt = Request::Rcpt { to: "jøhn@exämple.test".parse().unwrap(), params: vec![] };
+        let ascii = Request::Rcpt { to: "john@example.test".parse().unwrap(), params: vec![] };
+
+        let mut session = SmtpSession::new();
+        session.record(&"MAIL FROM:<alice@example.test>\r\n".parse().unwrap());
+        assert_eq!(session.check(&rcpt), Err(PolicyError::NonAsciiAddress));
+        assert_eq!(session.check(&ascii), Ok(()));
+        assert_eq!(PolicyError::NonAsciiAddress.response().to_string(),
+            "550 5.6.7 Non-ASCII addresses require SMTPUTF8\r\n");
+
+        session.record(&"MAIL FROM:<alice@example.test> SMTPUTF8\r\n".parse().unwrap());
+        assert_eq!(session.check(&rcpt), Ok(()));
+        assert_eq!(session.check(&ascii), Ok(()));
+
+        let from: Mailbox = "jøhn@exämple.test".parse().unwrap();
+        assert_eq!(session.check(&Request::Mail { from: from.clone(), params: vec![] }),
+            Err(PolicyError::NonAsciiAddress));
+        assert_eq!(session.check(&Request::Mail { from, params: vec![MailParam::SmtpUtf8] }), Ok(()));
+    }
+
+    #[test]
+    fn test_check() {
+        let mail: Request = "MAIL FROM:<john@example.test> AUTH=john+2Bsubmit@example.test\r\n"
+            .parse().unwrap();
+        match mail {
+            Request::Mail { ref params, .. } => {
+                assert_eq!(params, &[MailParam::Auth("john+submit@example.test".to_string())]);
+            },
+            _ => panic!("expected MAIL"),
+        }
+        let plain: Request = "MAIL FROM:<john@example.test>\r\n".parse().unwrap();
+
+        let mut session = SmtpSession::new();
+        assert_eq!(session.check(&mail), Err(PolicyError::AuthParamWithoutAuthentication));
+        assert_eq!(session.check(&plain), Ok(()));
+        assert_eq!(session.check(&Request::Quit), Ok(()));
+
+        session.set_authenticated();
+        assert_eq!(session.check(&mail), Ok(()));
+        assert_eq!(session.check(&plain), Ok(()));
+        assert_eq!(PolicyError::AuthParamWithoutAuthentication.response().to_string(),
+            "530 Authentication required\r\n");
+    }
+
+    #[test]
+    fn test_max_recipients() {
+        let rcpt = |i: usize| Request::Rcpt {
+            to: format!("user{}@example.test", i).parse().unwrap(),
+            params: vec![],
+        };
+
+        let mut session = SmtpSession::new();
+        assert_eq!(session.max_recipients(), None);
+        session.record(&"MAIL FROM:<john@example.test>\r\n".parse().unwrap());
+        for i in 0..150 {
+            assert_eq!(session.check(&rcpt(i)), Ok(()));
+            session.record(&rcpt(i));
+        }
+
+        session.set_max_recipients(100);
+        assert_eq!(session.max_recipients(), Some(100));
+        session.record(&"MAIL FROM:<john@example.test>\r\n".parse().unwrap());
+        for i in 0..100 {
+            assert_eq!(session.check(&rcpt(i)), Ok(()));
+            session.record(&rcpt(i));
+        }
+        assert_eq!(session.check(&rcpt(100)), Err(PolicyError::TooManyRecipients));
+        assert_eq!(session.check(&Request::Data), Ok(()));
+        assert_eq!(PolicyError::TooManyRecipients.response().to_string(), "452 Too many recipients\r\n");
+
+        session.reset();
+        assert_eq!(session.check(&rcpt(100)), Ok(()));
+    }
+}
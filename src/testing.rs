@@ -0,0 +1,212 @@
+//! Helpers for testing code that talks to an SMTP server
+//!
+//! This module is available to the crate's own tests, and to other crates
+//! with the `testing` feature enabled.
+//!
+//! The `MockSmtpServer` type is an in-memory connection that follows a
+//! script. It is created with the requests it expects, and the replies to
+//! send back for each. It can then be used in place of a network connection,
+//! for example to drive a `ClientTransport` end to end.
+
+use std::cell::{RefCell};
+use std::collections::{VecDeque};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::rc::{Rc};
+use futures::{Async, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+#[derive(Default)]
+struct MockState {
+    /// Replies not yet read by the client
+    output: Vec<u8>,
+    /// Data written by the client, but not yet matched
+    input: Vec<u8>,
+    /// The remaining requests, and the reply to each
+    script: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Whether the client shut down its writing half
+    shut_down: bool,
+}
+
+
+/// A scripted SMTP server, over an in-memory connection
+///
+/// Each expected request is a sequence of bytes, and the reply is sent once
+/// the client has written exactly those bytes. A message body is expected
+/// like any other request, e.g. `"Hello\r\n.\r\n"` after `DATA`. If the
+/// client writes anything else, the write fails with an error describing the
+/// mismatch.
+///
+/// Reads never block once the script is done, but return end of file, as if
+/// the server closed the connection.
+///
+/// Clones of the server share the same connection, so one can be handed to
+/// the client while the test inspects another.
+#[derive(Clone,Default)]
+pub struct MockSmtpServer {
+    state: Rc<RefCell<MockState>>,
+}
+
+impl MockSmtpServer {
+    /// Create a server with an empty script
+    pub fn new() -> Self {
+        MockSmtpServer::default()
+    }
+
+    /// Send a reply as soon as the connection is opened
+    ///
+    /// This is usually the `220` greeting.
+    pub fn greeting(self, reply: &str) -> Self {
+        self.state.borrow_mut().output.extend_from_slice(reply.as_bytes());
+        self
+    }
+
+    /// Expect a request, and send the reply when it is received
+    pub fn expect(self, request: &str, reply: &str) -> Self {
+        self.state.borrow_mut().script.push_back(
+            (request.as_bytes().to_vec(), reply.as_bytes().to_vec()));
+        self
+    }
+
+    /// Tells if all expected requests were received, and nothing more
+    pub fn is_done(&self) -> bool {
+        let state = self.state.borrow();
+        state.script.is_empty() && state.input.is_empty()
+    }
+
+    /// Tells if the client shut down its side of the connection
+    pub fn is_shut_down(&self) -> bool {
+        self.state.borrow().shut_down
+    }
+}
+
+impl MockState {
+    /// Match the input against the script, and queue replies
+    fn process(&mut self) -> IoResult<()> {
+        loop {
+            let matched = match self.script.front() {
+                Some(&(ref request, _)) if self.input.starts_with(request) => request.len(),
+                Some(&(ref request, _)) if request.starts_with(&self.input) => return Ok(()),
+                Some(&(ref request, _)) => {
+                    let len = request.len().min(self.input.len());
+                    return Err(IoError::new(IoErrorKind::InvalidData, format!(
+                        "expected request {:?}, got {:?}",
+                        String::from_utf8_lossy(request),
+                        String::from_utf8_lossy(&self.input[..len]))));
+                },
+                None if self.input.is_empty() => return Ok(()),
+                None => {
+                    return Err(IoError::new(IoErrorKind::InvalidData, format!(
+                        "unexpected request {:?}", String::from_utf8_lossy(&self.input))));
+                },
+            };
+            self.input.drain(..matched);
+            let (_, reply) = self.script.pop_front().expect("script entry was matched");
+            self.output.extend_from_slice(&reply);
+        }
+    }
+}
+
+impl Read for MockSmtpServer {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut state = self.state.borrow_mut();
+        if state.output.is_empty() {
+            if state.script.is_empty() {
+                return Ok(0);
+            }
+            return Err(IoError::new(IoErrorKind::WouldBlock, "awaiting request"));
+        }
+        let len = buf.len().min(state.output.len());
+        buf[..len].copy_from_slice(&state.output[..len]);
+        state.output.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for MockSmtpServer {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut state = self.state.borrow_mut();
+        state.input.extend_from_slice(buf);
+        state.process()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MockSmtpServer {}
+
+impl AsyncWrite for MockSmtpServer {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.state.borrow_mut().shut_down = true;
+        Ok(Async::Ready(()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use client::{ClientCodec, ClientIo, ClientTransport};
+    use futures::{Future, Sink, Stream};
+    use request::{ClientId, Request};
+    use response::{Response};
+    use std::io::{Error as IoError};
+    use testing::{MockSmtpServer};
+    use tokio_io::{AsyncRead};
+    use tokio_proto::streaming::pipeline::{Frame};
+
+    type Transport = ClientTransport<MockSmtpServer>;
+
+    fn read_reply(transport: Transport) -> Result<(Response, Transport), IoError> {
+        match transport.into_future().wait().map_err(|(err, _)| err)? {
+            (Some(Frame::Message { message, .. }), transport) => Ok((message, transport)),
+            _ => panic!("expected a reply"),
+        }
+    }
+
+    fn call(transport: Transport, request: Request) -> Result<(Response, Transport), IoError> {
+        read_reply(transport.send(request.into()).wait()?)
+    }
+
+    #[test]
+    fn test() {
+        let server = MockSmtpServer::new()
+            .greeting("220 mail.example.test ESMTP\r\n")
+            .expect("EHLO client.example.test\r\n", "250-mail.example.test\r\n250 PIPELINING\r\n")
+            .expect("MAIL FROM:<john@example.test>\r\n", "250 OK\r\n")
+            .expect("RCPT TO:<alice@example.test>\r\n", "250 OK\r\n")
+            .expect("DATA\r\n", "354 Start mail input\r\n")
+            .expect("Hello\r\n..World\r\n.\r\n", "250 Queued\r\n")
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let transport = ClientTransport::new(
+            ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+
+        let (greeting, transport) = read_reply(transport).unwrap();
+        assert_eq!(greeting.to_string(), "220 mail.example.test ESMTP\r\n");
+        let ehlo = Request::Ehlo(ClientId::Domain("client.example.test".to_string()));
+        let (response, transport) = call(transport, ehlo).unwrap();
+        assert_eq!(response.text, vec!["mail.example.test", "PIPELINING"]);
+        let mail = Request::Mail { from: "john@example.test".parse().unwrap(), params: vec![] };
+        let (response, transport) = call(transport, mail).unwrap();
+        assert_eq!(response.to_string(), "250 OK\r\n");
+        let rcpt = Request::Rcpt { to: "alice@example.test".parse().unwrap(), params: vec![] };
+        let (response, transport) = call(transport, rcpt).unwrap();
+        assert_eq!(response.to_string(), "250 OK\r\n");
+        let (response, transport) = transport.send_data(b"Hello\r\n.World".to_vec()).wait().unwrap();
+        assert_eq!(response.to_string(), "250 Queued\r\n");
+        let (response, transport) = call(transport, Request::Quit).unwrap();
+        assert_eq!(response.to_string(), "221 Bye\r\n");
+        assert!(server.is_done());
+        assert!(transport.into_future().wait().map_err(|(err, _)| err).unwrap().0.is_none());
+
+        let server = MockSmtpServer::new()
+            .expect("QUIT\r\n", "221 Bye\r\n");
+        let transport = ClientTransport::new(
+            ClientIo::Plain(server.clone()).framed(ClientCodec::new()));
+        let err = call(transport, Request::Rset).err().unwrap();
+        assert_eq!(err.to_string(), "expected request \"QUIT\\r\\n\", got \"RSET\\r\\n\"");
+        assert!(!server.is_done());
+    }
+}
@@ -1,46 +1,123 @@
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::{from_utf8};
 
 
-/// Encode a string as xtext
-pub struct XText<'a>(pub &'a str);
+/// Encode bytes as xtext
+///
+/// Encoding is done per byte, so any non-ASCII characters are encoded as
+/// multiple `+XX` sequences, one for each byte of their UTF-8 encoding. The
+/// bytes need not be valid UTF-8.
+pub struct XText<'a>(pub &'a [u8]);
 
 impl<'a> Display for XText<'a> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        let mut rest = self.0;
-        while let Some(idx) = rest.find(|c| {
-            c < '!' || c == '+' || c == '='
-        }) {
-            let (start, end) = rest.split_at(idx);
-            f.write_str(start)?;
-
-            let mut end_iter = end.char_indices();
-            let (_, c) = end_iter.next().expect("char");
-            write!(f, "+{:X}", c as u8)?;
-
-            if let Some((idx, _)) = end_iter.next() {
-                rest = &end[idx..];
-            } else {
-                rest = "";
+        // Unescaped runs only contain ASCII, so they are always valid UTF-8.
+        let ascii = |bytes| from_utf8(bytes).expect("unescaped xtext is ASCII");
+        let mut start = 0;
+        for (idx, &byte) in self.0.iter().enumerate() {
+            if byte < b'!' || byte > b'~' || byte == b'+' || byte == b'=' {
+                if start < idx {
+                    f.write_str(ascii(&self.0[start..idx]))?;
+                }
+                let hex = to_hex_upper(byte);
+                write!(f, "+{}{}", hex[0] as char, hex[1] as char)?;
+                start = idx + 1;
             }
         }
-        f.write_str(rest)
+        if start < self.0.len() {
+            f.write_str(ascii(&self.0[start..]))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode an xtext encoded string
+///
+/// Returns `None` if the input contains an invalid escape, or if the decoded
+/// bytes are not valid UTF-8.
+pub fn decode_xtext(input: &str) -> Option<String> {
+    String::from_utf8(decode_xtext_bytes(input)?).ok()
+}
+
+/// Decode xtext into raw bytes
+///
+/// Returns `None` if the input contains an invalid escape.
+pub fn decode_xtext_bytes(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'+' {
+            let high = iter.next().and_then(hex_value)?;
+            let low = iter.next().and_then(hex_value)?;
+            bytes.push(high << 4 | low);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Some(bytes)
+}
+
+/// The two uppercase hex digits of a byte, as ASCII
+///
+/// Escapes such as xtext require uppercase digits, so they should all be
+/// written using this.
+pub fn to_hex_upper(byte: u8) -> [u8; 2] {
+    const DIGITS: &'static [u8; 16] = b"0123456789ABCDEF";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xf) as usize]]
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0' ... b'9' => Some(byte - b'0'),
+        b'A' ... b'F' => Some(byte - b'A' + 10),
+        b'a' ... b'f' => Some(byte - b'a' + 10),
+        _ => None,
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use util::{XText};
+    use util::{XText, decode_xtext, decode_xtext_bytes, hex_value, to_hex_upper};
 
     #[test]
     fn test() {
         for (input, expect) in vec![
             ("bjorn", "bjorn"),
-            ("bjørn", "bjørn"),
-            ("Ø+= ❤️‰", "Ø+2B+3D+20❤️‰"),
+            ("bjørn", "bj+C3+B8rn"),
+            ("Ø+= ❤️‰", "+C3+98+2B+3D+20+E2+9D+A4+EF+B8+8F+E2+80+B0"),
             ("+", "+2B"),
+            ("!~\x7f\t", "!~+7F+09"),
+            ("", ""),
+        ] {
+            assert_eq!(format!("{}", XText(input.as_bytes())), expect);
+            assert_eq!(decode_xtext(expect), Some(input.to_string()));
+        }
+
+        for input in vec!["+", "+2", "+ZZ", "+FF"] {
+            assert_eq!(decode_xtext(input), None);
+        }
+
+        // Raw bytes need not be UTF-8.
+        assert_eq!(format!("{}", XText(b"a\xff")), "a+FF");
+        assert_eq!(decode_xtext_bytes("a+FF"), Some(b"a\xff".to_vec()));
+        assert_eq!(decode_xtext_bytes("+ZZ"), None);
+    }
+
+    #[test]
+    fn test_hex() {
+        for (byte, expect) in vec![
+            (0x00, b"00"),
+            (0x0f, b"0F"),
+            (0x2b, b"2B"),
+            (0xa0, b"A0"),
+            (0xff, b"FF"),
         ] {
-            assert_eq!(format!("{}", XText(input)), expect);
+            assert_eq!(&to_hex_upper(byte), expect);
+        }
+        for byte in 0..256 {
+            let hex = to_hex_upper(byte as u8);
+            assert_eq!(hex_value(hex[0]).unwrap() << 4 | hex_value(hex[1]).unwrap(), byte as u8);
         }
     }
 }